@@ -1,7 +1,7 @@
 use crate::error::{HttpError, Result};
 use crate::{HttpRequest, HttpResponse};
 use bytes::BytesMut;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADER_SIZE: usize = 1024 * 32;
 const BUFFER_SIZE: usize = 4096;
@@ -35,7 +35,7 @@ impl HttpRequest {
             }
 
             // Check overlap region (previous read boundary)
-            let search_start = if prev_len >= 3 { prev_len - 3 } else { 0 };
+            let search_start = prev_len.saturating_sub(3);
             if memchr::memmem::find(&buffer[search_start..], CRLF2).is_some() {
                 break;
             }
@@ -83,7 +83,7 @@ impl HttpResponse {
             }
 
             // Check overlap region (previous read boundary)
-            let search_start = if prev_len >= 3 { prev_len - 3 } else { 0 };
+            let search_start = prev_len.saturating_sub(3);
             if memchr::memmem::find(&buffer[search_start..], CRLF2).is_some() {
                 break;
             }
@@ -91,4 +91,33 @@ impl HttpResponse {
 
         Self::parse_bytes(buffer.freeze())
     }
+
+    /// Read and parse HTTP response head from a buffered async stream
+    ///
+    /// Unlike [`from_stream`](Self::from_stream), this never consumes bytes past the
+    /// terminating empty line, so data following the head stays in the stream.
+    /// This is required for tunnels such as `CONNECT`, where the payload directly follows the response.
+    pub async fn from_buf_reader<S>(stream: &mut S) -> Result<Self>
+    where
+        S: AsyncBufRead + Unpin,
+    {
+        let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+
+        loop {
+            let n = stream.read_until(b'\n', &mut buffer).await?;
+            if n == 0 {
+                return Err(HttpError::InvalidResponse("Connection closed".to_string()));
+            }
+
+            if buffer.len() > MAX_HEADER_SIZE {
+                return Err(HttpError::InvalidResponse("Header too large".to_string()));
+            }
+
+            if buffer.ends_with(CRLF2) {
+                break;
+            }
+        }
+
+        Self::parse_bytes(buffer.into())
+    }
 }
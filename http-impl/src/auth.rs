@@ -8,6 +8,21 @@ pub struct BasicAuth {
     pub password: String,
 }
 
+impl BasicAuth {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Encode credentials as a `Basic` header value for `Proxy-Authorization`
+    pub fn header_value(&self) -> String {
+        let credentials = format!("{}:{}", self.username, self.password);
+        format!("Basic {}", general_purpose::STANDARD.encode(credentials))
+    }
+}
+
 impl HttpRequest {
     pub fn parse_basic_auth(&self) -> Result<Option<BasicAuth>> {
         let auth_header = match self.header("Proxy-Authorization") {
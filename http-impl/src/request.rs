@@ -146,7 +146,12 @@ impl HttpRequestBuilder {
         let mut raw = BytesMut::new();
         raw.extend_from_slice(method.as_str().as_bytes());
         raw.extend_from_slice(b" ");
-        raw.extend_from_slice(uri.path().as_bytes());
+        // CONNECT uses the authority-form request target (RFC 9110, section 9.3.6)
+        let target = match uri.authority() {
+            Some(authority) if method == Method::CONNECT => authority.as_str(),
+            _ => uri.path(),
+        };
+        raw.extend_from_slice(target.as_bytes());
         raw.extend_from_slice(b" HTTP/1.1\r\n");
 
         for (name, value) in self.headers.iter() {
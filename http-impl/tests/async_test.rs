@@ -28,3 +28,20 @@ async fn test_async_response_write_to_stream() {
     assert!(buffer.starts_with(b"HTTP/1.1 200 OK"));
     assert!(buffer.ends_with(b"Hello"));
 }
+
+#[tokio::test]
+async fn test_async_response_from_buf_reader_keeps_payload() {
+    use tokio::io::{AsyncReadExt, BufReader};
+
+    let data = b"HTTP/1.1 200 Connection established\r\nProxy-Agent: test\r\n\r\nSSH-2.0-banner".to_vec();
+    let mut reader = BufReader::new(std::io::Cursor::new(data));
+
+    let response = http_impl::HttpResponse::from_buf_reader(&mut reader).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.header("Proxy-Agent").unwrap(), "test");
+    assert!(response.body().is_empty());
+
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, b"SSH-2.0-banner");
+}
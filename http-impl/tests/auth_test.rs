@@ -1,4 +1,4 @@
-use http_impl::HttpRequest;
+use http_impl::{BasicAuth, HttpRequest};
 
 #[test]
 fn test_parse_basic_auth() {
@@ -18,3 +18,13 @@ fn test_no_auth() {
     let auth = request.parse_basic_auth().unwrap();
     assert!(auth.is_none());
 }
+
+#[test]
+fn test_basic_auth_header_value() {
+    let auth = BasicAuth::new("user", "pass");
+    assert_eq!(auth.header_value(), "Basic dXNlcjpwYXNz");
+
+    let raw = format!("CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n", auth.header_value());
+    let request = HttpRequest::parse(raw.as_bytes()).unwrap();
+    assert_eq!(request.parse_basic_auth().unwrap(), Some(auth));
+}
@@ -23,3 +23,14 @@ fn test_builder_pattern() {
     assert_eq!(request.method(), &Method::POST);
     assert_eq!(request.body().as_ref(), b"{}");
 }
+
+#[test]
+fn test_builder_connect_authority_form() {
+    let request = HttpRequestBuilder::new()
+        .method(Method::CONNECT)
+        .uri("example.com:443".parse::<Uri>().unwrap())
+        .header("Host", "example.com:443")
+        .build();
+
+    assert!(request.raw_bytes().starts_with(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+}
@@ -1,6 +1,9 @@
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+};
 
 use crate::result::{Error, Result};
 
@@ -62,23 +65,25 @@ impl ProxyAddresses {
 /// PROXY Protocol v2 头部
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ProxyHeader {
-    /// 命令类型（始终为 Command::Proxy）
+    /// 命令类型
     pub command: Command,
     /// 地址族（AF_INET, AF_INET6 等）
     pub address_family: u8,
     /// 协议类型（PROTO_STREAM, PROTO_DGRAM 等）
     pub protocol: u8,
     /// 地址信息（真实客户端和目标地址）
-    pub addresses: ProxyAddresses,
+    ///
+    /// LOCAL 命令（健康检查）没有代理信息，为 `None`：此时应使用连接本身的地址。
+    pub addresses: Option<ProxyAddresses>,
 }
 
 /// 从 TCP 流中解析 PROXY Protocol v2 头部
 ///
-/// 此函数要求连接**必须是 PROXY Protocol v2 且命令为 PROXY (0x01)**。
+/// 此函数要求连接**必须是 PROXY Protocol v2**，命令为 PROXY (0x01) 或 LOCAL (0x00)。
+/// LOCAL 命令是负载均衡器的健康检查，其地址数据（可以为空）会被丢弃，`addresses` 为 `None`。
 ///
 /// **拒绝以下连接**：
 /// - 非 PROXY 协议连接 → `Err(InvalidSignature)`
-/// - LOCAL 命令 (0x00) → `Err(InvalidCommand(0x00))`
 /// - 其他未知命令 → `Err(InvalidCommand(...))`
 ///
 /// # 性能特性
@@ -88,7 +93,6 @@ pub struct ProxyHeader {
 ///
 /// # 错误
 /// - `InvalidSignature`: 不是 PROXY Protocol v2 连接
-/// - `InvalidCommand(0x00)`: LOCAL 命令（健康检查）不被接受
 /// - `InvalidCommand(cmd)`: 未知的命令类型
 /// - `InvalidAddressFamily`: 不支持的地址族
 /// - `Io`: I/O 错误
@@ -104,9 +108,11 @@ pub struct ProxyHeader {
 /// // 如果不是 PROXY 协议，会返回 Err(InvalidSignature)
 /// let header = parse_proxy_protocol(&mut stream).await?;
 ///
-/// // addresses 字段始终有效（不再是 Option）
-/// println!("Real client: {}", header.addresses.source);
-/// println!("Destination: {}", header.addresses.destination);
+/// // LOCAL 命令（健康检查）没有地址信息
+/// if let Some(addresses) = header.addresses {
+///     println!("Real client: {}", addresses.source);
+///     println!("Destination: {}", addresses.destination);
+/// }
 /// # Ok(())
 /// # }
 /// ```
@@ -121,6 +127,64 @@ pub async fn parse_proxy_protocol(stream: &mut TcpStream) -> Result<ProxyHeader>
         return Err(Error::InvalidSignature);
     }
 
+    let (command, address_family, protocol, addr_len) = parse_header(&header_buf)?;
+
+    // 7. 计算总帧长度并一次性读取
+    let total_len = HEADER_SIZE + addr_len;
+    let mut frame_buf = vec![0u8; total_len];
+    stream.read_exact(&mut frame_buf).await?;
+
+    // 8. 解析地址信息
+    let addresses = parse_command_addresses(command, &frame_buf[HEADER_SIZE..], address_family)?;
+
+    Ok(ProxyHeader {
+        command,
+        address_family,
+        protocol,
+        addresses,
+    })
+}
+
+/// 从任意异步流中读取并解析 PROXY Protocol v2 头部
+///
+/// 与 [`parse_proxy_protocol`] 接受和拒绝的连接相同，但不依赖 `peek`，因此适用于 TLS、Unix socket 等任意流。
+/// 头部字节会被消耗：出错时，已读取的字节不会放回流中。
+///
+/// # 示例
+/// ```no_run
+/// use proxy_protocol::version2::read_proxy_protocol;
+///
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let (_client, mut server) = tokio::io::duplex(1024);
+/// let header = read_proxy_protocol(&mut server).await?;
+/// if let Some(addresses) = header.addresses {
+///     println!("Real client: {}", addresses.source);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub async fn read_proxy_protocol<R>(stream: &mut R) -> Result<ProxyHeader>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut header_buf = [0u8; HEADER_SIZE];
+    stream.read_exact(&mut header_buf).await?;
+    let (command, address_family, protocol, addr_len) = parse_header(&header_buf)?;
+
+    let mut addr_buf = vec![0u8; addr_len];
+    stream.read_exact(&mut addr_buf).await?;
+    let addresses = parse_command_addresses(command, &addr_buf, address_family)?;
+
+    Ok(ProxyHeader {
+        command,
+        address_family,
+        protocol,
+        addresses,
+    })
+}
+
+/// 解析固定长度的头部：返回命令、地址族、协议和地址数据长度
+fn parse_header(header_buf: &[u8; HEADER_SIZE]) -> Result<(Command, u8, u8, usize)> {
     // 3. 快速路径：零拷贝签名检查
     if &header_buf[..SIGNATURE_LENGTH] != PROXY_SIGNATURE {
         return Err(Error::InvalidSignature);
@@ -135,8 +199,9 @@ pub async fn parse_proxy_protocol(stream: &mut TcpStream) -> Result<ProxyHeader>
         return Err(Error::UnsupportedVersion(version));
     }
 
-    // 只接受 PROXY 命令 (0x01)，拒绝 LOCAL (0x00) 和其他命令
+    // 只接受 LOCAL (0x00) 和 PROXY (0x01) 命令
     let command = match command_byte {
+        0x00 => Command::Local,
         0x01 => Command::Proxy,
         _ => return Err(Error::InvalidCommand(command_byte)),
     };
@@ -154,25 +219,16 @@ pub async fn parse_proxy_protocol(stream: &mut TcpStream) -> Result<ProxyHeader>
         return Err(Error::InvalidAddressLength(addr_len as u16));
     }
 
-    // 7. 计算总帧长度并一次性读取
-    let total_len = HEADER_SIZE + addr_len;
-    let mut frame_buf = vec![0u8; total_len];
-    stream.read_exact(&mut frame_buf).await?;
+    Ok((command, address_family, protocol, addr_len))
+}
 
-    // 8. 验证地址数据长度（PROXY 命令必须有地址数据）
-    if addr_len == 0 {
-        return Err(Error::InvalidAddressLength(0));
+/// 解析命令对应的地址信息：LOCAL 命令的地址数据被丢弃，PROXY 命令必须有地址数据
+fn parse_command_addresses(command: Command, buf: &[u8], family: u8) -> Result<Option<ProxyAddresses>> {
+    match command {
+        Command::Local => Ok(None),
+        Command::Proxy if buf.is_empty() => Err(Error::InvalidAddressLength(0)),
+        Command::Proxy => parse_addresses(buf, family).map(Some),
     }
-
-    // 9. 解析地址信息
-    let addresses = parse_addresses(&frame_buf[HEADER_SIZE..], address_family)?;
-
-    Ok(ProxyHeader {
-        command,
-        address_family,
-        protocol,
-        addresses,
-    })
 }
 
 /// 从缓冲区解析地址信息（零拷贝）
@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod tests {
-    use proxy_protocol::version2::{parse_proxy_protocol, Command};
+    use proxy_protocol::version2::{parse_proxy_protocol, read_proxy_protocol, Command};
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use tokio::{
         io::AsyncWriteExt,
@@ -36,11 +36,11 @@ mod tests {
         let header = parse_proxy_protocol(&mut stream).await.unwrap();
 
         assert_eq!(header.command, Command::Proxy);
-        // addresses 不再是 Option，直接访问
-        assert_eq!(header.addresses.source.ip(), IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)));
-        assert_eq!(header.addresses.source.port(), 12345);
-        assert_eq!(header.addresses.destination.ip(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
-        assert_eq!(header.addresses.destination.port(), 80);
+        let addresses = header.addresses.unwrap();
+        assert_eq!(addresses.source.ip(), IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)));
+        assert_eq!(addresses.source.port(), 12345);
+        assert_eq!(addresses.destination.ip(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(addresses.destination.port(), 80);
     }
 
     #[tokio::test]
@@ -58,19 +58,24 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_parse_proxy_protocol_local_command_rejected() {
-        // LOCAL 命令（健康检查）：版本2 + 命令0
-        // 现在应该被拒绝
+    async fn test_parse_proxy_protocol_local_command() {
+        // LOCAL 命令（健康检查）：版本2 + 命令0，地址长度为 0
         let data = b"\x0D\x0A\x0D\x0A\x00\x0D\x0A\x51\x55\x49\x54\x0A\x20\x00\x00\x00";
         let mut stream = create_test_stream(data).await;
 
-        let result = parse_proxy_protocol(&mut stream).await;
+        let header = parse_proxy_protocol(&mut stream).await.unwrap();
 
-        // 应该返回 InvalidCommand 错误
-        assert!(result.is_err());
+        // 没有地址信息，应使用连接本身的地址
+        assert_eq!(header.command, Command::Local);
+        assert_eq!(header.addresses, None);
+
+        // 其他未知命令仍然被拒绝
+        let data = b"\x0D\x0A\x0D\x0A\x00\x0D\x0A\x51\x55\x49\x54\x0A\x22\x00\x00\x00";
+        let mut stream = create_test_stream(data).await;
+        let result = parse_proxy_protocol(&mut stream).await;
         assert!(matches!(
             result.unwrap_err(),
-            proxy_protocol::result::Error::InvalidCommand(0x00)
+            proxy_protocol::result::Error::InvalidCommand(0x02)
         ));
     }
 
@@ -99,10 +104,10 @@ mod tests {
         let header = parse_proxy_protocol(&mut stream).await.unwrap();
 
         assert_eq!(header.command, Command::Proxy);
-        // addresses 不再是 Option
-        assert!(matches!(header.addresses.source.ip(), IpAddr::V6(_)));
-        assert_eq!(header.addresses.source.port(), 12345);
-        assert_eq!(header.addresses.destination.port(), 80);
+        let addresses = header.addresses.unwrap();
+        assert!(matches!(addresses.source.ip(), IpAddr::V6(_)));
+        assert_eq!(addresses.source.port(), 12345);
+        assert_eq!(addresses.destination.port(), 80);
     }
 
     #[tokio::test]
@@ -116,6 +121,24 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_read_proxy_protocol_from_any_stream() {
+        let data = b"\x0D\x0A\x0D\x0A\x00\x0D\x0A\x51\x55\x49\x54\x0A\x21\x11\x00\x0C\xC0\xA8\x01\x64\x0A\x00\x00\x01\x30\x39\x00\x50\x05";
+        let mut stream = &data[..];
+
+        let header = read_proxy_protocol(&mut stream).await.unwrap();
+        assert_eq!(
+            header.addresses.unwrap().source,
+            "192.168.1.100:12345".parse::<SocketAddr>().unwrap()
+        );
+        // 头部之后的数据保留在流中
+        assert_eq!(stream, b"\x05");
+
+        let mut stream = &b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"[..];
+        let result = read_proxy_protocol(&mut stream).await;
+        assert!(matches!(result.unwrap_err(), proxy_protocol::result::Error::InvalidSignature));
+    }
+
     #[tokio::test]
     async fn test_proxy_addresses_helpers() {
         let src = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 8080);
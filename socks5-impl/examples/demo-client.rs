@@ -0,0 +1,59 @@
+//! Fetch a web page through a SOCKS5 server.
+
+use socks5_impl::{Result, client, protocol::UserKey};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about = "SOCKS5 client fetching a web page", long_about = None)]
+struct Args {
+    /// The SOCKS5 server
+    #[arg(short, long, value_name = "host:port", default_value = "127.0.0.1:1080")]
+    server: String,
+
+    /// The host of the web server
+    #[arg(short = 'H', long, default_value = "example.com")]
+    host: String,
+
+    /// The port of the web server
+    #[arg(short = 'P', long, default_value_t = 80)]
+    port: u16,
+
+    /// The path to fetch
+    #[arg(long, default_value = "/")]
+    path: String,
+
+    /// The username on the SOCKS5 server
+    #[arg(short, long, requires = "password")]
+    username: Option<String>,
+
+    /// The password on the SOCKS5 server
+    #[arg(short, long, requires = "username")]
+    password: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Args = clap::Parser::parse();
+
+    let (host, port) = args.server.rsplit_once(':').ok_or("the server must be given as host:port")?;
+    let port = port.parse::<u16>().map_err(|err| format!("invalid port {port}: {err}"))?;
+    let (stream, proxy_addr) = client::connect_proxy((host, port)).await?;
+    println!("connected to the SOCKS5 server at {proxy_addr}");
+
+    let mut stream = BufStream::new(stream);
+    let auth = args
+        .username
+        .zip(args.password)
+        .map(|(username, password)| UserKey::new(username, password));
+    let bound = client::connect(&mut stream, (args.host.as_str(), args.port), auth).await?;
+    println!("connected to {}:{} from {bound}", args.host, args.port);
+
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", args.path, args.host);
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    println!("{}", String::from_utf8_lossy(&response));
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! A minimal SOCKS5 server serving `CONNECT` requests, written with the protocol messages only.
+
+use socks5_impl::{
+    Result,
+    protocol::{Address, AuthMethod, Command, Reply, Request, Response, StreamOperation, UserKey, handshake, handshake::password_method},
+};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about = "Minimal SOCKS5 server", long_about = None)]
+struct Args {
+    /// The address to listen on
+    #[arg(short, long, value_name = "address:port", default_value = "127.0.0.1:1080")]
+    listen_addr: SocketAddr,
+
+    /// Require this username
+    #[arg(short, long, requires = "password")]
+    username: Option<String>,
+
+    /// Require this password
+    #[arg(short, long, requires = "username")]
+    password: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Args = clap::Parser::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let user_key = args
+        .username
+        .zip(args.password)
+        .map(|(username, password)| UserKey::new(username, password));
+    let listener = TcpListener::bind(args.listen_addr).await?;
+    log::info!("listening on {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let user_key = user_key.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, user_key).await {
+                log::warn!("{peer}: {err}");
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, user_key: Option<UserKey>) -> Result<()> {
+    let method = if user_key.is_some() {
+        AuthMethod::UserPass
+    } else {
+        AuthMethod::NoAuth
+    };
+    let request = handshake::Request::retrieve_from_async_stream(&mut stream).await?;
+    if !request.evaluate_method(method) {
+        handshake::Response::new(AuthMethod::NoAcceptableMethods)
+            .write_to_async_stream(&mut stream)
+            .await?;
+        return Err("no acceptable authentication method".into());
+    }
+    handshake::Response::new(method).write_to_async_stream(&mut stream).await?;
+
+    if let Some(user_key) = user_key {
+        use password_method::Status::{Failed, Succeeded};
+        let request = password_method::Request::retrieve_from_async_stream(&mut stream).await?;
+        let valid = request.user_key == user_key;
+        let status = if valid { Succeeded } else { Failed };
+        password_method::Response::new(status).write_to_async_stream(&mut stream).await?;
+        if !valid {
+            return Err("invalid credentials".into());
+        }
+    }
+
+    let request = Request::retrieve_from_async_stream(&mut stream).await?;
+    if request.command != Command::Connect {
+        Response::new(Reply::CommandNotSupported, Address::unspecified())
+            .write_to_async_stream(&mut stream)
+            .await?;
+        return Err(format!("unsupported command {:?}", request.command).into());
+    }
+
+    let mut target = match TcpStream::connect(request.address.to_string()).await {
+        Ok(target) => target,
+        Err(err) => {
+            let response = Response::new(Reply::from(&err), Address::unspecified());
+            response.write_to_async_stream(&mut stream).await?;
+            return Err(err.into());
+        }
+    };
+    let response = Response::new(Reply::Succeeded, Address::from(target.local_addr()?));
+    response.write_to_async_stream(&mut stream).await?;
+    let (up, down) = tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+    log::info!("{} closed: {up} bytes up, {down} bytes down", request.address);
+    Ok(())
+}
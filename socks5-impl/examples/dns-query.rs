@@ -0,0 +1,104 @@
+//! Query a DNS server through a SOCKS5 server, over UDP or TCP.
+
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query},
+    rr::{Name, RecordType},
+};
+use socks5_impl::{Result, client, protocol::UserKey};
+use std::{net::SocketAddr, str::FromStr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about = "DNS query through a SOCKS5 server", long_about = None)]
+struct Args {
+    /// The SOCKS5 server
+    #[arg(short, long, value_name = "address:port", default_value = "127.0.0.1:1080")]
+    server: SocketAddr,
+
+    /// The DNS server, reached through the SOCKS5 server
+    #[arg(short, long, value_name = "address:port", default_value = "8.8.8.8:53")]
+    dns: SocketAddr,
+
+    /// The name to resolve
+    #[arg(short, long, default_value = "example.com")]
+    name: String,
+
+    /// The record type to query
+    #[arg(short, long, default_value = "A")]
+    record_type: String,
+
+    /// Query over TCP, with a CONNECT request, rather than over UDP
+    #[arg(long)]
+    tcp: bool,
+
+    /// How long to wait for the answer, in seconds
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+
+    /// The username on the SOCKS5 server
+    #[arg(short, long, requires = "password")]
+    username: Option<String>,
+
+    /// The password on the SOCKS5 server
+    #[arg(short, long, requires = "username")]
+    password: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Args = clap::Parser::parse();
+    let auth = args
+        .username
+        .clone()
+        .zip(args.password.clone())
+        .map(|(username, password)| UserKey::new(username, password));
+
+    let name = Name::from_str(&args.name).map_err(|err| format!("invalid name {}: {err}", args.name))?;
+    let record_type = RecordType::from_str(&args.record_type.to_uppercase()).map_err(|err| err.to_string())?;
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, record_type));
+    let query = query.to_vec().map_err(|err| err.to_string())?;
+
+    let timeout = Duration::from_secs(args.timeout);
+    let answer = if args.tcp {
+        tokio::time::timeout(timeout, query_tcp(&args, &query, auth))
+            .await
+            .map_err(|_| "no answer")??
+    } else {
+        query_udp(&args, &query, auth, timeout).await?
+    };
+    let answer = Message::from_vec(&answer).map_err(|err| err.to_string())?;
+    println!("{}: {}", args.name, answer.response_code());
+    for record in answer.answers() {
+        println!("{record}");
+    }
+    Ok(())
+}
+
+async fn query_udp(args: &Args, query: &[u8], auth: Option<UserKey>, timeout: Duration) -> Result<Vec<u8>> {
+    let client = client::create_udp_client(args.server, auth).await?;
+    client.send_to(query, args.dns).await?;
+    let mut answer = Vec::new();
+    client.recv_from(timeout, &mut answer).await?;
+    Ok(answer)
+}
+
+async fn query_tcp(args: &Args, query: &[u8], auth: Option<UserKey>) -> Result<Vec<u8>> {
+    let mut stream = BufStream::new(TcpStream::connect(args.server).await?);
+    client::connect(&mut stream, args.dns, auth).await?;
+    stream.write_u16(query.len() as u16).await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+    let len = stream.read_u16().await?;
+    let mut answer = vec![0; usize::from(len)];
+    stream.read_exact(&mut answer).await?;
+    Ok(answer)
+}
@@ -0,0 +1,497 @@
+//! A SOCKS5 server configured by a TOML file, see `s5-server.toml`.
+//!
+//! ```sh
+//! cargo run --example s5-server --features server,serde -- --config socks5-impl/examples/s5-server.toml
+//! ```
+//!
+//! Command line options override the configuration file, and can also be given as environment variables,
+//! read from a `.env` file if there is one.
+
+#[path = "s5-server/acl.rs"]
+mod acl;
+#[path = "s5-server/config.rs"]
+mod config;
+
+use config::{Config, ListenerConfig};
+use socks5_impl::{
+    Result,
+    protocol::{Address, AuthMethod, Command, Reply, StreamOperation, UserKey, handshake::password_method},
+    resolver::{CachingResolver, Resolver, ResolverAdaptor, SystemResolver},
+    server::{
+        Accounted, Accounting, AssociatedUdpSocket, AsyncStream, AuditLog, AuthExecutor, Bind, ClientConnection, Connect, ConnectionLimits,
+        DirectConnector, HttpProxyRequest, IncomingConnection, Metered, Metrics, MixedConnection, OutboundContext, ProxyProtocol, Server,
+        SessionContext, SessionTimeouts, TimeLimited, UdpAssociate,
+        connection::{associate, bind, connect},
+        throttle::{Throttled, Throttler},
+    },
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+};
+
+const MAX_UDP_PACKET_SIZE: usize = 65535;
+
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about = "SOCKS5 proxy server configured by a TOML file", long_about = None)]
+struct Args {
+    /// The configuration file
+    #[arg(short, long, env = "S5_CONFIG", value_name = "path")]
+    config: Option<PathBuf>,
+
+    /// Listen on these addresses instead of the configured listeners
+    #[arg(short, long, env = "S5_LISTEN", value_delimiter = ',', value_name = "address:port")]
+    listen: Vec<SocketAddr>,
+
+    /// Also accept SOCKS4 and HTTP proxy clients on the addresses given with --listen
+    #[arg(long, env = "S5_MIXED")]
+    mixed: bool,
+
+    /// Add users, overriding the configured users of the same name
+    #[arg(short, long = "user", env = "S5_USERS", value_delimiter = ',', value_name = "username:password")]
+    users: Vec<String>,
+
+    /// The log filter, overriding the configured level
+    #[arg(short, long, env = "S5_LOG_LEVEL", value_name = "level")]
+    verbosity: Option<String>,
+
+    /// Serve Prometheus metrics on this address, overriding the configured address
+    #[arg(long, env = "S5_METRICS", value_name = "address:port")]
+    metrics: Option<SocketAddr>,
+
+    /// Check the configuration and exit
+    #[arg(long)]
+    check: bool,
+}
+
+/// The name of the authenticated user, if authentication is required.
+type AuthOutput = std::io::Result<Option<String>>;
+
+/// Authenticates the configured users, or nobody when there are none.
+struct UsersAuth {
+    passwords: HashMap<String, String>,
+}
+
+#[async_trait::async_trait]
+impl AuthExecutor for UsersAuth {
+    type Output = AuthOutput;
+
+    fn auth_method(&self) -> AuthMethod {
+        if self.passwords.is_empty() {
+            AuthMethod::NoAuth
+        } else {
+            AuthMethod::UserPass
+        }
+    }
+
+    async fn execute(&self, stream: &mut dyn AsyncStream) -> Self::Output {
+        use password_method::{Request, Response, Status::*};
+        if self.passwords.is_empty() {
+            return Ok(None);
+        }
+        let req = Request::retrieve_from_async_stream(stream).await?;
+        let UserKey { username, password } = req.user_key;
+        let valid = self.verify_password(&username, &password) == Some(true);
+        Response::new(if valid { Succeeded } else { Failed })
+            .write_to_async_stream(stream)
+            .await?;
+        if !valid {
+            return Err(std::io::Error::other(format!("invalid credentials for {username}")));
+        }
+        Ok(Some(username))
+    }
+
+    fn identity(&self, output: &Self::Output) -> Option<String> {
+        output.as_ref().ok().cloned().flatten()
+    }
+
+    fn failure_reason(&self, output: &Self::Output) -> Option<&'static str> {
+        match output {
+            Ok(_) => None,
+            Err(err) if err.kind() == std::io::ErrorKind::Other => Some("invalid_credentials"),
+            Err(_) => Some("protocol_error"),
+        }
+    }
+
+    fn verify_password(&self, username: &str, password: &str) -> Option<bool> {
+        Some(self.passwords.get(username).is_some_and(|expected| expected == password))
+    }
+}
+
+/// What the sessions of every listener share.
+struct State {
+    config: Config,
+    /// Resolves the targets for both the ACL and the connector, so that they agree on the addresses.
+    resolver: ResolverAdaptor,
+    connector: DirectConnector,
+    metrics: Option<Metrics>,
+    accounting: Option<Accounting>,
+    throttler: Option<Throttler>,
+    timeouts: SessionTimeouts,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenvy::dotenv().ok();
+    let args: Args = clap::Parser::parse();
+
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if !args.listen.is_empty() {
+        config.listeners = args.listen.iter().map(|&addr| ListenerConfig::new(addr, args.mixed)).collect();
+    }
+    if config.listeners.is_empty() {
+        config
+            .listeners
+            .push(ListenerConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 1080)), args.mixed));
+    }
+    let mut passwords = config.auth.passwords()?;
+    for user in &args.users {
+        let Some((username, password)) = user.split_once(':') else {
+            return Err(format!("invalid user {user}, expected username:password").into());
+        };
+        passwords.insert(username.to_owned(), password.to_owned());
+    }
+    if let Some(level) = args.verbosity.clone() {
+        config.log.level = Some(level);
+    }
+    if let Some(metrics) = args.metrics {
+        config.log.metrics = Some(metrics);
+    }
+    if args.check {
+        println!(
+            "configuration is valid: {} listener(s), {} user(s)",
+            config.listeners.len(),
+            passwords.len()
+        );
+        return Ok(());
+    }
+
+    let level = config.log.level.as_deref().unwrap_or("info");
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    let auth = Arc::new(UsersAuth { passwords });
+    let state = Arc::new(build_state(config).await?);
+
+    let mut shutdowns = Vec::new();
+    for listener in &state.config.listeners {
+        let server = bind_server(listener, auth.clone(), &state).await?;
+        log::info!("listening on {}", server.local_addr()?);
+        shutdowns.push(server.shutdown_handle());
+        tokio::spawn(accept_loop(server, listener.mixed, state.clone()));
+    }
+
+    tokio::signal::ctrl_c().await?;
+    log::info!("shutting down");
+    let grace = Duration::from_secs(state.config.timeouts.shutdown);
+    let drains: Vec<_> = shutdowns
+        .into_iter()
+        .map(|shutdown| tokio::spawn(async move { shutdown.shutdown(grace).await }))
+        .collect();
+    let (mut drained, mut aborted) = (0, 0);
+    for drain in drains {
+        let report = drain.await.map_err(std::io::Error::other)?;
+        drained += report.drained;
+        aborted += report.aborted;
+    }
+    log::info!("{drained} session(s) drained, {aborted} aborted");
+    if let Some(accounting) = &state.accounting {
+        accounting.flush().await?;
+    }
+    Ok(())
+}
+
+async fn build_state(config: Config) -> Result<State> {
+    let metrics = match config.log.metrics {
+        Some(addr) => {
+            let metrics = Metrics::new();
+            let listener = TcpListener::bind(addr).await?;
+            log::info!("serving metrics on http://{}/metrics", listener.local_addr()?);
+            tokio::spawn(metrics.clone().serve(listener));
+            Some(metrics)
+        }
+        None => None,
+    };
+    let accounting = match &config.accounting {
+        Some(settings) => {
+            let accounting = Accounting::open(&settings.path).await?.with_default_quota(settings.default_quota);
+            for user in &config.auth.users {
+                if let Some(quota) = user.quota {
+                    accounting.set_quota(&user.username, quota);
+                }
+            }
+            let handle = accounting.clone();
+            let interval = Duration::from_secs(settings.flush_interval);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    if let Err(err) = handle.flush().await {
+                        log::warn!("failed to save the accounting: {err}");
+                    }
+                }
+            });
+            Some(accounting)
+        }
+        None => None,
+    };
+    let resolver: ResolverAdaptor = Arc::new(CachingResolver::new(
+        SystemResolver,
+        Duration::from_secs(60),
+        Duration::from_secs(5),
+    ));
+    let connector = DirectConnector::new()
+        .with_resolver(resolver.clone())
+        .with_timeout(Duration::from_secs(config.timeouts.connect));
+    Ok(State {
+        resolver,
+        connector,
+        metrics,
+        accounting,
+        throttler: config.throttle.map(Throttler::new),
+        timeouts: config.timeouts.session(),
+        config,
+    })
+}
+
+async fn bind_server(listener: &ListenerConfig, auth: Arc<UsersAuth>, state: &State) -> Result<Server<AuthOutput>> {
+    let mut server = Server::bind(listener.address, auth).await?;
+    if state.config.limits != ConnectionLimits::default() {
+        server = server.with_limits(state.config.limits);
+    }
+    if !listener.proxy_protocol.is_empty() {
+        let handshake = Duration::from_secs(state.config.timeouts.handshake);
+        let trusted = ProxyProtocol::new(listener.proxy_protocol.iter().copied()).with_timeout(handshake);
+        server = server.with_proxy_protocol(trusted);
+    }
+    if let Some(metrics) = &state.metrics {
+        server = server.with_metrics(metrics.clone());
+    }
+    if let Some(accounting) = &state.accounting {
+        server = server.with_accounting(accounting.clone());
+    }
+    if let Some(audit) = &state.config.audit {
+        server = server.with_audit_log(AuditLog::open(audit.clone())?);
+    }
+    Ok(server)
+}
+
+async fn accept_loop(server: Server<AuthOutput>, mixed: bool, state: Arc<State>) {
+    let shutdown = server.shutdown_handle();
+    loop {
+        match server.accept().await {
+            Ok((conn, _)) => {
+                let state = state.clone();
+                shutdown.spawn(async move {
+                    let peer = conn.context().client_addr();
+                    if let Err(err) = serve(conn, mixed, &state).await {
+                        log::debug!("session from {peer}: {err}");
+                    }
+                });
+            }
+            Err(_) if shutdown.is_shutdown() => break,
+            Err(err) => log::warn!("accept failed: {err}"),
+        }
+    }
+}
+
+async fn serve(conn: IncomingConnection<AuthOutput>, mixed: bool, state: &State) -> Result<()> {
+    let handshake = Duration::from_secs(state.config.timeouts.handshake);
+    let conn = match mixed {
+        true => match tokio::time::timeout(handshake, conn.sniff())
+            .await
+            .map_err(|_| "handshake timeout")??
+        {
+            MixedConnection::Socks5(conn) => conn,
+            MixedConnection::Socks4(request, _) => return handle_request(request, state).await,
+            MixedConnection::Http(conn, target, request) => return handle_http(conn, target, &request, state).await,
+        },
+        false => conn,
+    };
+    let (conn, output) = conn.authenticate_with_timeout(handshake).await?;
+    output?;
+    handle_request(conn.wait_request().await?, state).await
+}
+
+async fn handle_request(request: ClientConnection, state: &State) -> Result<()> {
+    let (command, target) = match &request {
+        ClientConnection::Connect(_, target) => (Command::Connect, target),
+        ClientConnection::Bind(_, target) => (Command::Bind, target),
+        ClientConnection::UdpAssociate(_, target) => (Command::UdpAssociate, target),
+    };
+    if check_target(state, request.context(), command, target).await.is_none() {
+        log::info!("denied {command:?} to {target} for {}", request.context().client_addr());
+        let denied = Reply::ConnectionNotAllowed;
+        match request {
+            ClientConnection::Connect(conn, _) => conn.reply(denied, Address::unspecified()).await?.stream.shutdown().await?,
+            ClientConnection::Bind(conn, _) => conn.reply(denied, Address::unspecified()).await?.stream.shutdown().await?,
+            ClientConnection::UdpAssociate(conn, _) => conn.reply(denied, Address::unspecified()).await?.stream.shutdown().await?,
+        }
+        return Ok(());
+    }
+    match request {
+        ClientConnection::Connect(conn, target) => handle_connect(conn, target, &[], state).await,
+        ClientConnection::Bind(conn, _) => handle_bind(conn, state).await,
+        ClientConnection::UdpAssociate(conn, _) => handle_udp_associate(conn, state).await,
+    }
+}
+
+async fn handle_http(conn: Connect<connect::NeedReply>, target: Address, request: &HttpProxyRequest, state: &State) -> Result<()> {
+    if check_target(state, conn.context(), Command::Connect, &target).await.is_none() {
+        log::info!("denied HTTP request to {target} for {}", conn.context().client_addr());
+        conn.reply(Reply::ConnectionNotAllowed, Address::unspecified())
+            .await?
+            .stream
+            .shutdown()
+            .await?;
+        return Ok(());
+    }
+    handle_connect(conn, target, &request.upstream_prelude(), state).await
+}
+
+async fn handle_connect(conn: Connect<connect::NeedReply>, target: Address, prelude: &[u8], state: &State) -> Result<()> {
+    let ctx = OutboundContext::from(conn.context());
+    let (mut conn, mut outbound) = conn.connect_outbound(&state.connector, &target, &ctx).await?;
+    outbound.write_all(prelude).await?;
+    let user = conn.context().identity.clone();
+    match relay(&mut conn.stream, &mut outbound, user.as_deref(), state).await {
+        Ok((up, down)) => conn.record_transfer(up, down),
+        Err(err) => {
+            conn.record_error(&err);
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+async fn handle_bind(conn: Bind<bind::NeedFirstReply>, state: &State) -> Result<()> {
+    let listener = TcpListener::bind((conn.stream.local_addr()?.ip(), 0)).await?;
+    let conn = conn.reply(Reply::Succeeded, Address::from(listener.local_addr()?)).await?;
+    let accepted = tokio::time::timeout(Duration::from_secs(state.config.timeouts.connect), listener.accept()).await;
+    let (mut inbound, peer) = match accepted {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(err)) => {
+            let _ = conn.reply(Reply::from(&err), Address::unspecified()).await;
+            return Err(err.into());
+        }
+        Err(_) => {
+            let _ = conn.reply(Reply::TtlExpired, Address::unspecified()).await;
+            return Err("no connection to the BIND address".into());
+        }
+    };
+    let mut conn = conn.reply(Reply::Succeeded, Address::from(peer)).await.map_err(|(err, _)| err)?;
+    let user = conn.context().identity.clone();
+    match relay(&mut conn.stream, &mut inbound, user.as_deref(), state).await {
+        Ok((up, down)) => conn.record_transfer(up, down),
+        Err(err) => {
+            conn.record_error(&err);
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+async fn handle_udp_associate(conn: UdpAssociate<associate::NeedReply>, state: &State) -> Result<()> {
+    let local_ip = conn.stream.local_addr()?.ip();
+    let client_socket = UdpSocket::bind((local_ip, 0)).await?;
+    let unspecified = match local_ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let outbound = UdpSocket::bind((unspecified, 0)).await?;
+    let bound = client_socket.local_addr()?;
+    let mut conn = conn.reply(Reply::Succeeded, Address::from(bound)).await?;
+
+    let user = conn.context().identity.clone();
+    let mut socket = AssociatedUdpSocket::from((client_socket, MAX_UDP_PACKET_SIZE)).with_timeouts(state.timeouts);
+    if let Some(metrics) = &state.metrics {
+        socket = socket.with_metrics(metrics.clone());
+    }
+    if let (Some(accounting), Some(user)) = (&state.accounting, &user) {
+        socket = socket.with_account(accounting.account(user));
+    }
+    if let Some(throttler) = &state.throttler {
+        socket = socket.with_throttle(throttler.throttle(user.as_deref()));
+    }
+
+    let (mut up, mut down) = (0, 0);
+    let ctx = conn.context().clone();
+    let mut client = None;
+    let mut buf = vec![0; MAX_UDP_PACKET_SIZE];
+    let result: std::io::Result<()> = async {
+        loop {
+            tokio::select! {
+                received = socket.recv_from() => {
+                    let (packet, frag, target, from) = received?;
+                    if frag != 0 {
+                        continue;
+                    }
+                    let Some(addrs) = check_target(state, &ctx, Command::UdpAssociate, &target).await else {
+                        continue;
+                    };
+                    client = Some(from);
+                    // The outbound socket only reaches the addresses of its own family
+                    let Some(target) = addrs.into_iter().find(|addr| addr.is_ipv4() == local_ip.is_ipv4()) else {
+                        continue;
+                    };
+                    up += outbound.send_to(&packet, target).await? as u64;
+                }
+                received = outbound.recv_from(&mut buf) => {
+                    let (len, from) = received?;
+                    if let Some(client) = client {
+                        down += socket.send_to(&buf[..len], 0, Address::from(from), client).await? as u64;
+                    }
+                }
+                closed = conn.wait_until_closed() => break closed,
+            }
+        }
+    }
+    .await;
+    conn.record_transfer(up, down);
+    if let Err(err) = result {
+        conn.record_error(&err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Resolve `target` and check the ACL against it and its addresses, returning the addresses if it is allowed.
+///
+/// A host name that does not resolve is only checked by name; connecting to it fails anyway.
+async fn check_target(state: &State, ctx: &SessionContext, command: Command, target: &Address) -> Option<Vec<SocketAddr>> {
+    let addrs = state.resolver.resolve_address(target).await.unwrap_or_default();
+    let ips: Vec<IpAddr> = addrs.iter().map(SocketAddr::ip).collect();
+    state.config.acl.allows(ctx, command, target, &ips).then_some(addrs)
+}
+
+trait Relayed: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Relayed for S {}
+
+/// Relay `client` and `remote` until both sides are closed, returning the bytes sent by the client and by the remote.
+async fn relay<C, R>(client: &mut C, remote: &mut R, user: Option<&str>, state: &State) -> std::io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send,
+    R: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut client: Box<dyn Relayed + '_> = Box::new(client);
+    if let Some(metrics) = &state.metrics {
+        client = Box::new(Metered::new(client, metrics.clone()));
+    }
+    if let (Some(accounting), Some(user)) = (&state.accounting, user) {
+        client = Box::new(Accounted::new(client, accounting.account(user)));
+    }
+    if let Some(throttler) = &state.throttler {
+        client = Box::new(Throttled::new(client, throttler.throttle(user)));
+    }
+    let mut client = TimeLimited::new(client, state.timeouts);
+    tokio::io::copy_bidirectional(&mut client, remote).await
+}
@@ -0,0 +1,95 @@
+use crate::config::{AclConfig, Action, CommandName, Rule};
+use socks5_impl::{
+    protocol::{Address, Command},
+    server::{Cidr, SessionContext},
+};
+use std::net::IpAddr;
+
+impl AclConfig {
+    /// Whether the request of the session in `ctx` is allowed.
+    ///
+    /// `resolved` holds the addresses the host name of a domain target resolves to. The request must be allowed
+    /// to each of them, so that networks also apply to host names.
+    pub fn allows(&self, ctx: &SessionContext, command: Command, target: &Address, resolved: &[IpAddr]) -> bool {
+        let ips = match target {
+            Address::SocketAddress(addr) => vec![Some(addr.ip())],
+            Address::DomainAddress(..) if resolved.is_empty() => vec![None],
+            Address::DomainAddress(..) => resolved.iter().copied().map(Some).collect(),
+        };
+        ips.into_iter().all(|ip| self.action(ctx, command, target, ip) == Action::Allow)
+    }
+
+    fn action(&self, ctx: &SessionContext, command: Command, target: &Address, ip: Option<IpAddr>) -> Action {
+        let rule = self.rules.iter().find(|rule| rule.matches(ctx, command, target, ip));
+        rule.map_or(self.default, |rule| rule.action)
+    }
+}
+
+impl Rule {
+    /// Whether this rule matches a request to `target`, at the address `ip`.
+    fn matches(&self, ctx: &SessionContext, command: Command, target: &Address, ip: Option<IpAddr>) -> bool {
+        let client = ctx.client_addr().ip();
+        let user = ctx.identity.as_deref();
+        (self.clients.is_empty() || self.clients.iter().any(|cidr| cidr.contains(client)))
+            && (self.users.is_empty() || self.users.iter().any(|name| Some(name.as_str()) == user))
+            && (self.commands.is_empty() || self.commands.iter().any(|name| name.matches(command)))
+            && (self.targets.is_empty() || self.targets.iter().any(|pattern| target_matches(pattern, target, ip)))
+            && (self.ports.is_empty() || self.ports.contains(&target.port()))
+    }
+}
+
+impl CommandName {
+    fn matches(self, command: Command) -> bool {
+        matches!(
+            (self, command),
+            (CommandName::Connect, Command::Connect)
+                | (CommandName::Bind, Command::Bind)
+                | (CommandName::UdpAssociate, Command::UdpAssociate)
+        )
+    }
+}
+
+fn target_matches(pattern: &str, target: &Address, ip: Option<IpAddr>) -> bool {
+    if let Ok(cidr) = pattern.parse::<Cidr>() {
+        return ip.is_some_and(|ip| cidr.contains(ip));
+    }
+    match target {
+        Address::SocketAddress(_) => false,
+        Address::DomainAddress(host, _) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => host == pattern,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks_match_the_addresses_of_host_names() {
+        let acl: AclConfig = toml::from_str(
+            r#"
+            [[rules]]
+            action = "deny"
+            targets = ["127.0.0.0/8", "*.internal"]
+            "#,
+        )
+        .unwrap();
+        let ctx = SessionContext::new("192.0.2.1:40000".parse().unwrap(), "192.0.2.2:1080".parse().unwrap());
+        let allows = |target: Address, resolved: &[&str]| {
+            let resolved: Vec<IpAddr> = resolved.iter().map(|ip| ip.parse().unwrap()).collect();
+            acl.allows(&ctx, Command::Connect, &target, &resolved)
+        };
+
+        assert!(!allows(Address::from(("localtest.me", 80)), &["127.0.0.1"]));
+        assert!(!allows(Address::from(("example.com", 80)), &["198.51.100.1", "127.0.0.1"]));
+        assert!(allows(Address::from(("example.com", 80)), &["198.51.100.1"]));
+        assert!(!allows(Address::from(("db.internal", 80)), &[]));
+        assert!(!allows(Address::from("127.0.0.1:80".parse::<std::net::SocketAddr>().unwrap()), &[]));
+    }
+}
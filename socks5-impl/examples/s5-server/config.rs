@@ -0,0 +1,208 @@
+use serde::Deserialize;
+use socks5_impl::server::{AuditConfig, Cidr, ConnectionLimits, Quota, SessionTimeouts, throttle::ThrottleConfig};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The configuration file of the server, see `s5-server.toml` for an annotated example.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub auth: AuthConfig,
+    pub acl: AclConfig,
+    pub timeouts: TimeoutsConfig,
+    pub limits: ConnectionLimits,
+    pub throttle: Option<ThrottleConfig>,
+    pub accounting: Option<AccountingConfig>,
+    pub audit: Option<AuditConfig>,
+    pub log: LogConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    /// Also accept SOCKS4 and HTTP proxy clients on this listener.
+    #[serde(default)]
+    pub mixed: bool,
+    /// The load balancers sending a PROXY protocol v2 header.
+    #[serde(default)]
+    pub proxy_protocol: Vec<Cidr>,
+}
+
+impl ListenerConfig {
+    pub fn new(address: SocketAddr, mixed: bool) -> Self {
+        let proxy_protocol = Vec::new();
+        Self {
+            address,
+            mixed,
+            proxy_protocol,
+        }
+    }
+}
+
+/// The users allowed to connect. Without any user, clients are not authenticated.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub users: Vec<UserConfig>,
+    /// A file of `username:password` lines, where empty lines and lines starting with `#` are ignored.
+    pub users_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub username: String,
+    pub password: String,
+    /// The quota of this user, overriding the default quota of the accounting.
+    #[serde(default)]
+    pub quota: Option<Quota>,
+}
+
+impl AuthConfig {
+    /// The password of every user, from the configuration file and the users file.
+    pub fn passwords(&self) -> std::io::Result<HashMap<String, String>> {
+        let mut passwords = HashMap::new();
+        if let Some(path) = &self.users_file {
+            passwords.extend(read_users_file(path)?);
+        }
+        for user in &self.users {
+            passwords.insert(user.username.clone(), user.password.clone());
+        }
+        Ok(passwords)
+    }
+}
+
+fn read_users_file(path: &Path) -> std::io::Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut users = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((username, password)) = line.split_once(':') else {
+            let err = format!("{}:{}: expected username:password", path.display(), number + 1);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+        };
+        users.push((username.to_owned(), password.to_owned()));
+    }
+    Ok(users)
+}
+
+/// Rules checked in order against every request, the first matching rule deciding.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// The action when no rule matches.
+    pub default: Action,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// A rule matches a request when each of its non empty lists has a matching entry.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: Action,
+    /// The networks of the clients.
+    #[serde(default)]
+    pub clients: Vec<Cidr>,
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub commands: Vec<CommandName>,
+    /// Networks, host names and `*.domain` patterns. Networks match the addresses host names resolve to.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommandName {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+/// The timeouts, in seconds.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub handshake: u64,
+    pub connect: u64,
+    pub idle: Option<u64>,
+    pub max_lifetime: Option<u64>,
+    /// How long to wait for the sessions to finish when shutting down.
+    pub shutdown: u64,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            handshake: 10,
+            connect: 10,
+            idle: None,
+            max_lifetime: None,
+            shutdown: 30,
+        }
+    }
+}
+
+impl TimeoutsConfig {
+    pub fn session(&self) -> SessionTimeouts {
+        SessionTimeouts {
+            idle: self.idle.map(Duration::from_secs),
+            max_lifetime: self.max_lifetime.map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountingConfig {
+    /// Where the usage of the users is persisted.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub default_quota: Quota,
+    /// How often the usage is written to `path`, in seconds.
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+}
+
+fn default_flush_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// The `env_logger` filter, `info` by default. `RUST_LOG` takes precedence.
+    pub level: Option<String>,
+    /// Serve Prometheus metrics at `/metrics` on this address.
+    pub metrics: Option<SocketAddr>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|err| {
+            let err = format!("{}: {err}", path.display());
+            std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+        })
+    }
+}
@@ -0,0 +1,51 @@
+//! Send a datagram to a UDP server through a SOCKS5 server, and print the answer.
+
+use socks5_impl::{Result, client::UdpClientImpl, protocol::UserKey};
+use std::{net::SocketAddr, time::Duration};
+
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about = "SOCKS5 UDP ASSOCIATE client", long_about = None)]
+struct Args {
+    /// The SOCKS5 server
+    #[arg(short, long, value_name = "address:port", default_value = "127.0.0.1:1080")]
+    server: SocketAddr,
+
+    /// The UDP server, reached through the SOCKS5 server
+    #[arg(short, long, value_name = "host:port")]
+    target: String,
+
+    /// The payload of the datagram
+    #[arg(short, long, default_value = "hello")]
+    message: String,
+
+    /// How long to wait for the answer, in seconds
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+
+    /// The username on the SOCKS5 server
+    #[arg(short, long, requires = "password")]
+    username: Option<String>,
+
+    /// The password on the SOCKS5 server
+    #[arg(short, long, requires = "username")]
+    password: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Args = clap::Parser::parse();
+
+    let (host, port) = args.target.rsplit_once(':').ok_or("the target must be given as host:port")?;
+    let port = port.parse::<u16>().map_err(|err| format!("invalid port {port}: {err}"))?;
+    let auth = args
+        .username
+        .zip(args.password)
+        .map(|(username, password)| UserKey::new(username, password));
+
+    let client = UdpClientImpl::datagram(args.server, (host, port), auth).await?;
+    let answer = client
+        .transfer_data(args.message.as_bytes(), Duration::from_secs(args.timeout))
+        .await?;
+    println!("{}", String::from_utf8_lossy(&answer));
+    Ok(())
+}
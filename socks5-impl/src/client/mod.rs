@@ -1,13 +1,10 @@
 use crate::{
     error::{Error, Result},
-    protocol::{Address, AddressType, AuthMethod, Command, Reply, StreamOperation, UserKey, Version},
-};
-use std::{
-    fmt::Debug,
-    io::Cursor,
-    net::{SocketAddr, ToSocketAddrs},
-    time::Duration,
+    happy_eyeballs::HappyEyeballs,
+    protocol::{Address, AddressType, AuthMethod, Command, Reply, Request, StreamOperation, UserKey, Version, handshake},
+    resolver::{Resolver, SystemResolver},
 };
+use std::{fmt::Debug, io::Cursor, net::SocketAddr, time::Duration};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufStream},
     net::{TcpStream, UdpSocket},
@@ -232,6 +229,154 @@ where
     stream.read_final().await
 }
 
+/// Opens the TCP connection to a SOCKS5 server given by socket address or domain name.
+///
+/// When the server name resolves to several addresses, the connection attempts are raced with
+/// [`HappyEyeballs`], and the address that won is returned alongside the stream.
+/// Use [`HappyEyeballs::connect_address`] directly for a custom resolver or attempt delay.
+///
+/// ```no_run
+/// # use socks5_impl::Result;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<()> {
+/// use socks5_impl::client;
+/// use tokio::io::BufStream;
+///
+/// let (stream, _proxy_addr) = client::connect_proxy(("my-proxy-server.com", 54321)).await?;
+/// let mut stream = BufStream::new(stream);
+/// client::connect(&mut stream, ("google.com", 80), None).await?;
+///
+/// # Ok(())
+/// # }
+/// ```
+pub async fn connect_proxy<A: Into<Address>>(proxy: A) -> Result<(TcpStream, SocketAddr)> {
+    let proxy = proxy.into();
+    Ok(HappyEyeballs::default().connect_address(&proxy, &SystemResolver).await?)
+}
+
+/// Connect to a SOCKS5 server over TLS, like [`connect_proxy`], verifying its certificate for the name or the
+/// IP address of `proxy`. Behind the `tls` feature.
+///
+/// The SOCKS5 commands, such as [`connect`], then run over the returned TLS stream, so that the RFC 1929
+/// password is not sent in cleartext. A client certificate is presented if `config` has one.
+///
+/// ```no_run
+/// # use socks5_impl::Result;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<()> {
+/// use socks5_impl::{client, protocol::UserKey, rustls};
+/// use std::sync::Arc;
+///
+/// # let config: Arc<rustls::ClientConfig> = unimplemented!();
+/// let (mut stream, _proxy_addr) = client::connect_proxy_tls(("my-proxy-server.com", 54321), config).await?;
+/// let auth = UserKey::new("alice", "secret");
+/// client::connect(&mut stream, ("google.com", 80), Some(auth)).await?;
+///
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "tls")]
+pub async fn connect_proxy_tls<A: Into<Address>>(
+    proxy: A,
+    config: std::sync::Arc<tokio_rustls::rustls::ClientConfig>,
+) -> Result<(tokio_rustls::client::TlsStream<TcpStream>, SocketAddr)> {
+    let proxy = proxy.into();
+    let server_name = tls_server_name(&proxy)?;
+    let (stream, proxy_addr) = connect_proxy(proxy).await?;
+    let stream = tokio_rustls::TlsConnector::from(config).connect(server_name, stream).await?;
+    Ok((stream, proxy_addr))
+}
+
+/// The name the certificate of the SOCKS5 server at `proxy` is verified for.
+#[cfg(feature = "tls")]
+pub(crate) fn tls_server_name(proxy: &Address) -> Result<tokio_rustls::rustls::pki_types::ServerName<'static>> {
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    match proxy {
+        Address::SocketAddress(addr) => Ok(ServerName::from(addr.ip())),
+        Address::DomainAddress(domain, _) => {
+            ServerName::try_from(domain.to_string()).map_err(|err| Error::InvalidAddress(format!("{domain}: {err}")))
+        }
+    }
+}
+
+/// Connect to a SOCKS5 server over WebSocket, like [`connect_proxy`], requesting the upgrade for `path`.
+/// Behind the `websocket` feature.
+///
+/// The SOCKS5 commands, such as [`connect`], then run over the returned stream. For `wss://`, request the
+/// upgrade over a TLS stream with [`websocket::connect`](crate::websocket::connect) instead.
+///
+/// ```no_run
+/// # use socks5_impl::Result;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<()> {
+/// use socks5_impl::client;
+///
+/// let (mut stream, _proxy_addr) = client::connect_proxy_websocket(("my-proxy-server.com", 8080), "/socks").await?;
+/// client::connect(&mut stream, ("google.com", 80), None).await?;
+///
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "websocket")]
+pub async fn connect_proxy_websocket<A: Into<Address>>(
+    proxy: A,
+    path: &str,
+) -> Result<(crate::websocket::WebSocketStream<TcpStream>, SocketAddr)> {
+    let proxy = proxy.into();
+    let (stream, proxy_addr) = connect_proxy(proxy.clone()).await?;
+    let stream = crate::websocket::connect(stream, &proxy.to_string(), path).await?;
+    Ok((stream, proxy_addr))
+}
+
+/// Connect to a SOCKS5 server over QUIC, verifying its certificate for the name or the IP address of `proxy`.
+/// Behind the `quic` feature.
+///
+/// Each stream [opened](crate::quic::QuicConnection::open) on the returned connection carries a SOCKS5 session,
+/// such as [`connect`] or [`udp_associate`]. `config` must allow TLS 1.3.
+///
+/// ```no_run
+/// # use socks5_impl::Result;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<()> {
+/// use socks5_impl::{client, rustls};
+/// use std::sync::Arc;
+///
+/// # let config: Arc<rustls::ClientConfig> = unimplemented!();
+/// let (connection, _proxy_addr) = client::connect_proxy_quic(("my-proxy-server.com", 54321), config).await?;
+/// let mut stream = connection.open().await?;
+/// client::connect(&mut stream, ("google.com", 80), None).await?;
+///
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "quic")]
+pub async fn connect_proxy_quic<A: Into<Address>>(
+    proxy: A,
+    config: std::sync::Arc<tokio_rustls::rustls::ClientConfig>,
+) -> Result<(crate::quic::QuicConnection, SocketAddr)> {
+    let proxy = proxy.into();
+    let server_name = match &proxy {
+        Address::SocketAddress(addr) => addr.ip().to_string(),
+        Address::DomainAddress(domain, _) => domain.to_string(),
+    };
+    let proxy_addr = SystemResolver
+        .resolve_address(&proxy)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or("InvalidAddress")?;
+    let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(config).map_err(|err| Error::String(format!("QUIC: {err}")))?;
+    let local_addr = if proxy_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let mut endpoint = quinn::Endpoint::client(local_addr.parse()?)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(std::sync::Arc::new(crypto)));
+    let connecting = endpoint
+        .connect(proxy_addr, &server_name)
+        .map_err(|err| Error::String(format!("QUIC: {err}")))?;
+    let connection = connecting.await.map_err(crate::quic::connection_error)?;
+    Ok((crate::quic::QuicConnection::new(connection), proxy_addr))
+}
+
 /// Proxifies a TCP connection. Performs the [`CONNECT`] command under the hood.
 ///
 /// [`CONNECT`]: https://tools.ietf.org/html/rfc1928#page-6
@@ -258,6 +403,76 @@ where
     init(socket, Command::Connect, addr, auth).await
 }
 
+/// Connects to `addr` through the proxy like [`connect`], writing the method selection, the credentials, the
+/// [`CONNECT`] request and `early_data` in a single flight rather than waiting for each answer of the proxy.
+///
+/// Only the method `auth` needs is offered: username and password with credentials, no authentication otherwise.
+/// If the proxy selects another method, rejects the credentials or fails to connect, an error is returned and
+/// `early_data` is lost. Otherwise `early_data`, which may be empty, is the first payload the target receives.
+///
+/// ```no_run
+/// # use socks5_impl::Result;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<()> {
+/// use socks5_impl::{client, protocol::UserKey};
+///
+/// let (mut stream, _proxy_addr) = client::connect_proxy(("my-proxy-server.com", 54321)).await?;
+/// let auth = UserKey::new("user", "pass");
+/// let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+/// client::connect_pipelined(&mut stream, ("example.com", 80), Some(auth), request).await?;
+///
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`CONNECT`]: https://tools.ietf.org/html/rfc1928#page-6
+pub async fn connect_pipelined<S, A>(socket: &mut S, addr: A, auth: Option<UserKey>, early_data: &[u8]) -> Result<Address>
+where
+    S: AsyncWriteExt + AsyncReadExt + Send + Unpin,
+    A: Into<Address>,
+{
+    let method = if auth.is_some() { AuthMethod::UserPass } else { AuthMethod::NoAuth };
+    let mut flight = Vec::new();
+    handshake::Request::new(vec![method]).write_to_buf(&mut flight);
+    if let Some(user_key) = auth {
+        if user_key.username.len() > 255 || user_key.password.len() > 255 {
+            return Err("Too long string".into());
+        }
+        handshake::password_method::Request { user_key }.write_to_buf(&mut flight);
+    }
+    Request::new(Command::Connect, addr.into()).write_to_buf(&mut flight);
+    flight.extend_from_slice(early_data);
+    socket.write_all(&flight).await?;
+    socket.flush().await?;
+
+    let selected = socket.read_selection_msg().await?;
+    if selected != method {
+        return Err(Error::InvalidAuthMethod(selected));
+    }
+    if method == AuthMethod::UserPass {
+        socket.read_auth_version().await?;
+        socket.read_auth_status().await?;
+    }
+    socket.read_final().await
+}
+
+/// Requests a UDP association over `socket`. Performs the [`UDP ASSOCIATE`] command under the hood, and returns
+/// the relay address of the proxy.
+///
+/// This is for the transports relaying the packets themselves, such as the
+/// [`QuicAssociation`](crate::quic::QuicAssociation) of a QUIC stream; see [`SocksDatagram`] to relay them over UDP.
+///
+/// `addr` is the address the client expects to send the packets from, usually the unspecified one.
+///
+/// [`UDP ASSOCIATE`]: https://tools.ietf.org/html/rfc1928#page-7
+pub async fn udp_associate<S, A>(socket: &mut S, addr: A, auth: Option<UserKey>) -> Result<Address>
+where
+    S: AsyncWriteExt + AsyncReadExt + Send + Unpin,
+    A: Into<Address>,
+{
+    init(socket, Command::UdpAssociate, addr, auth).await
+}
+
 /// A listener that accepts TCP connections through a proxy.
 ///
 /// ```no_run
@@ -323,11 +538,25 @@ where
     /// Creates `SocksDatagram`. Performs [`UDP ASSOCIATE`] under the hood.
     ///
     /// [`UDP ASSOCIATE`]: https://tools.ietf.org/html/rfc1928#page-7
-    pub async fn udp_associate(mut stream: S, socket: UdpSocket, auth: Option<UserKey>) -> Result<Self> {
+    pub async fn udp_associate(stream: S, socket: UdpSocket, auth: Option<UserKey>) -> Result<Self> {
+        Self::udp_associate_with_resolver(stream, socket, auth, &SystemResolver).await
+    }
+
+    /// Creates `SocksDatagram` like [`udp_associate`](Self::udp_associate),
+    /// resolving the relay address returned by the proxy with the given resolver.
+    pub async fn udp_associate_with_resolver<R>(mut stream: S, socket: UdpSocket, auth: Option<UserKey>, resolver: &R) -> Result<Self>
+    where
+        R: Resolver + Sync + ?Sized,
+    {
         let addr = if socket.local_addr()?.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
         let addr = addr.parse::<SocketAddr>()?;
         let proxy_addr = init(&mut stream, Command::UdpAssociate, addr, auth).await?;
-        let addr = proxy_addr.to_socket_addrs()?.next().ok_or("InvalidAddress")?;
+        let addr = resolver
+            .resolve_address(&proxy_addr)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or("InvalidAddress")?;
         socket.connect(addr).await?;
         Ok(Self {
             socket,
@@ -0,0 +1,169 @@
+//! Happy Eyeballs ([RFC 8305](https://tools.ietf.org/html/rfc8305)) connection establishment.
+//!
+//! Instead of trying the resolved addresses of a host one after another, connection attempts are
+//! started in a staggered fashion and raced against each other, alternating between address families.
+//! A blackholed address therefore only delays the connection by the attempt delay, not by the OS timeout.
+
+use crate::{protocol::Address, resolver::Resolver};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{net::TcpStream, task::JoinSet};
+
+/// The connection attempt delay recommended by RFC 8305.
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Configuration of the Happy Eyeballs algorithm.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HappyEyeballs {
+    attempt_delay: Duration,
+    first_address_family_count: usize,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> Self {
+        Self {
+            attempt_delay: DEFAULT_ATTEMPT_DELAY,
+            first_address_family_count: 1,
+        }
+    }
+}
+
+impl HappyEyeballs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the delay before starting the next connection attempt while the previous ones are still pending.
+    pub fn with_attempt_delay(mut self, attempt_delay: Duration) -> Self {
+        self.attempt_delay = attempt_delay;
+        self
+    }
+
+    /// Set how many addresses of the first address family are tried before switching to the other family.
+    ///
+    /// The first family is the family of the first resolved address, so it follows the order of the
+    /// [`Resolver`], e.g. a [`PreferenceResolver`](crate::resolver::PreferenceResolver).
+    pub fn with_first_address_family_count(mut self, count: usize) -> Self {
+        self.first_address_family_count = count.max(1);
+        self
+    }
+
+    /// Order `addrs` for connection attempts, interleaving the address families.
+    pub fn sort(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let Some(first) = addrs.first() else {
+            return addrs;
+        };
+        let first_is_ipv6 = first.is_ipv6();
+        let (mut first_family, other_family): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+        let head = first_family.len().min(self.first_address_family_count);
+        let mut sorted: Vec<_> = first_family.drain(..head).collect();
+        let (mut first_family, mut other_family) = (first_family.into_iter(), other_family.into_iter());
+        loop {
+            match (other_family.next(), first_family.next()) {
+                (None, None) => break,
+                (other, first) => sorted.extend(other.into_iter().chain(first)),
+            }
+        }
+        sorted
+    }
+
+    /// Race connection attempts to `addrs`, returning the first established stream and the address that won.
+    ///
+    /// A new attempt is started every attempt delay, or as soon as the previous attempt fails.
+    /// Pending attempts are cancelled once one succeeds. If every attempt fails, the last error is returned.
+    pub async fn connect(&self, addrs: Vec<SocketAddr>) -> std::io::Result<(TcpStream, SocketAddr)> {
+        let mut pending = self.sort(addrs).into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_err = None;
+
+        loop {
+            if attempts.is_empty() {
+                let Some(addr) = pending.next() else {
+                    let err = || std::io::Error::new(std::io::ErrorKind::NotFound, "no address to connect to");
+                    return Err(last_err.unwrap_or_else(err));
+                };
+                attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+            }
+
+            let has_pending = pending.len() > 0;
+            tokio::select! {
+                Some(joined) = attempts.join_next() => match joined {
+                    Ok((addr, Ok(stream))) => return Ok((stream, addr)),
+                    Ok((_, Err(err))) => last_err = Some(err),
+                    Err(err) => last_err = Some(std::io::Error::other(err)),
+                },
+                _ = tokio::time::sleep(self.attempt_delay), if has_pending => {},
+            }
+
+            // Either the delay elapsed or an attempt failed: start the next attempt right away.
+            if let Some(addr) = pending.next() {
+                attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+            }
+        }
+    }
+
+    /// Resolve `addr` with `resolver` and race connection attempts to the results.
+    pub async fn connect_address<R>(&self, addr: &Address, resolver: &R) -> std::io::Result<(TcpStream, SocketAddr)>
+    where
+        R: Resolver + Sync + ?Sized,
+    {
+        self.connect(resolver.resolve_address(addr).await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn sort_interleaves_families() {
+        let he = HappyEyeballs::new();
+        let sorted = he.sort(addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]));
+        assert_eq!(sorted, addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]));
+
+        let he = HappyEyeballs::new().with_first_address_family_count(2);
+        let sorted = he.sort(addrs(&["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1", "[::1]:1", "[::2]:1"]));
+        assert_eq!(sorted, addrs(&["10.0.0.1:1", "10.0.0.2:1", "[::1]:1", "10.0.0.3:1", "[::2]:1"]));
+
+        assert!(he.sort(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_attempt_starts_next_immediately() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let he = HappyEyeballs::new().with_attempt_delay(Duration::from_secs(10));
+        let start = Instant::now();
+        let (_stream, winner) = he.connect(vec![closed, open]).await.unwrap();
+        assert_eq!(winner, open);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let err = he.connect(vec![closed]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn stalled_attempt_is_overtaken() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // TEST-NET-1 is never routed: the attempt either hangs or fails, the second address must win anyway.
+        let blackhole = "192.0.2.1:9".parse().unwrap();
+
+        let he = HappyEyeballs::new().with_attempt_delay(Duration::from_millis(50));
+        let (_stream, winner) = tokio::time::timeout(Duration::from_secs(5), he.connect(vec![blackhole, open]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(winner, open);
+    }
+}
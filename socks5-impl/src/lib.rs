@@ -1,8 +1,20 @@
 #[cfg(feature = "client")]
 pub mod client;
 pub(crate) mod error;
+pub mod happy_eyeballs;
+#[cfg(feature = "mux")]
+pub mod mux;
 pub mod protocol;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod resolver;
 #[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 pub use crate::error::{Error, Result};
+#[cfg(feature = "quic")]
+pub use quinn;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
@@ -0,0 +1,593 @@
+//! Stream multiplexing, behind the `mux` feature.
+//!
+//! A [`MuxSession`] carries many [`MuxStream`]s over a single connection, such as a TCP or TLS one to an upstream
+//! SOCKS5 server, so that each SOCKS5 session does not pay for a connection and its handshakes of its own. The
+//! framing is that of yamux: 12 byte frame headers and a 256 KiB receive window per stream.
+//!
+//! ```no_run
+//! # #[cfg(feature = "client")]
+//! # async fn example() -> socks5_impl::Result<()> {
+//! use socks5_impl::{client, mux::{Mode, MuxSession}};
+//!
+//! let (link, _proxy_addr) = client::connect_proxy(("my-proxy-server.com", 54321)).await?;
+//! let session = MuxSession::new(link, Mode::Client);
+//! for target in ["google.com", "example.com"] {
+//!     let mut stream = session.open()?;
+//!     client::connect(&mut stream, (target, 80), None).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use bytes::{BufMut, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{Notify, mpsc},
+};
+
+const VERSION: u8 = 0;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_GO_AWAY: u8 = 3;
+
+const FLAG_SYN: u16 = 1;
+const FLAG_ACK: u16 = 2;
+const FLAG_FIN: u16 = 4;
+const FLAG_RST: u16 = 8;
+
+const HEADER_LEN: usize = 12;
+
+/// The receive window of a new stream, per the yamux specification.
+const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// The largest payload of the data frames written, larger writes are split.
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+
+/// The streams opened by the peer and not accepted yet, beyond which new ones are reset.
+const ACCEPT_BACKLOG: usize = 256;
+
+/// The replies to the frames of the peer, such as the acknowledgements of its pings, queued at most for the link;
+/// past them, the frames of the peer are not read until the link catches up.
+const REPLY_BACKLOG: usize = 64;
+
+/// The end of a multiplexed connection: the client opens odd numbered streams, the server even numbered ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Client,
+    Server,
+}
+
+/// Many streams over a single connection.
+///
+/// The connection is driven by background tasks until the session and all its streams are dropped, or until the
+/// peer closes it.
+#[derive(Debug)]
+pub struct MuxSession {
+    shared: Arc<Shared>,
+    incoming: Mutex<mpsc::Receiver<MuxStream>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    mode: Mode,
+    streams: Mutex<HashMap<u32, Arc<Mutex<StreamState>>>>,
+    /// The frames of the streams, which their windows bound.
+    frames: mpsc::UnboundedSender<Bytes>,
+    next_id: AtomicU32,
+    /// Set once no new stream can be opened.
+    closed: AtomicBool,
+    /// The value of the last ping answered by the peer.
+    pong: AtomicU32,
+    /// Notified to close the link, when the peer stops answering the pings.
+    dead: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct StreamState {
+    received: BytesMut,
+    /// The bytes read since the last window update sent to the peer.
+    unacked: u32,
+    recv_window: u32,
+    send_window: u32,
+    fin_received: bool,
+    fin_sent: bool,
+    reset: bool,
+    link_closed: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            received: BytesMut::new(),
+            unacked: 0,
+            recv_window: INITIAL_WINDOW,
+            send_window: INITIAL_WINDOW,
+            fin_received: false,
+            fin_sent: false,
+            reset: false,
+            link_closed: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake(&mut self) {
+        self.wake_reader();
+        self.wake_writer();
+    }
+}
+
+fn frame(ty: u8, flags: u16, id: u32, len: u32, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    buf.put_u8(VERSION);
+    buf.put_u8(ty);
+    buf.put_u16(flags);
+    buf.put_u32(id);
+    buf.put_u32(len);
+    buf.extend_from_slice(payload);
+    buf.freeze()
+}
+
+impl Shared {
+    fn send(&self, frame: Bytes) {
+        // The link is gone if the writer is, which the streams learn from the reader
+        let _ = self.frames.send(frame);
+    }
+
+    fn insert(self: &Arc<Self>, id: u32) -> MuxStream {
+        let state = Arc::new(Mutex::new(StreamState::new()));
+        self.streams.lock().unwrap().insert(id, state.clone());
+        MuxStream {
+            id,
+            state,
+            shared: self.clone(),
+        }
+    }
+}
+
+impl MuxSession {
+    /// Multiplex streams over `link`, as its client or server end.
+    ///
+    /// This must be called from within a tokio runtime, which drives the link.
+    pub fn new<S>(link: S, mode: Mode) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(link);
+        let (frames, queued) = mpsc::unbounded_channel();
+        let (replies, queued_replies) = mpsc::channel(REPLY_BACKLOG);
+        let (accepted, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let shared = Arc::new(Shared {
+            mode,
+            streams: Mutex::default(),
+            frames,
+            next_id: AtomicU32::new(if mode == Mode::Client { 1 } else { 2 }),
+            closed: AtomicBool::new(false),
+            pong: AtomicU32::new(0),
+            dead: Arc::default(),
+        });
+        tokio::spawn(write_frames(writer, queued, queued_replies));
+        let weak = Arc::downgrade(&shared);
+        let dead = shared.dead.clone();
+        tokio::spawn(async move {
+            let closed = tokio::select! {
+                closed = read_frames(reader, weak.clone(), replies, accepted) => closed,
+                _ = dead.notified() => Err(Error::new(ErrorKind::TimedOut, "ping timed out")),
+            };
+            if let Err(err) = closed {
+                log::debug!("mux session closed: {err}");
+            }
+            let Some(shared) = weak.upgrade() else {
+                return;
+            };
+            shared.closed.store(true, Ordering::Release);
+            for (_, state) in shared.streams.lock().unwrap().drain() {
+                let mut state = state.lock().unwrap();
+                state.link_closed = true;
+                state.wake();
+            }
+        });
+        Self {
+            shared,
+            incoming: Mutex::new(incoming),
+        }
+    }
+
+    /// Ping the peer every `interval`, and close the link if it did not answer the previous ping by then.
+    ///
+    /// This notices a link silently dropped on the way, such as by a NAT, which would otherwise look idle.
+    pub fn with_keepalive(self, interval: Duration) -> Self {
+        tokio::spawn(keep_alive(Arc::downgrade(&self.shared), interval));
+        self
+    }
+
+    /// Open a new stream.
+    pub fn open(&self) -> std::io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(Error::new(ErrorKind::ConnectionAborted, "mux session is closed"));
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.shared.insert(id);
+        self.shared.send(frame(TYPE_WINDOW_UPDATE, FLAG_SYN, id, 0, &[]));
+        Ok(stream)
+    }
+
+    /// Accept a stream opened by the peer.
+    pub async fn accept(&self) -> std::io::Result<MuxStream> {
+        std::future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    /// Polls to accept a stream opened by the peer.
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<MuxStream>> {
+        match std::task::ready!(self.incoming.lock().unwrap().poll_recv(cx)) {
+            Some(stream) => Poll::Ready(Ok(stream)),
+            None => Poll::Ready(Err(Error::new(ErrorKind::ConnectionAborted, "mux session is closed"))),
+        }
+    }
+
+    /// Whether the link is closed, or the peer does not accept new streams.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Get the end of the link this session is.
+    pub fn mode(&self) -> Mode {
+        self.shared.mode
+    }
+}
+
+async fn keep_alive(shared: Weak<Shared>, interval: Duration) {
+    let mut sent = 0u32;
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if shared.pong.load(Ordering::Acquire) != sent {
+            shared.dead.notify_one();
+            return;
+        }
+        sent = sent.wrapping_add(1);
+        shared.send(frame(TYPE_PING, FLAG_SYN, 0, sent, &[]));
+    }
+}
+
+async fn write_frames<W: AsyncWrite>(writer: W, mut queued: mpsc::UnboundedReceiver<Bytes>, mut replies: mpsc::Receiver<Bytes>) {
+    tokio::pin!(writer);
+    loop {
+        // The replies go first, so that a stream is acknowledged before its data
+        let frame = tokio::select! {
+            biased;
+            Some(frame) = replies.recv() => frame,
+            frame = queued.recv() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+        };
+        if writer.write_all(&frame).await.is_err() {
+            return;
+        }
+        if queued.is_empty() && replies.is_empty() && writer.flush().await.is_err() {
+            return;
+        }
+    }
+    // Every stream and the session are dropped
+    let _ = writer.shutdown().await;
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    shared: Weak<Shared>,
+    replies: mpsc::Sender<Bytes>,
+    accepted: mpsc::Sender<MuxStream>,
+) -> std::io::Result<()> {
+    let invalid = |msg: &str| Err(Error::new(ErrorKind::InvalidData, format!("mux: {msg}")));
+    let mut header = [0; HEADER_LEN];
+    loop {
+        reader.read_exact(&mut header).await?;
+        let (version, ty) = (header[0], header[1]);
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        let id = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let len = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            return invalid("unknown version");
+        }
+        let mut payload = BytesMut::new();
+        if ty == TYPE_DATA {
+            if len > INITIAL_WINDOW {
+                return invalid("frame larger than the window");
+            }
+            payload.resize(len as usize, 0);
+            reader.read_exact(&mut payload).await?;
+        }
+        let Some(shared) = shared.upgrade() else {
+            return Ok(());
+        };
+        match ty {
+            TYPE_DATA | TYPE_WINDOW_UPDATE => {}
+            TYPE_PING => {
+                if flags & FLAG_ACK != 0 {
+                    shared.pong.store(len, Ordering::Release);
+                } else if flags & FLAG_SYN != 0 {
+                    drop(shared);
+                    let _ = replies.send(frame(TYPE_PING, FLAG_ACK, 0, len, &[])).await;
+                }
+                continue;
+            }
+            TYPE_GO_AWAY => {
+                shared.closed.store(true, Ordering::Release);
+                continue;
+            }
+            _ => return invalid("unknown frame type"),
+        }
+
+        let state = if flags & FLAG_SYN != 0 {
+            let opened_by_client = id % 2 == 1;
+            if id == 0 || opened_by_client == (shared.mode == Mode::Client) || shared.streams.lock().unwrap().contains_key(&id) {
+                return invalid("invalid stream id");
+            }
+            let Ok(backlog) = accepted.try_reserve() else {
+                drop(shared);
+                let _ = replies.send(frame(TYPE_WINDOW_UPDATE, FLAG_RST, id, 0, &[])).await;
+                continue;
+            };
+            let stream = shared.insert(id);
+            let state = stream.state.clone();
+            drop(shared);
+            let _ = replies.send(frame(TYPE_WINDOW_UPDATE, FLAG_ACK, id, 0, &[])).await;
+            backlog.send(stream);
+            state
+        } else {
+            match shared.streams.lock().unwrap().get(&id) {
+                Some(state) => state.clone(),
+                None => continue,
+            }
+        };
+
+        let mut state = state.lock().unwrap();
+        if ty == TYPE_DATA {
+            if len > state.recv_window {
+                return invalid("frame larger than the window");
+            }
+            state.recv_window -= len;
+            state.received.extend_from_slice(&payload);
+            state.wake_reader();
+        } else {
+            state.send_window = state.send_window.saturating_add(len);
+            state.wake_writer();
+        }
+        if flags & FLAG_FIN != 0 {
+            state.fin_received = true;
+            state.wake_reader();
+        }
+        if flags & FLAG_RST != 0 {
+            state.reset = true;
+            state.wake();
+        }
+    }
+}
+
+/// A stream of a [`MuxSession`].
+///
+/// Shutting it down half-closes it, like a TCP stream; dropping it before the peer half-closed its end resets it.
+#[derive(Debug)]
+pub struct MuxStream {
+    id: u32,
+    state: Arc<Mutex<StreamState>>,
+    shared: Arc<Shared>,
+}
+
+impl MuxStream {
+    /// Get the id of this stream, which is the same on both ends of the link.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.received.is_empty() {
+            let n = state.received.len().min(buf.remaining());
+            buf.put_slice(&state.received.split_to(n));
+            state.unacked += n as u32;
+            // Grant the window back once half of it is consumed, as yamux implementations do
+            if state.unacked >= INITIAL_WINDOW / 2 && !state.fin_received {
+                let delta = std::mem::take(&mut state.unacked);
+                state.recv_window += delta;
+                self.shared.send(frame(TYPE_WINDOW_UPDATE, 0, self.id, delta, &[]));
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if state.fin_received {
+            return Poll::Ready(Ok(()));
+        }
+        if state.reset {
+            return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+        }
+        if state.link_closed {
+            return Poll::Ready(Err(Error::new(ErrorKind::ConnectionAborted, "mux session is closed")));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if state.reset {
+            return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+        }
+        if state.link_closed {
+            return Poll::Ready(Err(Error::new(ErrorKind::ConnectionAborted, "mux session is closed")));
+        }
+        if state.fin_sent {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(MAX_FRAME_PAYLOAD).min(state.send_window as usize);
+        state.send_window -= n as u32;
+        self.shared.send(frame(TYPE_DATA, 0, self.id, n as u32, &buf[..n]));
+        Poll::Ready(Ok(n))
+    }
+
+    /// The frames are flushed to the link as soon as none is left to write.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.fin_sent && !state.reset && !state.link_closed {
+            state.fin_sent = true;
+            self.shared.send(frame(TYPE_DATA, FLAG_FIN, self.id, 0, &[]));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.shared.streams.lock().unwrap().remove(&self.id);
+        let state = self.state.lock().unwrap();
+        if state.reset || state.link_closed {
+            return;
+        }
+        if !state.fin_sent {
+            self.shared.send(frame(TYPE_DATA, FLAG_FIN, self.id, 0, &[]));
+        }
+        if !state.fin_received {
+            self.shared.send(frame(TYPE_WINDOW_UPDATE, FLAG_RST, self.id, 0, &[]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn streams_share_a_link() {
+        let (client, server) = tokio::io::duplex(4096);
+        let client = MuxSession::new(client, Mode::Client);
+        let server = MuxSession::new(server, Mode::Server);
+        tokio::spawn(async move {
+            while let Ok(mut stream) = server.accept().await {
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    stream.read_to_end(&mut received).await.unwrap();
+                    stream.write_all(&received).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        // More than a receive window each, so that the window updates are needed
+        let sent: Vec<u8> = (0..600_000u32).map(|i| i as u8).collect();
+        let echo = |id: u8| {
+            let mut stream = client.open().unwrap();
+            let sent: Vec<u8> = sent.iter().map(|byte| byte ^ id).collect();
+            async move {
+                let (mut reader, mut writer) = tokio::io::split(&mut stream);
+                let write = async {
+                    writer.write_all(&sent).await.unwrap();
+                    writer.shutdown().await.unwrap();
+                };
+                let mut echoed = Vec::new();
+                let read = reader.read_to_end(&mut echoed);
+                let (_, read) = tokio::join!(write, read);
+                read.unwrap();
+                echoed == sent
+            }
+        };
+        let (first, second) = tokio::join!(echo(1), echo(2));
+        assert!(first && second);
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_is_reset() {
+        let (client, server) = tokio::io::duplex(4096);
+        let client = MuxSession::new(client, Mode::Client);
+        let server = MuxSession::new(server, Mode::Server);
+
+        let mut stream = client.open().unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut accepted = server.accept().await.unwrap();
+        drop(stream);
+
+        // The data written before the drop is still read, then the end of the stream
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        // Writing to it fails once the reset is received
+        let mut result = Ok(());
+        for _ in 0..100 {
+            result = accepted.write_all(b"world").await;
+            if result.is_err() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::ConnectionReset);
+
+        // Once the session of the peer is gone, so is the link
+        drop(client);
+        assert!(server.accept().await.is_err());
+        assert!(server.is_closed());
+    }
+
+    #[tokio::test]
+    async fn silent_link_is_closed_by_keepalive() {
+        let interval = Duration::from_millis(50);
+        let (client, server) = tokio::io::duplex(4096);
+        let client = MuxSession::new(client, Mode::Client).with_keepalive(interval);
+        let _server = MuxSession::new(server, Mode::Server);
+
+        // The pings of a live link are answered
+        tokio::time::sleep(interval * 4).await;
+        assert!(!client.is_closed());
+
+        // The pings of a link nobody answers on are not
+        let (silent, _peer) = tokio::io::duplex(4096);
+        let silent = MuxSession::new(silent, Mode::Client).with_keepalive(interval);
+        let mut stream = silent.open().unwrap();
+        assert!(silent.accept().await.is_err());
+        assert!(silent.is_closed());
+        let err = stream.read(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+    }
+}
@@ -169,6 +169,9 @@ impl StreamOperation for Address {
     }
 }
 
+/// Resolves domain addresses with the blocking system resolver.
+///
+/// Do not use this on an async executor thread, use a [`Resolver`](crate::resolver::Resolver) instead.
 impl ToSocketAddrs for Address {
     type Iter = std::vec::IntoIter<SocketAddr>;
 
@@ -189,6 +192,9 @@ impl std::fmt::Display for Address {
     }
 }
 
+/// Converts socket addresses and domain addresses holding an IP literal, without any name resolution.
+///
+/// Other domain addresses are rejected, resolve them with a [`Resolver`](crate::resolver::Resolver) instead.
 impl TryFrom<Address> for SocketAddr {
     type Error = std::io::Error;
 
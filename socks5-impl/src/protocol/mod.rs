@@ -4,6 +4,7 @@ pub mod handshake;
 mod reply;
 mod request;
 mod response;
+pub mod socks4;
 mod udp;
 
 pub use self::{
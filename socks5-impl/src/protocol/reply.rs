@@ -49,6 +49,21 @@ impl From<Reply> for u8 {
     }
 }
 
+/// Maps an error from establishing an outbound connection to the closest SOCKS5 reply code.
+impl From<&std::io::Error> for Reply {
+    fn from(err: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match err.kind() {
+            ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            ErrorKind::HostUnreachable | ErrorKind::TimedOut | ErrorKind::AddrNotAvailable => Reply::HostUnreachable,
+            ErrorKind::PermissionDenied => Reply::ConnectionNotAllowed,
+            ErrorKind::Unsupported => Reply::AddressTypeNotSupported,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
 impl std::fmt::Display for Reply {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
@@ -96,4 +111,14 @@ mod tests {
         assert_eq!(u8::from(Reply::CommandNotSupported), 0x07);
         assert_eq!(u8::from(Reply::AddressTypeNotSupported), 0x08);
     }
+
+    #[test]
+    fn reply_from_io_error() {
+        use std::io::{Error, ErrorKind};
+        assert_eq!(Reply::from(&Error::from(ErrorKind::ConnectionRefused)), Reply::ConnectionRefused);
+        assert_eq!(Reply::from(&Error::from(ErrorKind::NetworkUnreachable)), Reply::NetworkUnreachable);
+        assert_eq!(Reply::from(&Error::from(ErrorKind::TimedOut)), Reply::HostUnreachable);
+        assert_eq!(Reply::from(&Error::from(ErrorKind::PermissionDenied)), Reply::ConnectionNotAllowed);
+        assert_eq!(Reply::from(&Error::other("boom")), Reply::GeneralFailure);
+    }
 }
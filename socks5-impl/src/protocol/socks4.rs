@@ -0,0 +1,193 @@
+//! SOCKS4 and SOCKS4a messages, for servers also accepting SOCKS4 clients.
+
+use crate::protocol::{Address, Command, Reply, StreamOperation, Version};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const MAX_FIELD_LEN: usize = 255;
+
+/// SOCKS4 request, with the SOCKS4a domain extension
+///
+/// ```plain
+/// +----+----+---------+--------+-----------+------+-----------+------+
+/// | VN | CD | DSTPORT | DSTIP  |  USERID   | NULL |  DOMAIN   | NULL |
+/// +----+----+---------+--------+-----------+------+-----------+------+
+/// | 1  | 1  |    2    |   4    | Variable  |  1   | Variable  |  1   |
+/// +----+----+---------+--------+-----------+------+-----------+------+
+/// ```
+///
+/// The domain is only present when `DSTIP` is `0.0.0.x` with `x` non zero.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub command: Command,
+    pub address: Address,
+    pub user_id: String,
+}
+
+impl Request {
+    pub fn new(command: Command, address: Address, user_id: impl Into<String>) -> Self {
+        let user_id = user_id.into();
+        Self { command, address, user_id }
+    }
+}
+
+/// Read a NULL terminated field.
+async fn read_field<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> std::io::Result<String> {
+    let mut field = Vec::new();
+    loop {
+        match r.read_u8().await? {
+            0 => break,
+            _ if field.len() == MAX_FIELD_LEN => {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "SOCKS4 field too long"));
+            }
+            byte => field.push(byte),
+        }
+    }
+    String::from_utf8(field).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+#[async_trait::async_trait]
+impl StreamOperation for Request {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> std::io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + ?Sized,
+    {
+        let ver = Version::try_from(r.read_u8().await?)?;
+        if ver != Version::V4 {
+            let err = format!("Unsupported SOCKS version {0:#x}", u8::from(ver));
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, err));
+        }
+
+        let command = match Command::try_from(r.read_u8().await?)? {
+            Command::UdpAssociate => {
+                let err = "UDP ASSOCIATE is not part of SOCKS4";
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err));
+            }
+            command => command,
+        };
+        let port = r.read_u16().await?;
+        let ip = Ipv4Addr::from(r.read_u32().await?);
+        let user_id = read_field(r).await?;
+
+        let address = match ip.octets() {
+            [0, 0, 0, x] if x != 0 => Address::DomainAddress(read_field(r).await?.into_boxed_str(), port),
+            _ => Address::from((ip, port)),
+        };
+
+        Ok(Self { command, address, user_id })
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(Version::V4.into());
+        buf.put_u8(u8::from(self.command));
+        buf.put_u16(self.address.port());
+        match &self.address {
+            Address::SocketAddress(SocketAddr::V4(addr)) => buf.put_slice(&addr.ip().octets()),
+            _ => buf.put_slice(&[0, 0, 0, 1]),
+        }
+        buf.put_slice(self.user_id.as_bytes());
+        buf.put_u8(0);
+        match &self.address {
+            Address::SocketAddress(SocketAddr::V4(_)) => {}
+            address => {
+                buf.put_slice(address.domain().as_bytes());
+                buf.put_u8(0);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        let domain = match &self.address {
+            Address::SocketAddress(SocketAddr::V4(_)) => 0,
+            address => address.domain().len() + 1,
+        };
+        8 + self.user_id.len() + 1 + domain
+    }
+}
+
+/// SOCKS4 response
+///
+/// ```plain
+/// +----+----+---------+--------+
+/// | VN | CD | DSTPORT | DSTIP  |
+/// +----+----+---------+--------+
+/// | 1  | 1  |    2    |   4    |
+/// +----+----+---------+--------+
+/// ```
+///
+/// SOCKS4 only tells granted (90) from rejected (91) requests: every [`Reply`] but [`Reply::Succeeded`] is a rejection,
+/// and addresses other than IPv4 are sent as `0.0.0.0`.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub reply: Reply,
+    pub address: Address,
+}
+
+impl Response {
+    pub fn new(reply: Reply, address: Address) -> Self {
+        Self { reply, address }
+    }
+}
+
+const GRANTED: u8 = 90;
+const REJECTED: u8 = 91;
+
+#[async_trait::async_trait]
+impl StreamOperation for Response {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> std::io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + ?Sized,
+    {
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf).await?;
+        let reply = match buf[1] {
+            GRANTED => Reply::Succeeded,
+            _ => Reply::GeneralFailure,
+        };
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        Ok(Self::new(reply, Address::from((ip, port))))
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(0);
+        buf.put_u8(if self.reply == Reply::Succeeded { GRANTED } else { REJECTED });
+        match &self.address {
+            Address::SocketAddress(SocketAddr::V4(addr)) => {
+                buf.put_u16(addr.port());
+                buf.put_slice(&addr.ip().octets());
+            }
+            _ => buf.put_slice(&[0; 6]),
+        }
+    }
+
+    fn len(&self) -> usize {
+        8
+    }
+}
+
+#[tokio::test]
+async fn test_socks4() {
+    use std::io::Cursor;
+
+    let buf = b"\x04\x01\x00\x50\x00\x00\x00\x01alice\0example.com\0";
+    let req = Request::retrieve_from_async_stream(&mut Cursor::new(buf)).await.unwrap();
+    assert_eq!(req.command, Command::Connect);
+    assert_eq!(req.address, Address::from(("example.com", 80)));
+    assert_eq!(req.user_id, "alice");
+    let mut out = Vec::new();
+    req.write_to_async_stream(&mut out).await.unwrap();
+    assert_eq!(out, buf);
+
+    let buf = b"\x04\x02\x1f\x90\x7f\x00\x00\x01\0";
+    let req = Request::retrieve_from_async_stream(&mut Cursor::new(buf)).await.unwrap();
+    assert_eq!(req.address, Address::from((Ipv4Addr::LOCALHOST, 8080)));
+    assert_eq!(req.len(), buf.len());
+
+    let mut out = Vec::new();
+    let resp = Response::new(Reply::ConnectionRefused, Address::unspecified());
+    resp.write_to_async_stream(&mut out).await.unwrap();
+    assert_eq!(out, [0, REJECTED, 0, 0, 0, 0, 0, 0]);
+    let resp = Response::retrieve_from_async_stream(&mut Cursor::new(out)).await.unwrap();
+    assert_eq!(resp.reply, Reply::GeneralFailure);
+}
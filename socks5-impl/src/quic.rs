@@ -0,0 +1,238 @@
+//! SOCKS5 over QUIC, behind the `quic` feature.
+//!
+//! Each bidirectional stream of a [`QuicConnection`] carries one SOCKS5 session, so that the sessions between a
+//! client and the proxy neither wait for a TCP handshake each nor block each other on a lost packet.
+//!
+//! The packets of a UDP ASSOCIATE session travel as QUIC datagrams on the same connection, rather than on a UDP
+//! socket of their own: see [`QuicStream::associate`]. Each datagram starts with the id of the QUIC stream of the
+//! session, as a big-endian `u64`, followed by the SOCKS5 UDP request header and the payload.
+
+use crate::protocol::{Address, StreamOperation, UdpHeader};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// The datagrams buffered per association before the newest ones are dropped.
+const ASSOCIATION_BACKLOG: usize = 256;
+
+/// A QUIC connection between a SOCKS5 client and server, each stream of which is a session.
+#[derive(Debug, Clone)]
+pub struct QuicConnection {
+    connection: quinn::Connection,
+    datagrams: Arc<Mutex<Datagrams>>,
+}
+
+/// The routes of the datagrams received on a connection to its associations, by stream id.
+#[derive(Debug, Default)]
+struct Datagrams {
+    routes: HashMap<u64, mpsc::Sender<Bytes>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl QuicConnection {
+    /// Carry SOCKS5 sessions over an established QUIC connection.
+    pub fn new(connection: quinn::Connection) -> Self {
+        Self {
+            connection,
+            datagrams: Arc::default(),
+        }
+    }
+
+    /// Open a stream for a new session, as the client.
+    pub async fn open(&self) -> std::io::Result<QuicStream> {
+        let (send, recv) = self.connection.open_bi().await.map_err(connection_error)?;
+        Ok(self.stream(send, recv))
+    }
+
+    /// Accept the stream of a new session, as the server.
+    pub async fn accept(&self) -> std::io::Result<QuicStream> {
+        let (send, recv) = self.connection.accept_bi().await.map_err(connection_error)?;
+        Ok(self.stream(send, recv))
+    }
+
+    fn stream(&self, send: quinn::SendStream, recv: quinn::RecvStream) -> QuicStream {
+        QuicStream {
+            send,
+            recv,
+            connection: self.clone(),
+        }
+    }
+
+    /// Get the address of the peer.
+    pub fn remote_address(&self) -> std::net::SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Get a reference to the underlying QUIC connection.
+    pub fn get_ref(&self) -> &quinn::Connection {
+        &self.connection
+    }
+}
+
+/// A bidirectional QUIC stream, carrying one SOCKS5 session.
+#[derive(Debug)]
+pub struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    connection: QuicConnection,
+}
+
+impl QuicStream {
+    /// Get the id of this stream, which is the same on both ends of the connection.
+    pub fn id(&self) -> u64 {
+        self.send.id().into()
+    }
+
+    /// Get the connection this stream belongs to.
+    pub fn connection(&self) -> &QuicConnection {
+        &self.connection
+    }
+
+    /// Send and receive the packets of the UDP ASSOCIATE session of this stream as QUIC datagrams.
+    ///
+    /// The datagrams received before this call are dropped: the server should call it before replying to the
+    /// UDP ASSOCIATE request, the client once the reply is received.
+    pub fn associate(&self) -> QuicAssociation {
+        let id = self.id();
+        let (sender, received) = mpsc::channel(ASSOCIATION_BACKLOG);
+        let mut datagrams = self.connection.datagrams.lock().unwrap();
+        datagrams.routes.insert(id, sender);
+        if datagrams.task.as_ref().is_none_or(|task| task.is_finished()) {
+            let (connection, routes) = (self.connection.connection.clone(), self.connection.datagrams.clone());
+            datagrams.task = Some(tokio::spawn(route_datagrams(connection, routes)));
+        }
+        QuicAssociation {
+            id,
+            connection: self.connection.clone(),
+            received: tokio::sync::Mutex::new(received),
+        }
+    }
+
+    /// Consume this stream, returning its sending and receiving halves.
+    pub fn into_inner(self) -> (quinn::SendStream, quinn::RecvStream) {
+        (self.send, self.recv)
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+    }
+}
+
+/// Route the datagrams received on `connection` to its associations, until they are all dropped.
+async fn route_datagrams(connection: quinn::Connection, datagrams: Arc<Mutex<Datagrams>>) {
+    while let Ok(mut datagram) = connection.read_datagram().await {
+        if datagram.len() < 8 {
+            continue;
+        }
+        let id = u64::from_be_bytes(datagram.split_to(8)[..].try_into().unwrap());
+        let sender = datagrams.lock().unwrap().routes.get(&id).cloned();
+        if let Some(sender) = sender {
+            let _ = sender.try_send(datagram);
+        }
+    }
+    // The connection is closed, let the associations know
+    datagrams.lock().unwrap().routes.clear();
+}
+
+/// The UDP ASSOCIATE session of a [`QuicStream`], relaying its packets as QUIC datagrams.
+///
+/// Like the `AssociatedUdpSocket` of the server, it adds the SOCKS5 UDP header to the
+/// packets sent and parses it from the packets received, on the client and server sides alike.
+#[derive(Debug)]
+pub struct QuicAssociation {
+    id: u64,
+    connection: QuicConnection,
+    received: tokio::sync::Mutex<mpsc::Receiver<Bytes>>,
+}
+
+impl QuicAssociation {
+    /// Send a packet, with the fragment number and the address of the target (from the client) or of the source
+    /// (from the server) of the packet.
+    pub async fn send<P: AsRef<[u8]>>(&self, pkt: P, frag: u8, addr: Address) -> std::io::Result<usize> {
+        let header = UdpHeader::new(frag, addr);
+        let pkt = pkt.as_ref();
+        let mut buf = BytesMut::with_capacity(8 + header.len() + pkt.len());
+        buf.put_u64(self.id);
+        header.write_to_buf(&mut buf);
+        buf.extend_from_slice(pkt);
+        self.connection
+            .connection
+            .send_datagram_wait(buf.freeze())
+            .await
+            .map_err(|err| match err {
+                quinn::SendDatagramError::ConnectionLost(err) => connection_error(err),
+                quinn::SendDatagramError::TooLarge => Error::new(ErrorKind::InvalidInput, err),
+                err => Error::new(ErrorKind::Unsupported, err),
+            })?;
+        Ok(pkt.len())
+    }
+
+    /// Receive a packet, with its fragment number and the address of its target (on the server) or of its source
+    /// (on the client).
+    ///
+    /// The packets with an invalid SOCKS5 UDP header are dropped.
+    pub async fn recv(&self) -> std::io::Result<(Bytes, u8, Address)> {
+        let mut received = self.received.lock().await;
+        loop {
+            let Some(pkt) = received.recv().await else {
+                let err = self.connection.connection.close_reason().map(connection_error);
+                return Err(err.unwrap_or_else(|| ErrorKind::ConnectionAborted.into()));
+            };
+            if let Ok(header) = UdpHeader::retrieve_from_async_stream(&mut pkt.as_ref()).await {
+                return Ok((pkt.slice(header.len()..), header.frag, header.address));
+            }
+        }
+    }
+
+    /// Get the largest packet which can be sent, with the SOCKS5 UDP header included, if the peer accepts datagrams.
+    pub fn max_packet_size(&self) -> Option<usize> {
+        Some(self.connection.connection.max_datagram_size()?.saturating_sub(8))
+    }
+}
+
+impl Drop for QuicAssociation {
+    fn drop(&mut self) {
+        let mut datagrams = self.connection.datagrams.lock().unwrap();
+        datagrams.routes.remove(&self.id);
+        if datagrams.routes.is_empty()
+            && let Some(task) = datagrams.task.take()
+        {
+            task.abort();
+        }
+    }
+}
+
+pub(crate) fn connection_error(err: quinn::ConnectionError) -> Error {
+    let kind = match err {
+        quinn::ConnectionError::TimedOut => ErrorKind::TimedOut,
+        quinn::ConnectionError::Reset => ErrorKind::ConnectionReset,
+        _ => ErrorKind::ConnectionAborted,
+    };
+    Error::new(kind, err)
+}
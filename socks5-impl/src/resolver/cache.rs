@@ -0,0 +1,142 @@
+use crate::resolver::Resolver;
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+/// Caches the results of the inner resolver.
+///
+/// Successful lookups are kept for the positive TTL, failed lookups for the negative TTL.
+/// A zero TTL disables caching of the corresponding results.
+#[derive(Debug)]
+pub struct CachingResolver<R> {
+    inner: R,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    expires: Instant,
+    result: Result<Vec<IpAddr>, (ErrorKind, String)>,
+}
+
+impl<R> CachingResolver<R> {
+    pub fn new(inner: R, positive_ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            inner,
+            positive_ttl,
+            negative_ttl,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the maximum number of cached host names, 4096 by default.
+    ///
+    /// When the cache is full, expired entries are evicted; if none are expired, new results are not cached.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Drop every cached entry.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn get(&self, host: &str) -> Option<std::io::Result<Vec<IpAddr>>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(host).filter(|entry| entry.expires > Instant::now())?;
+        Some(match &entry.result {
+            Ok(ips) => Ok(ips.clone()),
+            Err((kind, msg)) => Err(std::io::Error::new(*kind, msg.clone())),
+        })
+    }
+
+    fn put(&self, host: String, result: &std::io::Result<Vec<IpAddr>>) {
+        let ttl = if result.is_ok() { self.positive_ttl } else { self.negative_ttl };
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&host) {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        let result = match result {
+            Ok(ips) => Ok(ips.clone()),
+            Err(err) => Err((err.kind(), err.to_string())),
+        };
+        let expires = now + ttl;
+        entries.insert(host, Entry { expires, result });
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: Resolver + Send + Sync> Resolver for CachingResolver<R> {
+    async fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        let key = host.to_ascii_lowercase();
+        if let Some(result) = self.get(&key) {
+            return result;
+        }
+        let result = self.inner.resolve(host).await;
+        self.put(key, &result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl Resolver for Counting {
+        async fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            match host {
+                "found.test" => Ok(vec!["192.0.2.1".parse().unwrap()]),
+                _ => Err(std::io::Error::new(ErrorKind::NotFound, "NXDOMAIN")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_positive_and_negative_results() {
+        let resolver = CachingResolver::new(Counting::default(), Duration::from_secs(60), Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(resolver.resolve("found.test").await.is_ok());
+            let err = resolver.resolve("missing.test").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+        }
+        assert_eq!(resolver.inner.0.load(Ordering::SeqCst), 2);
+
+        resolver.clear();
+        assert!(resolver.resolve("found.test").await.is_ok());
+        assert_eq!(resolver.inner.0.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_and_capacity() {
+        let resolver = CachingResolver::new(Counting::default(), Duration::from_secs(60), Duration::ZERO).with_max_entries(0);
+        assert!(resolver.resolve("found.test").await.is_ok());
+        assert!(resolver.resolve("found.test").await.is_ok());
+        assert!(resolver.resolve("missing.test").await.is_err());
+        assert!(resolver.resolve("missing.test").await.is_err());
+        assert_eq!(resolver.inner.0.load(Ordering::SeqCst), 4);
+    }
+}
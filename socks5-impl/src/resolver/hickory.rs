@@ -0,0 +1,175 @@
+use crate::resolver::{Resolver, not_empty};
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{Name, RData, RecordType},
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+const MAX_UDP_MESSAGE_SIZE: usize = 4096;
+
+/// Resolves names by querying a DNS server directly, without going through the operating system.
+///
+/// `A` and `AAAA` queries are sent concurrently over UDP; truncated answers are retried over TCP.
+#[derive(Clone, Debug)]
+pub struct HickoryResolver {
+    nameserver: SocketAddr,
+    timeout: Duration,
+}
+
+impl HickoryResolver {
+    pub fn new(nameserver: SocketAddr) -> Self {
+        Self {
+            nameserver,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Set the timeout of each query, 5 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn query(&self, name: &Name, record_type: RecordType) -> std::io::Result<Vec<IpAddr>> {
+        let id: u16 = rand::random();
+        let mut request = Message::new();
+        request
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name.clone(), record_type));
+        let request = request.to_vec().map_err(std::io::Error::other)?;
+
+        let mut response = tokio::time::timeout(self.timeout, self.exchange_udp(id, &request)).await??;
+        if response.truncated() {
+            response = tokio::time::timeout(self.timeout, self.exchange_tcp(id, &request)).await??;
+        }
+
+        match response.response_code() {
+            ResponseCode::NoError => {}
+            ResponseCode::NXDomain => {
+                let err = format!("{name} does not exist");
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, err));
+            }
+            code => return Err(std::io::Error::other(format!("DNS query for {name} failed: {code}"))),
+        }
+
+        let ips = response
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                RData::A(a) => Some(IpAddr::V4(a.0)),
+                RData::AAAA(aaaa) => Some(IpAddr::V6(aaaa.0)),
+                _ => None,
+            })
+            .collect();
+        Ok(ips)
+    }
+
+    async fn exchange_udp(&self, id: u16, request: &[u8]) -> std::io::Result<Message> {
+        let bind_addr = if self.nameserver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(self.nameserver).await?;
+        socket.send(request).await?;
+
+        let mut buf = vec![0; MAX_UDP_MESSAGE_SIZE];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            // Ignore stray datagrams that do not answer this query
+            if let Ok(response) = Message::from_vec(&buf[..len])
+                && response.id() == id
+            {
+                return Ok(response);
+            }
+        }
+    }
+
+    async fn exchange_tcp(&self, id: u16, request: &[u8]) -> std::io::Result<Message> {
+        let mut stream = TcpStream::connect(self.nameserver).await?;
+        let mut buf = Vec::with_capacity(2 + request.len());
+        buf.extend_from_slice(&(request.len() as u16).to_be_bytes());
+        buf.extend_from_slice(request);
+        stream.write_all(&buf).await?;
+
+        let len = stream.read_u16().await? as usize;
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await?;
+        let response = Message::from_vec(&buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if response.id() != id {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "DNS response id mismatch"));
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait::async_trait]
+impl Resolver for HickoryResolver {
+    async fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        let mut name = Name::from_utf8(host).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        name.set_fqdn(true);
+
+        let (v4, v6) = tokio::join!(self.query(&name, RecordType::A), self.query(&name, RecordType::AAAA));
+        let ips = match (v4, v6) {
+            (Err(err), Err(_)) => return Err(err),
+            (v4, v6) => v4.unwrap_or_default().into_iter().chain(v6.unwrap_or_default()).collect(),
+        };
+        not_empty(host, ips)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::{Record, rdata};
+
+    async fn fake_nameserver() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_UDP_MESSAGE_SIZE];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(OpCode::Query)
+                    .add_query(query.clone());
+                match query.name().to_string().as_str() {
+                    "example.test." => {
+                        let data = match query.query_type() {
+                            RecordType::A => RData::A(rdata::A::new(192, 0, 2, 1)),
+                            _ => RData::AAAA(rdata::AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                        };
+                        response.add_answer(Record::from_rdata(query.name().clone(), 60, data));
+                    }
+                    _ => {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                }
+                socket.send_to(&response.to_vec().unwrap(), peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn resolve_from_nameserver() {
+        let resolver = HickoryResolver::new(fake_nameserver().await);
+        let ips = resolver.resolve("example.test").await.unwrap();
+        assert_eq!(ips, vec!["192.0.2.1".parse::<IpAddr>().unwrap(), "2001:db8::1".parse().unwrap()]);
+
+        let err = resolver.resolve("missing.test").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::resolver::Resolver;
+use std::{collections::HashMap, net::IpAddr};
+
+/// Answers from a static host table before falling back to the inner resolver.
+///
+/// Host names are matched case-insensitively, and a trailing dot is ignored.
+#[derive(Clone, Debug)]
+pub struct HostsResolver<R> {
+    inner: R,
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl<R> HostsResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Add an override for `host`, replacing any previous entry.
+    pub fn with_host<I>(mut self, host: &str, ips: I) -> Self
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        self.insert(host, ips);
+        self
+    }
+
+    /// Add an override for `host`, replacing any previous entry.
+    pub fn insert<I>(&mut self, host: &str, ips: I)
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        self.hosts.insert(normalize(host), ips.into_iter().collect());
+    }
+
+    /// Add every entry of a table in `/etc/hosts` format.
+    ///
+    /// Each line holds an IP address followed by one or more host names; `#` starts a comment.
+    /// Addresses listed for the same name on several lines are merged in order.
+    pub fn with_hosts_file(mut self, content: &str) -> crate::Result<Self> {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(ip) = fields.next() else {
+                continue;
+            };
+            let ip = ip.parse::<IpAddr>()?;
+            for host in fields {
+                self.hosts.entry(normalize(host)).or_default().push(ip);
+            }
+        }
+        Ok(self)
+    }
+
+    fn lookup(&self, host: &str) -> Option<&Vec<IpAddr>> {
+        if self.hosts.is_empty() {
+            return None;
+        }
+        self.hosts.get(&normalize(host))
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[async_trait::async_trait]
+impl<R: Resolver + Send + Sync> Resolver for HostsResolver<R> {
+    async fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        match self.lookup(host) {
+            Some(ips) if !ips.is_empty() => Ok(ips.clone()),
+            _ => self.inner.resolve(host).await,
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Asynchronous name resolution for [`Address::DomainAddress`] targets.
+//!
+//! The [`Resolver`] trait is used wherever the server or the client turns a domain name into IP addresses,
+//! so that name resolution never blocks the async executor.
+//!
+//! Resolvers are composable:
+//!
+//! ```rust
+//! use socks5_impl::resolver::{CachingResolver, HostsResolver, IpPreference, PreferenceResolver, SystemResolver};
+//! use std::time::Duration;
+//!
+//! let resolver = HostsResolver::new(SystemResolver).with_host("proxy.internal", ["10.0.0.1".parse().unwrap()]);
+//! let resolver = CachingResolver::new(resolver, Duration::from_secs(300), Duration::from_secs(30));
+//! let resolver = PreferenceResolver::new(resolver, IpPreference::PreferIpv4);
+//! ```
+
+mod cache;
+#[cfg(feature = "hickory")]
+mod hickory;
+mod hosts;
+
+pub use self::{cache::CachingResolver, hosts::HostsResolver};
+
+#[cfg(feature = "hickory")]
+pub use self::hickory::HickoryResolver;
+
+use crate::protocol::Address;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+/// This trait is for defining how domain names are resolved.
+///
+/// Implementations must return at least one address, or an error.
+#[async_trait::async_trait]
+pub trait Resolver {
+    /// Resolve `host` into the IP addresses it points to.
+    async fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>>;
+
+    /// Resolve an [`Address`] into socket addresses.
+    ///
+    /// Socket addresses and domain addresses holding an IP literal are returned without a lookup.
+    async fn resolve_address(&self, addr: &Address) -> std::io::Result<Vec<SocketAddr>> {
+        match addr {
+            Address::SocketAddress(addr) => Ok(vec![*addr]),
+            Address::DomainAddress(host, port) => {
+                let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+                if let Ok(ip) = host.parse::<IpAddr>() {
+                    return Ok(vec![SocketAddr::new(ip, *port)]);
+                }
+                let ips = self.resolve(host).await?;
+                Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, *port)).collect())
+            }
+        }
+    }
+}
+
+pub type ResolverAdaptor = Arc<dyn Resolver + Send + Sync>;
+
+#[async_trait::async_trait]
+impl<R: Resolver + Send + Sync + ?Sized> Resolver for Arc<R> {
+    async fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        (**self).resolve(host).await
+    }
+}
+
+/// Resolves names with the operating system's resolver (`getaddrinfo`).
+///
+/// The lookup runs on tokio's blocking thread pool, so the async executor is never blocked.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait::async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        let ips: Vec<IpAddr> = tokio::net::lookup_host((host, 0)).await?.map(|addr| addr.ip()).collect();
+        not_empty(host, ips)
+    }
+}
+
+/// The address family policy applied by [`PreferenceResolver`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum IpPreference {
+    /// Keep the order returned by the inner resolver.
+    #[default]
+    Unspecified,
+    /// Only return IPv4 addresses.
+    Ipv4Only,
+    /// Only return IPv6 addresses.
+    Ipv6Only,
+    /// Return IPv4 addresses before IPv6 addresses.
+    PreferIpv4,
+    /// Return IPv6 addresses before IPv4 addresses.
+    PreferIpv6,
+}
+
+impl IpPreference {
+    /// Filter and reorder `ips` according to this policy. The relative order within one family is kept.
+    pub fn apply(&self, mut ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        match self {
+            IpPreference::Unspecified => {}
+            IpPreference::Ipv4Only => ips.retain(IpAddr::is_ipv4),
+            IpPreference::Ipv6Only => ips.retain(IpAddr::is_ipv6),
+            IpPreference::PreferIpv4 => ips.sort_by_key(IpAddr::is_ipv6),
+            IpPreference::PreferIpv6 => ips.sort_by_key(IpAddr::is_ipv4),
+        }
+        ips
+    }
+}
+
+/// Applies an [`IpPreference`] to the results of the inner resolver.
+#[derive(Clone, Debug)]
+pub struct PreferenceResolver<R> {
+    inner: R,
+    preference: IpPreference,
+}
+
+impl<R> PreferenceResolver<R> {
+    pub fn new(inner: R, preference: IpPreference) -> Self {
+        Self { inner, preference }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: Resolver + Send + Sync> Resolver for PreferenceResolver<R> {
+    async fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        let ips = self.preference.apply(self.inner.resolve(host).await?);
+        not_empty(host, ips)
+    }
+}
+
+fn not_empty(host: &str, ips: Vec<IpAddr>) -> std::io::Result<Vec<IpAddr>> {
+    if ips.is_empty() {
+        let err = format!("no usable address found for {host}");
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, err));
+    }
+    Ok(ips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const V4: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    #[test]
+    fn ip_preference() {
+        let ips = vec![V6, V4];
+        assert_eq!(IpPreference::Unspecified.apply(ips.clone()), vec![V6, V4]);
+        assert_eq!(IpPreference::PreferIpv4.apply(ips.clone()), vec![V4, V6]);
+        assert_eq!(IpPreference::PreferIpv6.apply(vec![V4, V6]), vec![V6, V4]);
+        assert_eq!(IpPreference::Ipv4Only.apply(ips.clone()), vec![V4]);
+        assert_eq!(IpPreference::Ipv6Only.apply(ips), vec![V6]);
+    }
+
+    #[tokio::test]
+    async fn resolve_address_literals() {
+        let addr = Address::from(("[::1]", 80));
+        let resolved = SystemResolver.resolve_address(&addr).await.unwrap();
+        assert_eq!(resolved, vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 80))]);
+
+        let addr = Address::from(("127.0.0.1", 80));
+        let resolved = SystemResolver.resolve_address(&addr).await.unwrap();
+        assert_eq!(resolved, vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 80))]);
+    }
+
+    #[tokio::test]
+    async fn preference_resolver_rejects_empty_result() {
+        let resolver = HostsResolver::new(SystemResolver).with_host("only-v6.test", [V6]);
+        let resolver = PreferenceResolver::new(resolver, IpPreference::Ipv4Only);
+        let err = resolver.resolve("only-v6.test").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn hosts_resolver() {
+        let table = "# comment\n127.0.0.2 Example.test www.example.test\n::2 example.test # v6\n";
+        let resolver = HostsResolver::new(SystemResolver).with_hosts_file(table).unwrap();
+        let ips = resolver.resolve("example.test.").await.unwrap();
+        assert_eq!(ips, vec!["127.0.0.2".parse::<IpAddr>().unwrap(), "::2".parse().unwrap()]);
+        let ips = resolver.resolve("WWW.example.test").await.unwrap();
+        assert_eq!(ips, vec!["127.0.0.2".parse::<IpAddr>().unwrap()]);
+
+        assert!(HostsResolver::new(SystemResolver).with_hosts_file("not-an-ip host").is_err());
+    }
+}
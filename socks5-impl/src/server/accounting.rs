@@ -0,0 +1,611 @@
+//! Per user traffic accounting and quotas.
+//!
+//! An [`Accounting`] counts the bytes and connections of every authenticated user, per UTC day, per UTC month
+//! and in total. With [`Server::with_accounting`](crate::server::Server::with_accounting), requests of users over
+//! their [`Quota`] are rejected; live sessions are closed when their relayed streams are wrapped into [`Accounted`],
+//! and their UDP sockets set up with [`AssociatedUdpSocket::with_account`](crate::server::AssociatedUdpSocket::with_account).
+//!
+//! An [`Accounted`] stream counts its traffic on its own and adds it to the counters of its user every 64 KiB,
+//! when its quota may be reached and when the stream is dropped, so relaying does not contend on the counters.
+//!
+//! Counters are persisted to a local file with [`Accounting::open`] and [`Accounting::flush`], and once more
+//! when the last handle to the [`Accounting`] is dropped, e.g. when the server shut down and its sessions ended.
+//!
+//! ```no_run
+//! use socks5_impl::server::{Accounting, Quota};
+//! use std::time::Duration;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let accounting = Accounting::open("/var/lib/socks5/usage").await?.with_default_quota(Quota::new().with_monthly_bytes(50 << 30));
+//! let handle = accounting.clone();
+//! tokio::spawn(async move {
+//!     loop {
+//!         tokio::time::sleep(Duration::from_secs(60)).await;
+//!         let _ = handle.flush().await;
+//!     }
+//! });
+//! # Ok(())
+//! # }
+//! ```
+
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The bytes an [`Accounted`] stream counts on its own before adding them to the counters of its user.
+const FOLD_BYTES: u64 = 64 << 10;
+
+/// Bytes and connections counted over a period.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Usage {
+    /// Bytes sent by the user.
+    pub bytes_up: u64,
+    /// Bytes received by the user.
+    pub bytes_down: u64,
+    pub connections: u64,
+}
+
+impl Usage {
+    /// The bytes relayed in both directions.
+    pub fn bytes(&self) -> u64 {
+        self.bytes_up.saturating_add(self.bytes_down)
+    }
+}
+
+/// The usage of one user for the current UTC day, the current UTC month and since the counters exist.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct UserUsage {
+    pub daily: Usage,
+    pub monthly: Usage,
+    pub total: Usage,
+}
+
+/// Daily and monthly caps on the bytes relayed for a user, in both directions.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Quota {
+    pub daily_bytes: Option<u64>,
+    pub monthly_bytes: Option<u64>,
+}
+
+impl Quota {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_daily_bytes(mut self, bytes: u64) -> Self {
+        self.daily_bytes = Some(bytes);
+        self
+    }
+
+    pub fn with_monthly_bytes(mut self, bytes: u64) -> Self {
+        self.monthly_bytes = Some(bytes);
+        self
+    }
+
+    /// Returns `true` if `usage` has used up this quota.
+    pub fn is_exceeded_by(&self, usage: &UserUsage) -> bool {
+        self.daily_bytes.is_some_and(|max| usage.daily.bytes() >= max) || self.monthly_bytes.is_some_and(|max| usage.monthly.bytes() >= max)
+    }
+}
+
+/// Counts the traffic of users, and enforces their quotas.
+///
+/// Cloning gives another handle to the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct Accounting {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    path: Option<PathBuf>,
+    quotas: Mutex<Quotas>,
+    records: Mutex<HashMap<String, Record>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let records = self.records.get_mut().unwrap_or_else(|err| err.into_inner());
+        let tmp = tmp_path(path);
+        let saved = std::fs::write(&tmp, format_records(records)).and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(err) = saved {
+            log::warn!("failed to save the accounting {}: {err}", path.display());
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Quotas {
+    default: Quota,
+    users: HashMap<String, Quota>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Record {
+    day: i64,
+    month: i64,
+    usage: UserUsage,
+}
+
+impl Record {
+    /// Start new daily and monthly periods if `now` is past the recorded ones.
+    fn roll(&mut self, (day, month): (i64, i64)) {
+        if self.day != day {
+            self.day = day;
+            self.usage.daily = Usage::default();
+        }
+        if self.month != month {
+            self.month = month;
+            self.usage.monthly = Usage::default();
+        }
+    }
+
+    fn add(&mut self, f: impl Fn(&mut Usage)) {
+        f(&mut self.usage.daily);
+        f(&mut self.usage.monthly);
+        f(&mut self.usage.total);
+    }
+}
+
+impl Accounting {
+    /// Create counters kept in memory only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the counters persisted at `path`, if any, and persist them there on [`flush`](Self::flush)
+    /// and when the last handle is dropped.
+    pub async fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let records = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => parse_records(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        let shared = Shared {
+            path: Some(path),
+            quotas: Mutex::default(),
+            records: Mutex::new(records),
+        };
+        Ok(Self { shared: Arc::new(shared) })
+    }
+
+    /// Set the quota of the users without a quota of their own.
+    pub fn with_default_quota(self, quota: Quota) -> Self {
+        self.shared.quotas.lock().unwrap().default = quota;
+        self
+    }
+
+    /// Set the quota of `user`, replacing the default quota for this user.
+    pub fn set_quota(&self, user: &str, quota: Quota) {
+        self.shared.quotas.lock().unwrap().users.insert(user.to_owned(), quota);
+    }
+
+    /// The quota applying to `user`.
+    pub fn quota(&self, user: &str) -> Quota {
+        let quotas = self.shared.quotas.lock().unwrap();
+        quotas.users.get(user).copied().unwrap_or(quotas.default)
+    }
+
+    /// The current usage of `user`, or `None` if the user has never been counted.
+    pub fn usage(&self, user: &str) -> Option<UserUsage> {
+        let mut records = self.shared.records.lock().unwrap();
+        let record = records.get_mut(user)?;
+        record.roll(periods(SystemTime::now()));
+        Some(record.usage)
+    }
+
+    /// The current usage of every user counted so far.
+    pub fn usages(&self) -> Vec<(String, UserUsage)> {
+        let now = periods(SystemTime::now());
+        let mut records = self.shared.records.lock().unwrap();
+        records
+            .iter_mut()
+            .map(|(user, record)| {
+                record.roll(now);
+                (user.clone(), record.usage)
+            })
+            .collect()
+    }
+
+    /// Returns an error of kind [`QuotaExceeded`](std::io::ErrorKind::QuotaExceeded) if `user` is over quota.
+    pub fn check(&self, user: &str) -> std::io::Result<()> {
+        let quota = self.quota(user);
+        if self.usage(user).is_some_and(|usage| quota.is_exceeded_by(&usage)) {
+            let err = format!("user {user} is over quota");
+            return Err(std::io::Error::new(std::io::ErrorKind::QuotaExceeded, err));
+        }
+        Ok(())
+    }
+
+    /// Count a new connection of `user`.
+    pub fn record_connection(&self, user: &str) {
+        self.update(user, |usage| usage.connections += 1);
+    }
+
+    /// A handle counting the traffic of `user`.
+    pub fn account(&self, user: &str) -> UserAccount {
+        let accounting = self.clone();
+        let user = Arc::from(user);
+        UserAccount { accounting, user }
+    }
+
+    /// Write the counters to the file given to [`open`](Self::open). Does nothing for in memory counters.
+    ///
+    /// The file is replaced atomically, so a crash never leaves it half written.
+    pub async fn flush(&self) -> std::io::Result<()> {
+        let Some(path) = &self.shared.path else {
+            return Ok(());
+        };
+        let content = format_records(&self.shared.records.lock().unwrap());
+        let tmp = tmp_path(path);
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+
+    /// The bytes `user` may still relay before going over quota, `None` without quota.
+    fn remaining(&self, user: &str) -> Option<u64> {
+        let quota = self.quota(user);
+        let usage = self.usage(user).unwrap_or_default();
+        let daily = quota.daily_bytes.map(|max| max.saturating_sub(usage.daily.bytes()));
+        let monthly = quota.monthly_bytes.map(|max| max.saturating_sub(usage.monthly.bytes()));
+        daily.into_iter().chain(monthly).min()
+    }
+
+    fn update(&self, user: &str, f: impl Fn(&mut Usage)) {
+        let now = periods(SystemTime::now());
+        let mut records = self.shared.records.lock().unwrap();
+        let record = records.entry(user.to_owned()).or_default();
+        record.roll(now);
+        record.add(f);
+    }
+}
+
+/// Counts the traffic of one user of an [`Accounting`].
+#[derive(Clone, Debug)]
+pub struct UserAccount {
+    accounting: Accounting,
+    user: Arc<str>,
+}
+
+impl UserAccount {
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Count `bytes` sent by the user.
+    pub fn add_upload(&self, bytes: usize) {
+        self.accounting.update(&self.user, |usage| usage.bytes_up += bytes as u64);
+    }
+
+    /// Count `bytes` received by the user.
+    pub fn add_download(&self, bytes: usize) {
+        self.accounting.update(&self.user, |usage| usage.bytes_down += bytes as u64);
+    }
+
+    /// Returns an error of kind [`QuotaExceeded`](std::io::ErrorKind::QuotaExceeded) if the user is over quota.
+    pub fn check(&self) -> std::io::Result<()> {
+        self.accounting.check(&self.user)
+    }
+}
+
+/// The traffic of one session not yet added to the counters of its user.
+#[derive(Debug)]
+struct Tally {
+    account: UserAccount,
+    up: u64,
+    down: u64,
+    /// The bytes to count before adding them to the counters and checking the quota again.
+    allowance: u64,
+}
+
+impl Tally {
+    fn new(account: UserAccount) -> Self {
+        Self {
+            account,
+            up: 0,
+            down: 0,
+            allowance: 0,
+        }
+    }
+
+    /// Returns an error of kind [`QuotaExceeded`](std::io::ErrorKind::QuotaExceeded) once the user is over quota.
+    fn check(&mut self) -> std::io::Result<()> {
+        if self.allowance > 0 {
+            return Ok(());
+        }
+        self.fold();
+        match self.account.accounting.remaining(&self.account.user) {
+            Some(0) => {
+                let err = format!("user {} is over quota", self.account.user);
+                Err(std::io::Error::new(std::io::ErrorKind::QuotaExceeded, err))
+            }
+            remaining => {
+                self.allowance = remaining.map_or(FOLD_BYTES, |remaining| remaining.min(FOLD_BYTES));
+                Ok(())
+            }
+        }
+    }
+
+    fn count(&mut self, up: usize, down: usize) {
+        self.up += up as u64;
+        self.down += down as u64;
+        self.allowance = self.allowance.saturating_sub((up + down) as u64);
+        if self.allowance == 0 {
+            self.fold();
+        }
+    }
+
+    /// Add the bytes counted so far to the counters of the user.
+    fn fold(&mut self) {
+        if self.up > 0 || self.down > 0 {
+            let (up, down) = (std::mem::take(&mut self.up), std::mem::take(&mut self.down));
+            self.account.accounting.update(&self.account.user, |usage| {
+                usage.bytes_up += up;
+                usage.bytes_down += down;
+            });
+        }
+    }
+}
+
+impl Drop for Tally {
+    fn drop(&mut self) {
+        self.fold();
+    }
+}
+
+/// Counts the traffic of the client stream `S`.
+///
+/// The stream wrapped is the one of the client, not the one to the target: the bytes read from it are sent by the
+/// client, its uploads, and the bytes written to it are received by the client, its downloads. The other stream
+/// wrappers of the server, such as [`Throttled`](crate::server::throttle::Throttled), follow the same convention.
+///
+/// The traffic is added to the counters of the user every 64 KiB, when the quota of the user may be reached, and
+/// when the stream is dropped. Once the user is over quota, reads and writes fail with
+/// [`QuotaExceeded`](std::io::ErrorKind::QuotaExceeded), which ends the relay of the session.
+#[derive(Debug)]
+pub struct Accounted<S> {
+    inner: S,
+    tally: Tally,
+}
+
+impl<S> Accounted<S> {
+    pub fn new(inner: S, account: UserAccount) -> Self {
+        let tally = Tally::new(account);
+        Self { inner, tally }
+    }
+
+    pub fn account(&self) -> &UserAccount {
+        &self.tally.account
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Accounted<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.tally.check()?;
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.tally.count(buf.filled().len() - filled, 0);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Accounted<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        this.tally.check()?;
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.tally.count(0, written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+/// The UTC day and month of `time`, both counted from the Unix epoch.
+fn periods(time: SystemTime) -> (i64, i64) {
+    let day = unix_secs(time).div_euclid(86400);
+    let (year, month, _) = civil_date(day);
+    (day, (year - 1970) * 12 + month - 1)
+}
+
+/// The seconds from the Unix epoch to `time`.
+pub(crate) fn unix_secs(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+/// The year, month and day of the `day`-th day from the Unix epoch.
+pub(crate) fn civil_date(day: i64) -> (i64, i64, i64) {
+    // Civil date from days, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = day + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day_of_month = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day_of_month)
+}
+
+const FILE_HEADER: &str = "# user day month daily(up down connections) monthly(up down connections) total(up down connections)";
+
+fn format_records(records: &HashMap<String, Record>) -> String {
+    let mut content = format!("{FILE_HEADER}\n");
+    for (user, record) in records {
+        let user = utf8_percent_encode(user, NON_ALPHANUMERIC);
+        content.push_str(&format!("{user} {} {}", record.day, record.month));
+        for usage in [record.usage.daily, record.usage.monthly, record.usage.total] {
+            content.push_str(&format!(" {} {} {}", usage.bytes_up, usage.bytes_down, usage.connections));
+        }
+        content.push('\n');
+    }
+    content
+}
+
+fn parse_records(content: &str) -> std::io::Result<HashMap<String, Record>> {
+    let invalid = |line: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid accounting record: {line}"));
+    let mut records = HashMap::new();
+    for line in content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let [user, day, month, counters @ ..] = fields.as_slice() else {
+            return Err(invalid(line));
+        };
+        let counters = counters.iter().map(|n| n.parse::<u64>()).collect::<Result<Vec<_>, _>>();
+        let (Ok(day), Ok(month), Ok(counters)) = (day.parse(), month.parse(), counters) else {
+            return Err(invalid(line));
+        };
+        let [daily, monthly, total] = match counters.as_slice() {
+            [a, b, c, d, e, f, g, h, i] => [(*a, *b, *c), (*d, *e, *f), (*g, *h, *i)],
+            _ => return Err(invalid(line)),
+        }
+        .map(|(bytes_up, bytes_down, connections)| Usage {
+            bytes_up,
+            bytes_down,
+            connections,
+        });
+        let user = percent_decode_str(user).decode_utf8().map_err(|_| invalid(line))?;
+        let usage = UserUsage { daily, monthly, total };
+        records.insert(user.into_owned(), Record { day, month, usage });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn utc_periods() {
+        let at = |secs| periods(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), (0, 0));
+        // 2024-02-29T23:59:59Z and 2024-03-01T00:00:00Z
+        assert_eq!(at(1_709_251_199), (19782, 54 * 12 + 1));
+        assert_eq!(at(1_709_251_200), (19783, 54 * 12 + 2));
+
+        let mut record = Record {
+            day: 1,
+            month: 0,
+            ..Default::default()
+        };
+        record.add(|usage| usage.bytes_up += 10);
+        record.roll((2, 0));
+        assert_eq!(record.usage.daily.bytes(), 0);
+        assert_eq!(record.usage.monthly.bytes(), 10);
+        record.roll((40, 1));
+        assert_eq!(record.usage.monthly.bytes(), 0);
+        assert_eq!(record.usage.total.bytes(), 10);
+    }
+
+    #[tokio::test]
+    async fn counters_are_persisted() {
+        let path = std::env::temp_dir().join(format!("socks5-impl-accounting-{}", std::process::id()));
+        let accounting = Accounting::open(&path).await.unwrap();
+        accounting.record_connection("alice smith");
+        accounting.account("alice smith").add_upload(42);
+        accounting.account("bob").add_download(7);
+        accounting.flush().await.unwrap();
+
+        let reopened = Accounting::open(&path).await.unwrap();
+        drop(accounting);
+        let usage = reopened.usage("alice smith").unwrap();
+        assert_eq!(
+            usage.daily,
+            Usage {
+                bytes_up: 42,
+                bytes_down: 0,
+                connections: 1
+            }
+        );
+        assert_eq!(usage.total, usage.daily);
+        assert_eq!(reopened.usage("bob").unwrap().monthly.bytes_down, 7);
+        assert!(parse_records("bob 1 2 3").is_err());
+
+        // saved when the last handle is dropped
+        reopened.account("bob").add_download(1);
+        drop(reopened);
+        let reopened = Accounting::open(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reopened.usage("bob").unwrap().total.bytes_down, 8);
+    }
+
+    #[tokio::test]
+    async fn session_is_closed_over_quota() {
+        let accounting = Accounting::new();
+        accounting.set_quota("alice", Quota::new().with_daily_bytes(8));
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Accounted::new(server, accounting.account("alice"));
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"abc").await.unwrap();
+        assert_eq!(accounting.usage("alice").unwrap().daily.bytes(), 8);
+
+        let err = server.write_all(b"more").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::QuotaExceeded);
+        assert!(accounting.check("alice").is_err());
+        assert!(accounting.check("bob").is_ok());
+    }
+
+    #[tokio::test]
+    async fn session_traffic_is_counted_when_dropped() {
+        let accounting = Accounting::new();
+        let (_client, server) = tokio::io::duplex(64);
+        let mut server = Accounted::new(server, accounting.account("bob"));
+        server.write_all(b"abc").await.unwrap();
+        assert_eq!(accounting.usage("bob"), None);
+
+        drop(server);
+        assert_eq!(accounting.usage("bob").unwrap().total.bytes_down, 3);
+    }
+}
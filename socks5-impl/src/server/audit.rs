@@ -0,0 +1,355 @@
+//! A per session audit log, written as JSON lines.
+//!
+//! Register an [`AuditLog`] with [`Server::with_audit_log`](crate::server::Server::with_audit_log): when a session
+//! closes, a line such as the following one is appended to the log file.
+//!
+//! ```json
+//! {"timestamp":"2024-03-01T12:00:00.000Z","session_id":1,"client_addr":"192.0.2.1:50000","username":"alice","command":"connect","target":"example.com:443","resolved_ip":"93.184.216.34","reply":"succeeded","bytes_up":517,"bytes_down":4096,"duration_ms":1200,"error":null}
+//! ```
+//!
+//! The bytes are the ones recorded with `record_transfer`, see [`SessionSummary`](crate::server::SessionSummary).
+//! The file is rotated by size or age, the rotated files being numbered from `.1`, the most recent one.
+
+use crate::{
+    protocol::{Address, Command, Reply},
+    server::{
+        accounting::{civil_date, unix_secs},
+        context::SessionContext,
+        metrics::{command_label, reply_label},
+        observer::{SessionObserver, SessionSummary},
+    },
+};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Where and how to write an [`AuditLog`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct AuditConfig {
+    /// The log file, appended to.
+    pub path: PathBuf,
+    /// Rotate the file before it grows over this size.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_bytes: Option<u64>,
+    /// Rotate the file when it has been written for this long.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rotate_every: Option<Duration>,
+    /// How many rotated files to keep.
+    #[cfg_attr(feature = "serde", serde(default = "default_max_files"))]
+    pub max_files: usize,
+    /// Write the port of the targets only, hiding the destination hosts and their addresses.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub redact_hosts: bool,
+}
+
+#[cfg(feature = "serde")]
+fn default_max_files() -> usize {
+    AuditConfig::DEFAULT_MAX_FILES
+}
+
+impl AuditConfig {
+    const DEFAULT_MAX_FILES: usize = 5;
+
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: None,
+            rotate_every: None,
+            max_files: Self::DEFAULT_MAX_FILES,
+            redact_hosts: false,
+        }
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_rotate_every(mut self, rotate_every: Duration) -> Self {
+        self.rotate_every = Some(rotate_every);
+        self
+    }
+
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    pub fn with_redact_hosts(mut self, redact_hosts: bool) -> Self {
+        self.redact_hosts = redact_hosts;
+        self
+    }
+}
+
+/// Writes a JSON line per closed session, from a background thread.
+///
+/// The thread stops once every clone of the log, including the ones held by the server, is dropped.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    redact_hosts: bool,
+    sessions: Mutex<HashMap<u64, Entry>>,
+    sender: mpsc::Sender<Message>,
+}
+
+/// What is known about a session before it closes.
+#[derive(Debug, Default)]
+struct Entry {
+    command: Option<Command>,
+    target: Option<Address>,
+    resolved_ip: Option<IpAddr>,
+    reply: Option<Reply>,
+}
+
+enum Message {
+    Line(String),
+    Flush(tokio::sync::oneshot::Sender<()>),
+}
+
+impl std::fmt::Debug for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Message::Line(line) => f.debug_tuple("Line").field(line).finish(),
+            Message::Flush(_) => f.write_str("Flush"),
+        }
+    }
+}
+
+impl AuditLog {
+    /// Open the log file, creating it if needed, and start the thread writing to it.
+    pub fn open(config: AuditConfig) -> std::io::Result<Self> {
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+        let (sender, receiver) = mpsc::channel();
+        let shared = Shared {
+            redact_hosts: config.redact_hosts,
+            sessions: Mutex::new(HashMap::new()),
+            sender,
+        };
+        let mut writer = Writer {
+            config,
+            file,
+            size,
+            opened: Instant::now(),
+        };
+        std::thread::Builder::new().name("socks5-audit".into()).spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Line(line) => {
+                        if let Err(err) = writer.write(&line) {
+                            log::warn!("failed to write the audit log {}: {err}", writer.config.path.display());
+                        }
+                    }
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        })?;
+        Ok(Self { shared: Arc::new(shared) })
+    }
+
+    /// Wait until the records of the sessions closed so far are written.
+    pub async fn flush(&self) {
+        let (done, written) = tokio::sync::oneshot::channel();
+        if self.shared.sender.send(Message::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    fn update(&self, ctx: &SessionContext, f: impl FnOnce(&mut Entry)) {
+        f(self.shared.sessions.lock().unwrap().entry(ctx.id).or_default());
+    }
+
+    fn format(&self, ctx: &SessionContext, entry: Entry, summary: &SessionSummary) -> String {
+        let target = entry.target.map(|target| match self.shared.redact_hosts {
+            true => format!("redacted:{}", target.port()),
+            false => target.to_string(),
+        });
+        let resolved_ip = entry.resolved_ip.filter(|_| !self.shared.redact_hosts);
+        format!(
+            "{{\"timestamp\":{},\"session_id\":{},\"client_addr\":{},\"username\":{},\"command\":{},\"target\":{},\"resolved_ip\":{},\"reply\":{},\"bytes_up\":{},\"bytes_down\":{},\"duration_ms\":{},\"error\":{}}}\n",
+            json_string(&format_timestamp(ctx.accepted_at)),
+            ctx.id,
+            json_string(&ctx.client_addr().to_string()),
+            json_option(ctx.identity.as_deref()),
+            json_option(entry.command.map(command_label)),
+            json_option(target.as_deref()),
+            json_option(resolved_ip.map(|ip| ip.to_string()).as_deref()),
+            json_option(entry.reply.map(reply_label)),
+            summary.bytes_up,
+            summary.bytes_down,
+            summary.duration.as_millis(),
+            json_option(summary.error.as_deref()),
+        )
+    }
+}
+
+impl SessionObserver for AuditLog {
+    fn on_request(&self, ctx: &SessionContext, command: Command, target: &Address) {
+        self.update(ctx, |entry| {
+            entry.command = Some(command);
+            entry.target = Some(target.clone());
+        });
+    }
+
+    fn on_connected(&self, ctx: &SessionContext, _target: &Address, remote: Option<SocketAddr>, _latency: Duration) {
+        self.update(ctx, |entry| entry.resolved_ip = remote.map(|remote| remote.ip()));
+    }
+
+    fn on_reply(&self, ctx: &SessionContext, reply: Reply) {
+        self.update(ctx, |entry| entry.reply = Some(reply));
+    }
+
+    fn on_closed(&self, ctx: &SessionContext, summary: &SessionSummary) {
+        let entry = self.shared.sessions.lock().unwrap().remove(&ctx.id).unwrap_or_default();
+        let _ = self.shared.sender.send(Message::Line(self.format(ctx, entry, summary)));
+    }
+}
+
+/// The writing end of an [`AuditLog`], owned by its thread.
+struct Writer {
+    config: AuditConfig,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl Writer {
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64;
+        let full = self.config.max_bytes.is_some_and(|max| self.size > 0 && self.size + len > max);
+        let old = self.config.rotate_every.is_some_and(|every| self.opened.elapsed() >= every);
+        if full || old {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let path = &self.config.path;
+        for n in (1..self.config.max_files).rev() {
+            ignore_not_found(std::fs::rename(rotated(path, n), rotated(path, n + 1)))?;
+        }
+        if self.config.max_files > 0 {
+            std::fs::rename(path, rotated(path, 1))?;
+        } else {
+            ignore_not_found(std::fs::remove_file(path))?;
+        }
+        self.file = open_append(path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = OsString::from(path);
+    rotated.push(format!(".{n}"));
+    rotated.into()
+}
+
+fn ignore_not_found(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Format `time` as RFC 3339 in UTC, with milliseconds.
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = unix_secs(time);
+    let millis = time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.subsec_millis());
+    let (year, month, day) = civil_date(secs.div_euclid(86400));
+    let secs_of_day = secs.rem_euclid(86400);
+    let (hour, minute, second) = (secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z")
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_option(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".into(), json_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_and_strings() {
+        let at = |millis| format_timestamp(UNIX_EPOCH + Duration::from_millis(millis));
+        assert_eq!(at(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(1_709_251_199_250), "2024-02-29T23:59:59.250Z");
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+        assert_eq!(json_option(None), "null");
+    }
+
+    #[tokio::test]
+    async fn records_are_written_and_rotated() {
+        let dir = std::env::temp_dir().join(format!("socks5-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let config = AuditConfig::new(&path).with_max_bytes(300).with_redact_hosts(true);
+        let log = AuditLog::open(config).unwrap();
+
+        let ctx = SessionContext::new("192.0.2.1:50000".parse().unwrap(), "192.0.2.2:1080".parse().unwrap());
+        let target = Address::from(("example.com", 443));
+        log.on_request(&ctx, Command::Connect, &target);
+        log.on_connected(&ctx, &target, Some("203.0.113.1:443".parse().unwrap()), Duration::ZERO);
+        log.on_reply(&ctx, Reply::Succeeded);
+        let summary = SessionSummary {
+            bytes_up: 517,
+            bytes_down: 4096,
+            ..Default::default()
+        };
+        log.on_closed(&ctx, &summary);
+        log.on_closed(&ctx, &SessionSummary::default());
+        log.flush().await;
+
+        let first = std::fs::read_to_string(rotated(&path, 1)).unwrap();
+        assert!(first.starts_with(&format!("{{\"timestamp\":{}", json_string(&format_timestamp(ctx.accepted_at)))));
+        let expected = format!(
+            "\"session_id\":{},\"client_addr\":\"192.0.2.1:50000\",\"username\":null,\"command\":\"connect\",\"target\":\"redacted:443\",\"resolved_ip\":null,\"reply\":\"succeeded\",\"bytes_up\":517,\"bytes_down\":4096,\"duration_ms\":0,\"error\":null}}\n",
+            ctx.id
+        );
+        assert!(first.ends_with(&expected), "{first}");
+        let second = std::fs::read_to_string(&path).unwrap();
+        assert!(second.contains("\"command\":null"), "{second}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
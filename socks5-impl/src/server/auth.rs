@@ -1,6 +1,6 @@
 use crate::protocol::{AuthMethod, StreamOperation, UserKey, handshake::password_method};
+use crate::server::{context::SessionContext, transport::AsyncStream};
 use std::sync::Arc;
-use tokio::net::TcpStream;
 
 /// This trait is for defining the socks5 authentication method.
 ///
@@ -9,11 +9,12 @@ use tokio::net::TcpStream;
 /// You can create your own authentication method by implementing this trait. Since GAT is not stabled yet,
 /// [async_trait](https://docs.rs/async-trait/latest/async_trait/index.html) needs to be used.
 ///
+/// The method runs over the stream of the connection, whatever its transport, see [`AsyncStream`].
+///
 /// # Example
 /// ```rust
 /// use socks5_impl::protocol::AuthMethod;
-/// use socks5_impl::server::auth::AuthExecutor;
-/// use tokio::net::TcpStream;
+/// use socks5_impl::server::{AsyncStream, auth::AuthExecutor};
 ///
 /// pub struct MyAuth;
 ///
@@ -25,7 +26,7 @@ use tokio::net::TcpStream;
 ///         AuthMethod::from(0x80)
 ///     }
 ///
-///     async fn execute(&self, _stream: &mut TcpStream) -> Self::Output {
+///     async fn execute(&self, _stream: &mut dyn AsyncStream) -> Self::Output {
 ///         // do something
 ///         Ok(1145141919810)
 ///     }
@@ -36,7 +37,37 @@ pub trait AuthExecutor {
     type Output;
     fn auth_method(&self) -> AuthMethod;
     fn set_method(&mut self, _: AuthMethod) {}
-    async fn execute(&self, stream: &mut TcpStream) -> Self::Output;
+    async fn execute(&self, stream: &mut dyn AsyncStream) -> Self::Output;
+
+    /// Run the authentication method knowing the [`SessionContext`] of the connection, such as the
+    /// [`peer_cred`](SessionContext::peer_cred) of a Unix socket client.
+    ///
+    /// This is what the server calls; it runs [`execute`](Self::execute) by default.
+    async fn execute_with_context(&self, stream: &mut dyn AsyncStream, _ctx: &SessionContext) -> Self::Output {
+        self.execute(stream).await
+    }
+
+    /// The name of the user authenticated by `output`, if any.
+    ///
+    /// It is used for the per user caps of [`ConnectionLimits`](crate::server::ConnectionLimits).
+    fn identity(&self, _output: &Self::Output) -> Option<String> {
+        None
+    }
+
+    /// The reason why `output` is a failed authentication, if it is one.
+    ///
+    /// It labels the authentication failures counted by [`Metrics`](crate::server::Metrics).
+    fn failure_reason(&self, _output: &Self::Output) -> Option<&'static str> {
+        None
+    }
+
+    /// Check the username and password of a client which does not speak SOCKS5, such as an HTTP proxy client.
+    ///
+    /// `None` means that this method does not authenticate with a username and password, so only SOCKS5 clients
+    /// can be served, unless [`auth_method`](Self::auth_method) is [`AuthMethod::NoAuth`].
+    fn verify_password(&self, _username: &str, _password: &str) -> Option<bool> {
+        None
+    }
 }
 
 pub type AuthAdaptor<O> = Arc<dyn AuthExecutor<Output = O> + Send + Sync>;
@@ -52,7 +83,7 @@ impl AuthExecutor for NoAuth {
         AuthMethod::NoAuth
     }
 
-    async fn execute(&self, _: &mut TcpStream) -> Self::Output {}
+    async fn execute(&self, _: &mut dyn AsyncStream) -> Self::Output {}
 }
 
 /// Username and password as the socks5 handshake method.
@@ -75,7 +106,7 @@ impl AuthExecutor for UserKeyAuth {
         AuthMethod::UserPass
     }
 
-    async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+    async fn execute(&self, stream: &mut dyn AsyncStream) -> Self::Output {
         use password_method::{Request, Response, Status::*};
         let req = Request::retrieve_from_async_stream(stream).await?;
 
@@ -88,4 +119,20 @@ impl AuthExecutor for UserKeyAuth {
             Err(std::io::Error::other("username or password is incorrect"))
         }
     }
+
+    fn identity(&self, output: &Self::Output) -> Option<String> {
+        matches!(output, Ok(true)).then(|| self.user_key.username.clone())
+    }
+
+    fn failure_reason(&self, output: &Self::Output) -> Option<&'static str> {
+        match output {
+            Ok(_) => None,
+            Err(err) if err.kind() == std::io::ErrorKind::Other => Some("invalid_credentials"),
+            Err(_) => Some("protocol_error"),
+        }
+    }
+
+    fn verify_password(&self, username: &str, password: &str) -> Option<bool> {
+        Some(username == self.user_key.username && password == self.user_key.password)
+    }
 }
@@ -1,38 +1,53 @@
 use crate::protocol::{Address, Reply, Response, StreamOperation, UdpHeader};
+use crate::server::{
+    accounting::UserAccount,
+    context::SessionContext,
+    metrics::Metrics,
+    session::Session,
+    throttle::Throttle,
+    timeout::{Deadlines, SessionTimeouts, wait_expired},
+    transport::AsyncStream,
+};
 use bytes::{Bytes, BytesMut};
 use std::{
     net::SocketAddr,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 use stream::Stream;
 use tokio::{
     io::AsyncReadExt,
-    net::{ToSocketAddrs, UdpSocket},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
 };
 
 /// Socks5 connection type `UdpAssociate`
 #[derive(Debug)]
-pub struct UdpAssociate<S> {
-    pub stream: Stream,
+pub struct UdpAssociate<S, T: AsyncStream = TcpStream> {
+    pub stream: Stream<T>,
+    session: Session,
     _state: S,
 }
 
-impl<S: Default> UdpAssociate<S> {
+impl<S: Default, T: AsyncStream> UdpAssociate<S, T> {
     #[inline]
-    pub(super) fn new(stream: Stream) -> Self {
+    pub(super) fn new(stream: Stream<T>, session: Session) -> Self {
         Self {
             stream,
+            session,
             _state: S::default(),
         }
     }
 
     /// Reply to the SOCKS5 client with the given reply and address.
     ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<UdpAssociate<Ready>> {
+    /// If encountered an error while writing the reply, the error is returned.
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<UdpAssociate<Ready, T>> {
+        self.session.on_reply(reply);
         let resp = Response::new(reply, addr);
         resp.write_to_async_stream(&mut *self.stream).await?;
-        Ok(UdpAssociate::<Ready>::new(self.stream))
+        Ok(UdpAssociate::<Ready, T>::new(self.stream, self.session))
     }
 }
 
@@ -42,8 +57,8 @@ pub struct NeedReply;
 #[derive(Debug, Default)]
 pub struct Ready;
 
-impl UdpAssociate<Ready> {
-    /// Wait until the client closes this TCP connection.
+impl<T: AsyncStream> UdpAssociate<Ready, T> {
+    /// Wait until the client closes this connection.
     ///
     /// Socks5 protocol defines that when the client closes the TCP connection used to send the associate command,
     /// the server should release the associated UDP socket.
@@ -58,9 +73,28 @@ impl UdpAssociate<Ready> {
     }
 }
 
-impl<S> From<UdpAssociate<S>> for Stream {
+impl<S, T: AsyncStream> UdpAssociate<S, T> {
+    /// Get the [`SessionContext`] of this connection.
     #[inline]
-    fn from(conn: UdpAssociate<S>) -> Self {
+    pub fn context(&self) -> &SessionContext {
+        self.session.context()
+    }
+
+    /// Record bytes relayed for this session, reported to the [`SessionObserver`](crate::server::SessionObserver)
+    /// when the session is closed.
+    pub fn record_transfer(&mut self, bytes_up: u64, bytes_down: u64) {
+        self.session.record_transfer(bytes_up, bytes_down);
+    }
+
+    /// Record the error ending this session, reported to the [`SessionObserver`](crate::server::SessionObserver).
+    pub fn record_error(&mut self, err: &std::io::Error) {
+        self.session.record_error(err);
+    }
+}
+
+impl<S, T: AsyncStream> From<UdpAssociate<S, T>> for Stream<T> {
+    #[inline]
+    fn from(conn: UdpAssociate<S, T>) -> Self {
         conn.stream
     }
 }
@@ -89,9 +123,91 @@ impl<S> From<UdpAssociate<S>> for Stream {
 pub struct AssociatedUdpSocket {
     socket: UdpSocket,
     buf_size: AtomicUsize,
+    throttle: Throttle,
+    account: Option<UserAccount>,
+    metrics: Option<Metrics>,
+    deadlines: Option<Mutex<Deadlines>>,
 }
 
 impl AssociatedUdpSocket {
+    /// Throttle the relayed packets: received packets count as upload, sent packets as download.
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Count the relayed packets on `account`: received packets count as upload, sent packets as download.
+    ///
+    /// Once the user is over quota, receiving and sending fail with [`QuotaExceeded`](std::io::ErrorKind::QuotaExceeded).
+    pub fn with_account(mut self, account: UserAccount) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    /// Count the relayed bytes, and the packets dropped for an invalid SOCKS5 UDP header, in `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Close the association when no packet went through in either direction for too long, or once it reaches its
+    /// maximum lifetime: receiving then fails with [`TimedOut`](std::io::ErrorKind::TimedOut).
+    pub fn with_timeouts(mut self, timeouts: SessionTimeouts) -> Self {
+        self.deadlines = Some(Mutex::new(Deadlines::new(timeouts)));
+        self
+    }
+
+    fn touch(&self) {
+        if let Some(deadlines) = &self.deadlines {
+            deadlines.lock().unwrap().touch();
+        }
+    }
+
+    /// Run `recv` until the association times out.
+    async fn until_expired<T>(&self, recv: impl Future<Output = std::io::Result<T>>) -> std::io::Result<T> {
+        let Some(deadlines) = &self.deadlines else {
+            return recv.await;
+        };
+        tokio::select! {
+            result = recv => result,
+            err = wait_expired(|| *deadlines.lock().unwrap()) => Err(err),
+        }
+    }
+
+    /// Account and throttle a received packet of `len` bytes.
+    async fn received(&self, len: usize) -> std::io::Result<()> {
+        self.touch();
+        if let Some(account) = &self.account {
+            account.check()?;
+            account.add_upload(len);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.add_bytes_up(len);
+        }
+        self.throttle.consume_upload(len).await;
+        Ok(())
+    }
+
+    /// Check the quota and throttle before sending a packet of `len` bytes.
+    async fn sending(&self, len: usize) -> std::io::Result<()> {
+        if let Some(account) = &self.account {
+            account.check()?;
+        }
+        self.throttle.consume_download(len).await;
+        Ok(())
+    }
+
+    fn sent(&self, len: usize) -> usize {
+        self.touch();
+        if let Some(account) = &self.account {
+            account.add_download(len);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.add_bytes_down(len);
+        }
+        len
+    }
+
     /// Connects the UDP socket setting the default destination for send() and limiting packets that are read via recv from the address specified in addr.
     #[inline]
     pub async fn connect<A: ToSocketAddrs>(&self, addr: A) -> std::io::Result<()> {
@@ -114,6 +230,10 @@ impl AssociatedUdpSocket {
     /// The [`connect`](#method.connect) method will connect this socket to a remote address.
     /// This method will fail if the socket is not connected.
     pub async fn recv(&self) -> std::io::Result<(Bytes, u8, Address)> {
+        self.until_expired(self.recv_packet()).await
+    }
+
+    async fn recv_packet(&self) -> std::io::Result<(Bytes, u8, Address)> {
         let max_packet_size = self.buf_size.load(Ordering::Acquire);
         // 使用 BytesMut 避免初始化开销，并支持潜在的缓冲区复用
         let mut buf = BytesMut::zeroed(max_packet_size);
@@ -124,10 +244,14 @@ impl AssociatedUdpSocket {
 
             if let Ok(header) = UdpHeader::retrieve_from_async_stream(&mut pkt.as_ref()).await {
                 let pkt = pkt.slice(header.len()..);
+                self.received(pkt.len()).await?;
                 return Ok((pkt, header.frag, header.address));
             }
 
             // 解析失败，重置缓冲区以便重试
+            if let Some(metrics) = &self.metrics {
+                metrics.udp_packet_dropped();
+            }
             buf.clear();
             buf.resize(max_packet_size, 0);
         }
@@ -136,6 +260,10 @@ impl AssociatedUdpSocket {
     /// Receives a socks5 UDP relay packet on the socket from the any remote address.
     /// On success, returns the packet itself, the fragment number, the remote target address and the source address.
     pub async fn recv_from(&self) -> std::io::Result<(Bytes, u8, Address, SocketAddr)> {
+        self.until_expired(self.recv_packet_from()).await
+    }
+
+    async fn recv_packet_from(&self) -> std::io::Result<(Bytes, u8, Address, SocketAddr)> {
         let max_packet_size = self.buf_size.load(Ordering::Acquire);
         // 使用 BytesMut 避免初始化开销，并支持潜在的缓冲区复用
         let mut buf = BytesMut::zeroed(max_packet_size);
@@ -146,10 +274,14 @@ impl AssociatedUdpSocket {
 
             if let Ok(header) = UdpHeader::retrieve_from_async_stream(&mut pkt.as_ref()).await {
                 let pkt = pkt.slice(header.len()..);
+                self.received(pkt.len()).await?;
                 return Ok((pkt, header.frag, header.address, src_addr));
             }
 
             // 解析失败，重置缓冲区以便重试
+            if let Some(metrics) = &self.metrics {
+                metrics.udp_packet_dropped();
+            }
             buf.clear();
             buf.resize(max_packet_size, 0);
         }
@@ -162,7 +294,9 @@ impl AssociatedUdpSocket {
         header.write_to_buf(&mut buf);
         buf.extend_from_slice(pkt.as_ref());
 
-        self.socket.send(&buf).await.map(|len| len - header.len())
+        self.sending(pkt.as_ref().len()).await?;
+        let len = self.socket.send(&buf).await?;
+        Ok(self.sent(len - header.len()))
     }
 
     /// Sends a UDP relay packet to a specified remote address to which it is connected. The socks5 UDP header will be added to the packet.
@@ -172,7 +306,9 @@ impl AssociatedUdpSocket {
         header.write_to_buf(&mut buf);
         buf.extend_from_slice(pkt.as_ref());
 
-        self.socket.send_to(&buf, to_addr).await.map(|len| len - header.len())
+        self.sending(pkt.as_ref().len()).await?;
+        let len = self.socket.send_to(&buf, to_addr).await?;
+        Ok(self.sent(len - header.len()))
     }
 }
 
@@ -182,6 +318,10 @@ impl From<(UdpSocket, usize)> for AssociatedUdpSocket {
         AssociatedUdpSocket {
             socket: from.0,
             buf_size: AtomicUsize::new(from.1),
+            throttle: Throttle::default(),
+            account: None,
+            metrics: None,
+            deadlines: None,
         }
     }
 }
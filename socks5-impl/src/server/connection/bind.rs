@@ -1,7 +1,11 @@
-use crate::protocol::{Address, Reply, Response, StreamOperation};
+use crate::protocol::{Address, Reply};
+use crate::server::{context::SessionContext, session::Session, transport::AsyncStream};
 use std::marker::PhantomData;
 use stream::Stream;
-use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpStream, tcp::WriteHalf},
+};
 
 /// Socks5 command type `Bind`
 ///
@@ -11,10 +15,11 @@ use tokio::net::tcp::{ReadHalf, WriteHalf};
 /// using [`reply()`](crate::server::connection::Bind::reply),
 /// you will get a `Bind<Ready>`, which can be used as a regular async TCP stream.
 ///
-/// A `Bind<S>` can be converted to a regular tokio [`TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) by using the `From` trait.
+/// A `Bind<S>` can be converted to a [`Stream`] by using the `From` trait.
 #[derive(Debug)]
-pub struct Bind<S> {
-    pub stream: Stream,
+pub struct Bind<S, T: AsyncStream = TcpStream> {
+    pub stream: Stream<T>,
+    session: Session,
     _state: PhantomData<S>,
 }
 
@@ -30,67 +35,94 @@ pub struct NeedSecondReply;
 #[derive(Debug, Default)]
 pub struct Ready;
 
-impl Bind<NeedFirstReply> {
+impl<T: AsyncStream> Bind<NeedFirstReply, T> {
     #[inline]
-    pub(super) fn new(stream: Stream) -> Self {
+    pub(super) fn new(stream: Stream<T>, session: Session) -> Self {
         Self {
             stream,
+            session,
             _state: PhantomData,
         }
     }
 
     /// Reply to the SOCKS5 client with the given reply and address.
     ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Bind<NeedSecondReply>> {
-        let resp = Response::new(reply, addr);
-        resp.write_to_async_stream(&mut *self.stream).await?;
-        Ok(Bind::<NeedSecondReply>::new(self.stream))
+    /// If encountered an error while writing the reply, the error alongside the original stream is returned.
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Bind<NeedSecondReply, T>> {
+        self.session.on_reply(reply);
+        super::write_reply(&mut self.stream, self.session.dialect(), reply, addr).await?;
+        Ok(Bind::<NeedSecondReply, T>::new(self.stream, self.session))
     }
 }
 
-impl Bind<NeedSecondReply> {
+impl<T: AsyncStream> Bind<NeedSecondReply, T> {
     #[inline]
-    fn new(stream: Stream) -> Self {
+    fn new(stream: Stream<T>, session: Session) -> Self {
         Self {
             stream,
+            session,
             _state: PhantomData,
         }
     }
 
     /// Reply to the SOCKS5 client with the given reply and address.
     ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> Result<Bind<Ready>, (std::io::Error, Stream)> {
-        let resp = Response::new(reply, addr);
-
-        if let Err(err) = resp.write_to_async_stream(&mut *self.stream).await {
+    /// If encountered an error while writing the reply, the error alongside the original stream is returned.
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> Result<Bind<Ready, T>, (std::io::Error, Stream<T>)> {
+        self.session.on_reply(reply);
+        if let Err(err) = super::write_reply(&mut self.stream, self.session.dialect(), reply, addr).await {
             return Err((err, self.stream));
         }
 
-        Ok(Bind::<Ready>::new(self.stream))
+        Ok(Bind::<Ready, T>::new(self.stream, self.session))
     }
 }
 
-impl Bind<Ready> {
+impl<T: AsyncStream> Bind<Ready, T> {
     #[inline]
-    fn new(stream: Stream) -> Self {
+    fn new(stream: Stream<T>, session: Session) -> Self {
         Self {
             stream,
+            session,
             _state: PhantomData,
         }
     }
+}
 
+impl Bind<Ready> {
     /// Split the connection into a read and a write half.
+    ///
+    /// The read half yields the bytes the client sent ahead of the replies first, see [`Connect::split`](super::connect::Connect::split).
     #[inline]
-    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
-        self.stream.split()
+    pub fn split(&mut self) -> (super::BufferedReadHalf<'_>, WriteHalf<'_>) {
+        let buffered = std::io::Cursor::new(self.stream.take_buffered());
+        let (read, write) = (*self.stream).split();
+        (buffered.chain(read), write)
+    }
+}
+
+impl<S, T: AsyncStream> Bind<S, T> {
+    /// Get the [`SessionContext`] of this connection.
+    #[inline]
+    pub fn context(&self) -> &SessionContext {
+        self.session.context()
+    }
+
+    /// Record bytes relayed for this session, reported to the [`SessionObserver`](crate::server::SessionObserver)
+    /// when the session is closed.
+    pub fn record_transfer(&mut self, bytes_up: u64, bytes_down: u64) {
+        self.session.record_transfer(bytes_up, bytes_down);
+    }
+
+    /// Record the error ending this session, reported to the [`SessionObserver`](crate::server::SessionObserver).
+    pub fn record_error(&mut self, err: &std::io::Error) {
+        self.session.record_error(err);
     }
 }
 
-impl<S> From<Bind<S>> for Stream {
+impl<S, T: AsyncStream> From<Bind<S, T>> for Stream<T> {
     #[inline]
-    fn from(conn: Bind<S>) -> Self {
+    fn from(conn: Bind<S, T>) -> Self {
         conn.stream
     }
 }
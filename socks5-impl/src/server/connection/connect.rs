@@ -1,21 +1,30 @@
-use crate::protocol::{Address, Reply, Response, StreamOperation};
+use crate::protocol::{Address, Reply};
+use crate::server::outbound::{OutboundConnector, OutboundContext};
+use crate::server::relay::{self, RelayStats, RelaySummary, Transfer};
+use crate::server::{context::SessionContext, session::Session, transport::AsyncStream};
+use std::time::Instant;
 use stream::Stream;
-use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    net::{TcpStream, tcp::WriteHalf},
+};
 
 /// Socks5 connection type `Connect`
 ///
-/// This connection can be used as a regular async TCP stream after replying the client.
+/// This connection can be used as a regular async stream after replying the client.
 #[derive(Debug)]
-pub struct Connect<S> {
-    pub stream: Stream,
+pub struct Connect<S, T: AsyncStream = TcpStream> {
+    pub stream: Stream<T>,
+    session: Session,
     _state: S,
 }
 
-impl<S: Default> Connect<S> {
+impl<S: Default, T: AsyncStream> Connect<S, T> {
     #[inline]
-    pub(super) fn new(stream: Stream) -> Self {
+    pub(super) fn new(stream: Stream<T>, session: Session) -> Self {
         Self {
             stream,
+            session,
             _state: S::default(),
         }
     }
@@ -27,27 +36,106 @@ pub struct NeedReply;
 #[derive(Debug, Default)]
 pub struct Ready;
 
-impl Connect<NeedReply> {
+impl<T: AsyncStream> Connect<NeedReply, T> {
     /// Reply to the client.
     #[inline]
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Connect<Ready>> {
-        let resp = Response::new(reply, addr);
-        resp.write_to_async_stream(&mut *self.stream).await?;
-        Ok(Connect::<Ready>::new(self.stream))
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Connect<Ready, T>> {
+        self.session.on_reply(reply);
+        super::write_reply(&mut self.stream, self.session.dialect(), reply, addr).await?;
+        Ok(Connect::<Ready, T>::new(self.stream, self.session))
+    }
+
+    /// Open the outbound connection to `target` with the given connector and reply to the client accordingly.
+    ///
+    /// On success the client is replied with [`Reply::Succeeded`] and the bound address reported by the connector,
+    /// and the ready connection is returned alongside the outbound stream.
+    /// On failure the client is replied with the [`Reply`] matching the error, and the error is returned.
+    pub async fn connect_outbound<C>(
+        self,
+        connector: &C,
+        target: &Address,
+        ctx: &OutboundContext,
+    ) -> std::io::Result<(Connect<Ready, T>, C::Stream)>
+    where
+        C: OutboundConnector + Sync,
+    {
+        let start = Instant::now();
+        match connector.connect(target, ctx).await {
+            Ok((outbound, bound)) => {
+                self.session.on_connected(target, connector.peer_addr(&outbound), start.elapsed());
+                Ok((self.reply(Reply::Succeeded, bound).await?, outbound))
+            }
+            Err(err) => {
+                let mut conn = self.reply(Reply::from(&err), Address::unspecified()).await?;
+                conn.session.record_error(&err);
+                let _ = conn.stream.shutdown().await;
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<T: AsyncStream> Connect<Ready, T> {
+    /// Relay the bytes between the client and `outbound` until both directions are done, see [`relay`](mod@relay).
+    ///
+    /// The bytes relayed, or the error ending the relay, are recorded for the session.
+    pub async fn relay<U>(&mut self, outbound: &mut U) -> std::io::Result<Transfer>
+    where
+        U: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        self.relay_with_stats(outbound, &RelayStats::new()).await.into_result()
+    }
+
+    /// Relay the bytes between the client and `outbound` as [`relay`](Self::relay) does, recording the activity of
+    /// each direction in `stats`, and summarize the relay once it is done.
+    pub async fn relay_with_stats<U>(&mut self, outbound: &mut U, stats: &RelayStats) -> RelaySummary
+    where
+        U: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let summary = relay::relay_with_stats(&mut self.stream, outbound, stats).await;
+        self.record_transfer(summary.up.bytes, summary.down.bytes);
+        if let Some(err) = &summary.error {
+            self.record_error(err);
+        }
+        summary
     }
 }
 
 impl Connect<Ready> {
     /// Returns the read/write half of the stream.
+    ///
+    /// The read half yields the bytes the client pipelined after its request, taken out of
+    /// [`buffered`](Stream::buffered), before those of the TCP stream.
     #[inline]
-    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
-        (*self.stream).split()
+    pub fn split(&mut self) -> (super::BufferedReadHalf<'_>, WriteHalf<'_>) {
+        let buffered = std::io::Cursor::new(self.stream.take_buffered());
+        let (read, write) = (*self.stream).split();
+        (buffered.chain(read), write)
+    }
+}
+
+impl<S, T: AsyncStream> Connect<S, T> {
+    /// Get the [`SessionContext`] of this connection.
+    #[inline]
+    pub fn context(&self) -> &SessionContext {
+        self.session.context()
+    }
+
+    /// Record bytes relayed for this session, reported to the [`SessionObserver`](crate::server::SessionObserver)
+    /// when the session is closed.
+    pub fn record_transfer(&mut self, bytes_up: u64, bytes_down: u64) {
+        self.session.record_transfer(bytes_up, bytes_down);
+    }
+
+    /// Record the error ending this session, reported to the [`SessionObserver`](crate::server::SessionObserver).
+    pub fn record_error(&mut self, err: &std::io::Error) {
+        self.session.record_error(err);
     }
 }
 
-impl<S> From<Connect<S>> for Stream {
+impl<S, T: AsyncStream> From<Connect<S, T>> for Stream<T> {
     #[inline]
-    fn from(conn: Connect<S>) -> Self {
+    fn from(conn: Connect<S, T>) -> Self {
         conn.stream
     }
 }
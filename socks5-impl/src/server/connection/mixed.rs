@@ -0,0 +1,391 @@
+//! Serving SOCKS5, SOCKS4 and HTTP proxy clients on the same port.
+//!
+//! [`IncomingConnection::sniff`] reads the first byte the client sends to tell the protocols apart.
+//! SOCKS4 and HTTP clients are turned into the same [`Connect`] and [`Bind`] as SOCKS5 clients,
+//! whose replies are written in the protocol of the client.
+//!
+//! ```no_run
+//! use socks5_impl::protocol::{Address, Reply};
+//! use socks5_impl::server::{ClientConnection, Connect, MixedConnection, Server, auth::NoAuth, connection::connect::NeedReply};
+//! use std::sync::Arc;
+//! use tokio::{io::AsyncWriteExt, net::TcpStream};
+//!
+//! async fn relay(conn: Connect<NeedReply>, target: Address, prelude: &[u8]) -> socks5_impl::Result<()> {
+//!     let mut outbound = TcpStream::connect(target.to_string()).await?;
+//!     let mut conn = conn.reply(Reply::Succeeded, Address::unspecified()).await?;
+//!     outbound.write_all(prelude).await?;
+//!     tokio::io::copy_bidirectional(&mut conn.stream, &mut outbound).await?;
+//!     Ok(())
+//! }
+//!
+//! # async fn example() -> socks5_impl::Result<()> {
+//! let server = Server::bind("127.0.0.1:1080".parse().unwrap(), Arc::new(NoAuth)).await?;
+//! let (conn, _) = server.accept().await?;
+//! match conn.sniff().await? {
+//!     MixedConnection::Socks5(conn) => {
+//!         let (conn, _) = conn.authenticate().await?;
+//!         if let ClientConnection::Connect(conn, target) = conn.wait_request().await? {
+//!             relay(conn, target, &[]).await?;
+//!         }
+//!     }
+//!     MixedConnection::Socks4(ClientConnection::Connect(conn, target), _) => relay(conn, target, &[]).await?,
+//!     MixedConnection::Http(conn, target, request) => relay(conn, target, &request.upstream_prelude()).await?,
+//!     MixedConnection::Socks4(..) => {}
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use super::{ClientConnection, IncomingConnection, bind::Bind, connect::Connect};
+use crate::{
+    protocol::{Address, AuthMethod, Command, Reply, StreamOperation, socks4},
+    server::{session::Dialect, transport::AsyncStream},
+};
+use bytes::{Bytes, BytesMut};
+use http::{Method, StatusCode};
+use http_impl::{BasicAuth, HttpRequest, HttpResponseBuilder};
+use std::net::IpAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// A connection whose protocol has been detected by [`IncomingConnection::sniff`].
+#[derive(Debug)]
+pub enum MixedConnection<O, T: AsyncStream = TcpStream> {
+    /// A SOCKS5 client, to be authenticated with [`IncomingConnection::authenticate`].
+    Socks5(IncomingConnection<O, T>),
+    /// The request of a SOCKS4 or SOCKS4a client, alongside its user id.
+    Socks4(ClientConnection<T>, String),
+    /// An HTTP proxy client, either opening a `CONNECT` tunnel or sending a plain HTTP request to forward.
+    Http(Connect<super::connect::NeedReply, T>, Address, Box<HttpProxyRequest>),
+}
+
+/// The request of an HTTP proxy client.
+#[derive(Debug)]
+pub struct HttpProxyRequest {
+    request: HttpRequest,
+}
+
+impl HttpProxyRequest {
+    /// The request as read from the client.
+    pub fn request(&self) -> &HttpRequest {
+        &self.request
+    }
+
+    /// Whether the client asks for a `CONNECT` tunnel, rather than sending a request to forward.
+    pub fn is_tunnel(&self) -> bool {
+        self.request.method() == Method::CONNECT
+    }
+
+    /// The credentials sent in the `Proxy-Authorization` header, if any.
+    pub fn credentials(&self) -> Option<BasicAuth> {
+        self.request.parse_basic_auth().ok().flatten()
+    }
+
+    /// The bytes to send to the target before relaying the connection.
+    ///
+    /// For a plain HTTP request, this is the request in origin form, without its `Proxy-*` headers and with
+    /// `Connection: close`, since the following requests of the client are relayed to the same target.
+    /// In both cases, it ends with what the client sent after the request head.
+    pub fn upstream_prelude(&self) -> Bytes {
+        if self.is_tunnel() {
+            return self.request.body().clone();
+        }
+        let path = self.request.uri().path_and_query().map_or("/", |path| path.as_str());
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(format!("{} {path} HTTP/1.1\r\n", self.request.method()).as_bytes());
+        for (name, value) in self.request.headers() {
+            if name.as_str().starts_with("proxy-") || name == http::header::CONNECTION {
+                continue;
+            }
+            buf.extend_from_slice(name.as_str().as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(value.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"Connection: close\r\n\r\n");
+        buf.extend_from_slice(self.request.body());
+        buf.freeze()
+    }
+
+    /// The address the request is for.
+    fn target(&self) -> Option<Address> {
+        let uri = self.request.uri();
+        let port = match uri.port_u16() {
+            Some(port) => port,
+            None if self.is_tunnel() => return None,
+            None if uri.scheme_str() == Some("http") => 80,
+            None => return None,
+        };
+        let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+        Some(match host.parse::<IpAddr>() {
+            Ok(ip) => Address::from((ip, port)),
+            Err(_) => Address::from((host, port)),
+        })
+    }
+}
+
+/// The protocol found by [`IncomingConnection::sniff`], with the request read so far.
+enum Detected {
+    Socks5,
+    Socks4(socks4::Request),
+    Http(Address, Box<HttpProxyRequest>),
+}
+
+impl<O, T: AsyncStream> IncomingConnection<O, T> {
+    /// Detect the protocol of the client from its first byte: `0x05` for SOCKS5, `0x04` for SOCKS4,
+    /// and an ASCII letter for an HTTP proxy request.
+    ///
+    /// SOCKS4 and HTTP requests are read and returned ready to be replied. As those clients cannot run the SOCKS5
+    /// handshake, they are only served when the [`AuthExecutor`](crate::server::AuthExecutor) requires no authentication,
+    /// or for HTTP clients, when it accepts the `Proxy-Authorization` credentials with
+    /// [`verify_password`](crate::server::AuthExecutor::verify_password). Other clients are rejected.
+    pub async fn sniff(mut self) -> crate::Result<MixedConnection<O, T>> {
+        let detected = match self.detect().await {
+            Ok(Detected::Socks5) => return Ok(MixedConnection::Socks5(self)),
+            Ok(detected) => detected,
+            Err(err) => {
+                self.session.record_error(&err);
+                return Err(err);
+            }
+        };
+        let (stream, session) = (self.stream, self.session);
+        Ok(match detected {
+            Detected::Socks4(request) => {
+                let conn = match request.command {
+                    Command::Bind => ClientConnection::Bind(Bind::new(stream, session), request.address),
+                    _ => ClientConnection::Connect(Connect::new(stream, session), request.address),
+                };
+                MixedConnection::Socks4(conn, request.user_id)
+            }
+            Detected::Http(target, request) => MixedConnection::Http(Connect::new(stream, session), target, request),
+            Detected::Socks5 => unreachable!(),
+        })
+    }
+
+    async fn detect(&mut self) -> crate::Result<Detected> {
+        let first = self.stream.read_u8().await?;
+        self.sniffed = Some(first);
+        match first {
+            0x05 => Ok(Detected::Socks5),
+            0x04 => Ok(Detected::Socks4(self.socks4().await?)),
+            b'A'..=b'Z' => self.http().await,
+            byte => {
+                let err = format!("unknown protocol starting with {byte:#04x}");
+                Err(std::io::Error::new(std::io::ErrorKind::Unsupported, err).into())
+            }
+        }
+    }
+
+    async fn socks4(&mut self) -> crate::Result<socks4::Request> {
+        let sniffed = self.sniffed.take();
+        let request = socks4::Request::retrieve_from_async_stream(&mut sniffed.as_slice().chain(&mut self.stream)).await?;
+        self.session.set_dialect(Dialect::Socks4);
+        self.session.on_request(request.command, &request.address);
+        if self.auth.auth_method() != AuthMethod::NoAuth {
+            self.session.on_auth_failure("no_acceptable_method");
+            self.session.on_reply(Reply::ConnectionNotAllowed);
+            super::write_reply(
+                &mut self.stream,
+                Dialect::Socks4,
+                Reply::ConnectionNotAllowed,
+                Address::unspecified(),
+            )
+            .await?;
+            let err = "SOCKS4 clients cannot authenticate";
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, err).into());
+        }
+        // The client may still have an identity, such as the name of its TLS certificate
+        if let Err(err) = self.session.admit_request(request.command) {
+            self.session.on_reply(Reply::GeneralFailure);
+            super::write_reply(&mut self.stream, Dialect::Socks4, Reply::GeneralFailure, Address::unspecified()).await?;
+            return Err(err.into());
+        }
+        Ok(request)
+    }
+
+    async fn http(&mut self) -> crate::Result<Detected> {
+        let invalid = |err: http_impl::HttpError| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string());
+        let sniffed = self.sniffed.take();
+        let request = HttpProxyRequest {
+            request: HttpRequest::from_stream(&mut sniffed.as_slice().chain(&mut self.stream))
+                .await
+                .map_err(invalid)?,
+        };
+        let dialect = if request.is_tunnel() {
+            Dialect::HttpConnect
+        } else {
+            Dialect::HttpForward
+        };
+        self.session.set_dialect(dialect);
+
+        if self.auth.auth_method() != AuthMethod::NoAuth {
+            let credentials = request.credentials();
+            let verified = credentials
+                .as_ref()
+                .and_then(|c| self.auth.verify_password(&c.username, &c.password));
+            match (credentials, verified) {
+                (Some(credentials), Some(true)) => {
+                    self.session.set_auth_method(AuthMethod::UserPass);
+                    self.session.set_user(Some(credentials.username));
+                }
+                (_, verified) => {
+                    let reason = if verified.is_some() {
+                        "invalid_credentials"
+                    } else {
+                        "no_acceptable_method"
+                    };
+                    self.session.on_auth_failure(reason);
+                    let response = HttpResponseBuilder::new()
+                        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+                        .header("Proxy-Authenticate", "Basic realm=\"proxy\"")
+                        .header("Content-Length", "0")
+                        .header("Connection", "close")
+                        .build();
+                    response.write_to_stream(&mut self.stream).await.map_err(invalid)?;
+                    let err = "HTTP proxy authentication failed";
+                    return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, err).into());
+                }
+            }
+        }
+
+        let Some(target) = request.target() else {
+            write_http_reply(&mut self.stream, dialect, Reply::AddressTypeNotSupported).await?;
+            let err = format!("no target in HTTP request for {}", request.request.uri());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err).into());
+        };
+        self.session.on_request(Command::Connect, &target);
+        if let Err(err) = self.session.admit_request(Command::Connect) {
+            self.session.on_reply(Reply::GeneralFailure);
+            write_http_reply(&mut self.stream, dialect, Reply::GeneralFailure).await?;
+            return Err(err.into());
+        }
+        Ok(Detected::Http(target, Box::new(request)))
+    }
+}
+
+/// Write `reply` as an HTTP response. A successful plain HTTP request is answered by the target itself.
+pub(super) async fn write_http_reply<W>(w: &mut W, dialect: Dialect, reply: Reply) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let status = match reply {
+        Reply::Succeeded if dialect == Dialect::HttpForward => return Ok(()),
+        Reply::Succeeded => return w.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await,
+        Reply::ConnectionNotAllowed => StatusCode::FORBIDDEN,
+        Reply::TtlExpired => StatusCode::GATEWAY_TIMEOUT,
+        Reply::CommandNotSupported | Reply::AddressTypeNotSupported => StatusCode::BAD_REQUEST,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    let response = HttpResponseBuilder::new()
+        .status(status)
+        .header("Content-Length", "0")
+        .header("Connection", "close")
+        .build();
+    w.write_all(response.raw_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        Acceptor, ConnectionLimits, Server, SessionContext,
+        auth::{NoAuth, UserKeyAuth},
+    };
+    use std::{
+        sync::Arc,
+        task::{Context, Poll},
+    };
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Accepts TCP connections as if they had presented the TLS certificate of `bob`.
+    struct Bob(TcpListener);
+
+    impl Acceptor for Bob {
+        type Stream = TcpStream;
+
+        fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, SessionContext)>> {
+            let (stream, ctx) = std::task::ready!(Acceptor::poll_accept(&self.0, cx))?;
+            Poll::Ready(Ok((stream, ctx.with_tls_identity(Some("bob".into())))))
+        }
+    }
+
+    #[tokio::test]
+    async fn one_port_for_all_protocols() {
+        let auth = Arc::new(UserKeyAuth::new("alice", "secret"));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), auth).await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        assert!(matches!(conn.sniff().await.unwrap(), MixedConnection::Socks5(_)));
+
+        // SOCKS4 clients cannot authenticate
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"\x04\x01\x00\x50\x7f\x00\x00\x01\0").await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        assert!(conn.sniff().await.is_err());
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 91);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let credentials = BasicAuth::new("alice", "secret").header_value();
+        let request = format!("CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: {credentials}\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        let MixedConnection::Http(conn, target, request) = conn.sniff().await.unwrap() else {
+            panic!("not an HTTP request");
+        };
+        assert_eq!(target, Address::from(("example.com", 443)));
+        assert!(request.is_tunnel());
+        assert_eq!(conn.context().identity.as_deref(), Some("alice"));
+        conn.reply(Reply::Succeeded, Address::unspecified()).await.unwrap();
+        let mut reply = [0; 39];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"HTTP/1.1 200 Connection established\r\n\r\n");
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET http://[::1]:8080/a?b HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n")
+            .await
+            .unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        assert!(conn.sniff().await.is_err());
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("HTTP/1.1 407"));
+
+        // SOCKS4 clients known by their certificate are held to the caps of their user
+        let listener = Bob(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let addr = listener.0.local_addr().unwrap();
+        let server = Server::from_acceptor(listener, Arc::new(NoAuth)).with_limits(ConnectionLimits::new().with_max_sessions_per_user(1));
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(b" P   ").await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        let _first = conn.sniff().await.unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b" P   ").await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        assert!(conn.sniff().await.is_err());
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 91);
+    }
+
+    #[test]
+    fn forwarded_request() {
+        let raw = "GET http://192.0.2.1/a?b HTTP/1.1\r\nHost: 192.0.2.1\r\nProxy-Connection: keep-alive\r\n\r\n";
+        let request = HttpProxyRequest {
+            request: HttpRequest::parse(raw.as_bytes()).unwrap(),
+        };
+        assert_eq!(request.target(), Some(Address::from(("192.0.2.1".parse::<IpAddr>().unwrap(), 80))));
+        let prelude = request.upstream_prelude();
+        assert_eq!(&prelude[..], b"GET /a?b HTTP/1.1\r\nhost: 192.0.2.1\r\nConnection: close\r\n\r\n");
+    }
+}
@@ -1,31 +1,76 @@
 use self::{associate::UdpAssociate, bind::Bind, connect::Connect};
-use crate::protocol::{self, Address, AuthMethod, Command, StreamOperation, handshake};
-use crate::server::AuthAdaptor;
-use std::time::Duration;
+use crate::protocol::{self, Address, AuthMethod, Command, Reply, Response, StreamOperation, handshake};
+use crate::server::{
+    AuthAdaptor,
+    context::SessionContext,
+    session::{Dialect, Session},
+    transport::AsyncStream,
+};
+use std::time::{Duration, Instant};
 use stream::Stream;
-use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite},
+    net::TcpStream,
+};
 
 pub mod associate;
 pub mod bind;
 pub mod connect;
+pub mod mixed;
 
 /// An incoming connection. This may not be a valid socks5 connection. You need to call [`authenticate()`](#method.authenticate)
 /// to perform the socks5 handshake. It will be converted to a proper socks5 connection after the handshake succeeds.
-pub struct IncomingConnection<O> {
-    stream: TcpStream,
+///
+/// The connection runs over a [`TcpStream`] by default, or over the stream of any other [`Acceptor`](crate::server::Acceptor).
+pub struct IncomingConnection<O, T: AsyncStream = TcpStream> {
+    stream: Stream<T>,
     auth: AuthAdaptor<O>,
+    session: Session,
+    /// The first byte of the client, already read by [`sniff`](Self::sniff).
+    sniffed: Option<u8>,
 }
 
 impl<O> IncomingConnection<O> {
     #[inline]
     pub fn new(stream: TcpStream, auth: AuthAdaptor<O>) -> Self {
-        IncomingConnection { stream, auth }
+        let session = Session::new(SessionContext::from_stream(&stream));
+        Self::with_session(stream, auth, session)
+    }
+}
+
+impl<O, T: AsyncStream> IncomingConnection<O, T> {
+    /// Create a connection over `stream`, whose peer is described by `ctx`.
+    #[inline]
+    pub fn with_context(stream: T, auth: AuthAdaptor<O>, ctx: SessionContext) -> Self {
+        Self::with_session(stream, auth, Session::new(ctx))
+    }
+
+    /// Wrap an accepted stream whose session has already been set up by the server.
+    #[inline]
+    pub(crate) fn with_session(stream: T, auth: AuthAdaptor<O>, session: Session) -> Self {
+        IncomingConnection {
+            stream: Stream::new(stream),
+            auth,
+            session,
+            sniffed: None,
+        }
     }
+
+    /// Get the [`SessionContext`] of this connection.
+    #[inline]
+    pub fn context(&self) -> &SessionContext {
+        self.session.context()
+    }
+
     /// Set a timeout for the SOCKS5 handshake.
-    pub async fn authenticate_with_timeout(self, timeout: Duration) -> crate::Result<(Authenticated, O)> {
-        tokio::time::timeout(timeout, self.authenticate())
-            .await
-            .map_err(|_| crate::Error::String("handshake timeout".into()))?
+    pub async fn authenticate_with_timeout(self, timeout: Duration) -> crate::Result<(Authenticated<T>, O)> {
+        let metrics = self.session.metrics().cloned();
+        tokio::time::timeout(timeout, self.authenticate()).await.map_err(|_| {
+            if let Some(metrics) = metrics {
+                metrics.auth_failure("timeout");
+            }
+            crate::Error::String("handshake timeout".into())
+        })?
     }
 
     /// Perform a SOCKS5 authentication handshake using the given
@@ -33,18 +78,42 @@ impl<O> IncomingConnection<O> {
     ///
     /// If the handshake succeeds, an [`Authenticated`]
     /// alongs with the output of the [`AuthExecutor`](crate::server::auth::AuthExecutor) adapter is returned.
-    /// Otherwise, the error and the original [`TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) is returned.
+    /// Otherwise, the error is returned.
     ///
     /// Note that this method will not implicitly close the connection even if the handshake failed.
-    pub async fn authenticate(mut self) -> crate::Result<(Authenticated, O)> {
-        let request = handshake::Request::retrieve_from_async_stream(&mut self.stream).await?;
+    pub async fn authenticate(mut self) -> crate::Result<(Authenticated<T>, O)> {
+        let start = Instant::now();
+        let output = match self.handshake().await {
+            Ok(output) => output,
+            Err(err) => {
+                let reason = match &err {
+                    crate::Error::Io(err) if err.kind() == std::io::ErrorKind::Unsupported => "no_acceptable_method",
+                    _ => "protocol_error",
+                };
+                self.session.on_handshake(start.elapsed(), Some(reason));
+                self.session.record_error(&err);
+                return Err(err);
+            }
+        };
+        self.session.on_handshake(start.elapsed(), self.auth.failure_reason(&output));
+        self.session.set_user(self.auth.identity(&output));
+        Ok((Authenticated::new(self.stream, self.session), output))
+    }
+
+    async fn handshake(&mut self) -> crate::Result<O> {
+        // A client may pipeline its methods, credentials and request, and even its first payload bytes: read them
+        // at once, and keep what follows the request for the session
+        self.stream.set_read_ahead(true);
+        let sniffed = self.sniffed.take();
+        let mut reader = sniffed.as_slice().chain(&mut self.stream);
+        let request = handshake::Request::retrieve_from_async_stream(&mut reader).await?;
         if let Some(method) = self.evaluate_request(&request) {
             // Note: set_method is not called here because auth is behind Arc and requires &mut self
             // The default implementation does nothing anyway
             let response = handshake::Response::new(method);
             response.write_to_async_stream(&mut self.stream).await?;
-            let output = self.auth.execute(&mut self.stream).await;
-            Ok((Authenticated::new(Stream::new(self.stream)), output))
+            self.session.set_auth_method(method);
+            Ok(self.auth.execute_with_context(&mut self.stream, self.session.context()).await)
         } else {
             let response = handshake::Response::new(AuthMethod::NoAcceptableMethods);
             response.write_to_async_stream(&mut self.stream).await?;
@@ -63,31 +132,60 @@ impl<O> IncomingConnection<O> {
     }
 }
 
-impl<O> std::fmt::Debug for IncomingConnection<O> {
+impl<O, T: AsyncStream + std::fmt::Debug> std::fmt::Debug for IncomingConnection<O, T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("IncomingConnection").field("stream", &self.stream).finish()
     }
 }
 
-impl<O> From<IncomingConnection<O>> for TcpStream {
+/// Give the TCP stream back, unless bytes of the client have already been read from it, such as by
+/// [`sniff`](IncomingConnection::sniff): the connection is returned then, so that they are not lost.
+impl<O> TryFrom<IncomingConnection<O>> for TcpStream {
+    type Error = IncomingConnection<O>;
+
     #[inline]
-    fn from(conn: IncomingConnection<O>) -> Self {
-        conn.stream
+    fn try_from(conn: IncomingConnection<O>) -> Result<Self, Self::Error> {
+        if conn.sniffed.is_some() || !conn.stream.buffered().is_empty() {
+            return Err(conn);
+        }
+        Ok(conn.stream.into_inner())
+    }
+}
+
+/// The read half of a split connection, which yields the bytes the client pipelined after its request before
+/// reading from the TCP stream.
+pub type BufferedReadHalf<'a> = tokio::io::Chain<std::io::Cursor<Vec<u8>>, tokio::net::tcp::ReadHalf<'a>>;
+
+/// Write `reply` to the client, in the protocol it speaks.
+async fn write_reply<W>(w: &mut W, dialect: Dialect, reply: Reply, addr: Address) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    match dialect {
+        Dialect::Socks5 => Response::new(reply, addr).write_to_async_stream(w).await,
+        Dialect::Socks4 => protocol::socks4::Response::new(reply, addr).write_to_async_stream(w).await,
+        Dialect::HttpConnect | Dialect::HttpForward => mixed::write_http_reply(w, dialect, reply).await,
     }
 }
 
-/// A TCP stream that has been authenticated.
+/// A stream that has been authenticated.
 ///
 /// To get the command from the SOCKS5 client, use
 /// [`wait_request`](crate::server::connection::Authenticated::wait_request).
 ///
-/// It can also be converted back into a raw [`tokio::TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) with `From` trait.
-pub struct Authenticated(Stream);
+/// It can also be converted back into a [`Stream`] with `From` trait.
+pub struct Authenticated<T: AsyncStream = TcpStream>(Stream<T>, Session);
+
+impl<T: AsyncStream> Authenticated<T> {
+    #[inline]
+    fn new(stream: Stream<T>, session: Session) -> Self {
+        Self(stream, session)
+    }
 
-impl Authenticated {
+    /// Get the [`SessionContext`] of this connection, with the negotiated method and the authenticated identity.
     #[inline]
-    fn new(stream: Stream) -> Self {
-        Self(stream)
+    pub fn context(&self) -> &SessionContext {
+        self.1.context()
     }
 
     /// Waits the SOCKS5 client to send a request.
@@ -96,24 +194,53 @@ impl Authenticated {
     ///
     /// When encountering an error, the stream will be returned alongside the error.
     ///
+    /// Requests over the per user caps of [`ConnectionLimits`](crate::server::ConnectionLimits), or from users
+    /// over their [`Quota`](crate::server::Quota), are replied with [`Reply::GeneralFailure`] and returned as an error.
+    ///
     /// Note that this method will not implicitly close the connection even if the client sends an invalid request.
-    pub async fn wait_request(mut self) -> crate::Result<ClientConnection> {
-        let req = protocol::Request::retrieve_from_async_stream(&mut *self.0).await?;
+    pub async fn wait_request(mut self) -> crate::Result<ClientConnection<T>> {
+        let req = match protocol::Request::retrieve_from_async_stream(&mut self.0).await {
+            Ok(req) => {
+                // The bytes the client pipelined after its request stay buffered, for the session to relay
+                self.0.set_read_ahead(false);
+                req
+            }
+            Err(err) => {
+                self.1.record_error(&err);
+                return Err(err.into());
+            }
+        };
+        self.1.on_request(req.command, &req.address);
+
+        if let Err(err) = self.1.admit_request(req.command) {
+            self.1.record_error(&err);
+            self.1.on_reply(Reply::GeneralFailure);
+            let resp = Response::new(Reply::GeneralFailure, Address::unspecified());
+            resp.write_to_async_stream(&mut *self.0).await?;
+            return Err(err.into());
+        }
 
+        let Self(stream, session) = self;
         match req.command {
             Command::UdpAssociate => Ok(ClientConnection::UdpAssociate(
-                UdpAssociate::<associate::NeedReply>::new(self.0),
+                UdpAssociate::<associate::NeedReply, T>::new(stream, session),
+                req.address,
+            )),
+            Command::Bind => Ok(ClientConnection::Bind(
+                Bind::<bind::NeedFirstReply, T>::new(stream, session),
+                req.address,
+            )),
+            Command::Connect => Ok(ClientConnection::Connect(
+                Connect::<connect::NeedReply, T>::new(stream, session),
                 req.address,
             )),
-            Command::Bind => Ok(ClientConnection::Bind(Bind::<bind::NeedFirstReply>::new(self.0), req.address)),
-            Command::Connect => Ok(ClientConnection::Connect(Connect::<connect::NeedReply>::new(self.0), req.address)),
         }
     }
 }
 
-impl From<Authenticated> for Stream {
+impl<T: AsyncStream> From<Authenticated<T>> for Stream<T> {
     #[inline]
-    fn from(conn: Authenticated) -> Self {
+    fn from(conn: Authenticated<T>) -> Self {
         conn.0
     }
 }
@@ -124,8 +251,126 @@ impl From<Authenticated> for Stream {
 /// - Bind
 /// - Connect
 #[derive(Debug)]
-pub enum ClientConnection {
-    UdpAssociate(UdpAssociate<associate::NeedReply>, Address),
-    Bind(Bind<bind::NeedFirstReply>, Address),
-    Connect(Connect<connect::NeedReply>, Address),
+pub enum ClientConnection<T: AsyncStream = TcpStream> {
+    UdpAssociate(UdpAssociate<associate::NeedReply, T>, Address),
+    Bind(Bind<bind::NeedFirstReply, T>, Address),
+    Connect(Connect<connect::NeedReply, T>, Address),
+}
+
+impl<T: AsyncStream> ClientConnection<T> {
+    /// Get the [`SessionContext`] of this connection.
+    pub fn context(&self) -> &SessionContext {
+        match self {
+            ClientConnection::UdpAssociate(conn, _) => conn.context(),
+            ClientConnection::Bind(conn, _) => conn.context(),
+            ClientConnection::Connect(conn, _) => conn.context(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use crate::{
+        protocol::{Address, Reply, UserKey},
+        server::{ClientConnection, MixedConnection, Server, auth::UserKeyAuth},
+    };
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn pipelined_payload_reaches_the_target() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(UserKeyAuth::new("alice", "secret")))
+            .await
+            .unwrap();
+        let proxy = server.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            let auth = Some(UserKey::new("alice", "secret"));
+            crate::client::connect_pipelined(&mut stream, target_addr, auth, b"hello")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            response
+        });
+
+        let (conn, _) = server.accept().await.unwrap();
+        let (conn, _) = conn.authenticate().await.unwrap();
+        let ClientConnection::Connect(conn, addr) = conn.wait_request().await.unwrap() else {
+            panic!("not a CONNECT request");
+        };
+        assert_eq!(addr, Address::from(target_addr));
+        let mut outbound = TcpStream::connect(target_addr).await.unwrap();
+        let (mut accepted, _) = target.accept().await.unwrap();
+        let mut conn = conn.reply(Reply::Succeeded, Address::from(target_addr)).await.unwrap();
+        let relayed = tokio::spawn(async move { conn.relay(&mut outbound).await });
+
+        // The payload sent along the request is relayed ahead of anything else
+        let mut hello = [0; 5];
+        accepted.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        accepted.write_all(b"world").await.unwrap();
+        drop(accepted);
+
+        assert_eq!(client.await.unwrap(), b"world");
+        let transfer = relayed.await.unwrap().unwrap();
+        assert_eq!((transfer.bytes_up, transfer.bytes_down), (5, 5));
+    }
+
+    #[tokio::test]
+    async fn pipelined_payload_is_read_from_the_split_halves() {
+        let target_addr: std::net::SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(UserKeyAuth::new("alice", "secret")))
+            .await
+            .unwrap();
+        let proxy = server.local_addr().unwrap();
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let auth = Some(UserKey::new("alice", "secret"));
+        let client = tokio::spawn(async move {
+            crate::client::connect_pipelined(&mut stream, target_addr, auth, b"hello")
+                .await
+                .unwrap();
+            stream.write_all(b" world").await.unwrap();
+            stream
+        });
+
+        let (conn, _) = server.accept().await.unwrap();
+        let (conn, _) = conn.authenticate().await.unwrap();
+        let ClientConnection::Connect(conn, _) = conn.wait_request().await.unwrap() else {
+            panic!("not a CONNECT request");
+        };
+        let mut conn = conn.reply(Reply::Succeeded, Address::from(target_addr)).await.unwrap();
+        let _client = client.await.unwrap();
+        let (mut read, _) = conn.split();
+        let mut received = [0; 11];
+        read.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn sniffed_connection_is_not_converted_back() {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(UserKeyAuth::new("alice", "secret")))
+            .await
+            .unwrap();
+        let proxy = server.local_addr().unwrap();
+
+        let _client = TcpStream::connect(proxy).await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        assert!(TcpStream::try_from(conn).is_ok());
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        let MixedConnection::Socks5(conn) = conn.sniff().await.unwrap() else {
+            panic!("not a SOCKS5 client");
+        };
+        assert!(TcpStream::try_from(conn).is_err());
+    }
 }
@@ -0,0 +1,148 @@
+use crate::protocol::AuthMethod;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+use tokio::net::TcpStream;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What is known about a session, carried from the [`IncomingConnection`](crate::server::IncomingConnection)
+/// through [`Authenticated`](crate::server::connection::Authenticated) to [`Connect`](crate::server::Connect),
+/// [`Bind`](crate::server::Bind) and [`UdpAssociate`](crate::server::UdpAssociate).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionContext {
+    /// An identifier, unique within the process.
+    pub id: u64,
+    /// The address of the TCP peer, which is a load balancer when the connection came through the PROXY protocol.
+    ///
+    /// It is the unspecified address `0.0.0.0:0` for transports without IP addresses, such as Unix sockets.
+    pub peer_addr: SocketAddr,
+    /// The local address the connection was accepted on.
+    pub local_addr: SocketAddr,
+    /// The authentication method negotiated in the handshake, once it is done.
+    pub auth_method: Option<AuthMethod>,
+    /// The name of the authenticated user, see [`AuthExecutor::identity`](crate::server::AuthExecutor::identity),
+    /// or else the [`tls_identity`](Self::tls_identity) of the client.
+    pub identity: Option<String>,
+    /// When the connection was accepted.
+    pub accepted_at: SystemTime,
+    /// The source address announced by a PROXY protocol header, if any.
+    pub proxy_source: Option<SocketAddr>,
+    /// The credentials of the peer process, for connections accepted on a Unix socket.
+    pub peer_cred: Option<PeerCred>,
+    /// The name of the certificate of the client, for connections accepted over TLS with client authentication.
+    pub tls_identity: Option<String>,
+}
+
+/// The credentials of the process on the other end of a Unix socket, as reported by `SO_PEERCRED`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PeerCred {
+    /// The effective user id of the peer.
+    pub uid: u32,
+    /// The effective group id of the peer.
+    pub gid: u32,
+    /// The process id of the peer, on the platforms reporting it.
+    pub pid: Option<i32>,
+}
+
+#[cfg(unix)]
+impl From<tokio::net::unix::UCred> for PeerCred {
+    fn from(cred: tokio::net::unix::UCred) -> Self {
+        Self {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        }
+    }
+}
+
+impl SessionContext {
+    /// Create the context of a connection accepted now, with a new id.
+    pub fn new(peer_addr: SocketAddr, local_addr: SocketAddr) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            local_addr,
+            auth_method: None,
+            identity: None,
+            accepted_at: SystemTime::now(),
+            proxy_source: None,
+            peer_cred: None,
+            tls_identity: None,
+        }
+    }
+
+    /// Set the credentials of the peer process.
+    pub fn with_peer_cred(mut self, peer_cred: Option<PeerCred>) -> Self {
+        self.peer_cred = peer_cred;
+        self
+    }
+
+    /// Set the name of the certificate of the client, which is also its identity until it authenticates.
+    pub fn with_tls_identity(mut self, tls_identity: Option<String>) -> Self {
+        self.identity = tls_identity.clone();
+        self.tls_identity = tls_identity;
+        self
+    }
+
+    /// The context of another session over the same connection, such as a multiplexed stream, with a new id.
+    #[cfg(feature = "mux")]
+    pub(crate) fn fork(&self) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            accepted_at: SystemTime::now(),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn from_stream(stream: &TcpStream) -> Self {
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let peer_addr = stream.peer_addr().unwrap_or(unspecified);
+        let local_addr = stream.local_addr().unwrap_or(unspecified);
+        Self::new(peer_addr, local_addr)
+    }
+
+    /// The address of the SOCKS5 client: the PROXY protocol source if any, the TCP peer otherwise.
+    #[inline]
+    pub fn client_addr(&self) -> SocketAddr {
+        self.proxy_source.unwrap_or(self.peer_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{ClientConnection, Server, auth::UserKeyAuth};
+    use std::sync::Arc;
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+    #[tokio::test]
+    async fn context_is_carried_to_the_request() {
+        let auth = Arc::new(UserKeyAuth::new("alice", "secret"));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), auth).await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80]).await.unwrap();
+
+        let (conn, peer_addr) = server.accept().await.unwrap();
+        let id = conn.context().id;
+        assert_eq!(conn.context().peer_addr, peer_addr);
+        assert_eq!(conn.context().local_addr, addr);
+        assert_eq!(conn.context().auth_method, None);
+
+        let (authenticated, _) = conn.authenticate().await.unwrap();
+        assert_eq!(authenticated.context().auth_method, Some(AuthMethod::UserPass));
+        assert_eq!(authenticated.context().identity.as_deref(), Some("alice"));
+
+        let conn = authenticated.wait_request().await.unwrap();
+        assert!(matches!(conn, ClientConnection::Connect(..)));
+        let ctx = conn.context();
+        assert_eq!((ctx.id, ctx.client_addr()), (id, peer_addr));
+        assert_eq!(ctx.identity.as_deref(), Some("alice"));
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Caps on concurrent sessions, enforced by a [`Server`](crate::server::Server) configured with
+/// [`with_limits`](crate::server::Server::with_limits).
+///
+/// - The total and per source IP caps are checked when a connection is accepted; connections over the limit are
+///   closed before the handshake.
+/// - The per user caps are checked in [`wait_request`](crate::server::connection::Authenticated::wait_request);
+///   requests over the limit are answered with [`Reply::GeneralFailure`](crate::protocol::Reply::GeneralFailure).
+///   The user is the one reported by [`AuthExecutor::identity`](crate::server::AuthExecutor::identity).
+///
+/// A session holds its slots until its connection is dropped, including when it is converted into a
+/// [`Stream`](stream::Stream).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ConnectionLimits {
+    pub max_sessions: Option<usize>,
+    pub max_sessions_per_ip: Option<usize>,
+    pub max_sessions_per_user: Option<usize>,
+    pub max_udp_associations_per_user: Option<usize>,
+}
+
+impl ConnectionLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum number of concurrent sessions of the server.
+    pub fn with_max_sessions(mut self, max: usize) -> Self {
+        self.max_sessions = Some(max);
+        self
+    }
+
+    /// Set the maximum number of concurrent sessions from one source IP address.
+    pub fn with_max_sessions_per_ip(mut self, max: usize) -> Self {
+        self.max_sessions_per_ip = Some(max);
+        self
+    }
+
+    /// Set the maximum number of concurrent sessions of one authenticated user.
+    pub fn with_max_sessions_per_user(mut self, max: usize) -> Self {
+        self.max_sessions_per_user = Some(max);
+        self
+    }
+
+    /// Set the maximum number of concurrent UDP associations of one authenticated user.
+    pub fn with_max_udp_associations_per_user(mut self, max: usize) -> Self {
+        self.max_udp_associations_per_user = Some(max);
+        self
+    }
+}
+
+/// Keeps track of the sessions counted against [`ConnectionLimits`].
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionLimiter {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    limits: ConnectionLimits,
+    counts: Mutex<HashMap<Slot, usize>>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Slot {
+    Total,
+    Ip(IpAddr),
+    User(String),
+    UdpAssociation(String),
+}
+
+/// A slot taken from a [`ConnectionLimiter`], given back when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    shared: Arc<Shared>,
+    slot: Slot,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        let shared = Shared {
+            limits,
+            counts: Mutex::new(HashMap::new()),
+        };
+        Self { shared: Arc::new(shared) }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.shared.limits
+    }
+
+    /// The number of sessions currently counted, in total.
+    pub fn sessions(&self) -> usize {
+        self.count(&Slot::Total)
+    }
+
+    /// The number of sessions currently counted for `ip`.
+    pub fn sessions_of_ip(&self, ip: IpAddr) -> usize {
+        self.count(&Slot::Ip(ip))
+    }
+
+    /// The number of sessions currently counted for `user`.
+    pub fn sessions_of_user(&self, user: &str) -> usize {
+        self.count(&Slot::User(user.to_owned()))
+    }
+
+    /// Take a session slot for a connection from `ip`, checking the total and per IP caps.
+    pub fn acquire_session(&self, ip: IpAddr) -> std::io::Result<Vec<Permit>> {
+        let limits = &self.shared.limits;
+        let mut counts = self.shared.counts.lock().unwrap();
+        let total = self.take(&mut counts, Slot::Total, limits.max_sessions, "too many sessions")?;
+        let per_ip = self.take(
+            &mut counts,
+            Slot::Ip(ip),
+            limits.max_sessions_per_ip,
+            "too many sessions from this address",
+        );
+        drop(counts);
+        Ok(vec![total, per_ip?])
+    }
+
+    /// Take a session slot for `user`.
+    pub fn acquire_user(&self, user: &str) -> std::io::Result<Permit> {
+        let max = self.shared.limits.max_sessions_per_user;
+        let mut counts = self.shared.counts.lock().unwrap();
+        self.take(&mut counts, Slot::User(user.to_owned()), max, "too many sessions for this user")
+    }
+
+    /// Take a UDP association slot for `user`.
+    pub fn acquire_udp_association(&self, user: &str) -> std::io::Result<Permit> {
+        let max = self.shared.limits.max_udp_associations_per_user;
+        let mut counts = self.shared.counts.lock().unwrap();
+        self.take(
+            &mut counts,
+            Slot::UdpAssociation(user.to_owned()),
+            max,
+            "too many UDP associations for this user",
+        )
+    }
+
+    fn count(&self, slot: &Slot) -> usize {
+        self.shared.counts.lock().unwrap().get(slot).copied().unwrap_or_default()
+    }
+
+    fn take(&self, counts: &mut HashMap<Slot, usize>, slot: Slot, max: Option<usize>, err: &str) -> std::io::Result<Permit> {
+        let count = counts.entry(slot.clone()).or_default();
+        if max.is_some_and(|max| *count >= max) {
+            if *count == 0 {
+                counts.remove(&slot);
+            }
+            return Err(std::io::Error::new(std::io::ErrorKind::QuotaExceeded, err));
+        }
+        *count += 1;
+        let shared = self.shared.clone();
+        Ok(Permit { shared, slot })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.shared.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.slot) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.slot);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_are_given_back() {
+        let limits = ConnectionLimits::new().with_max_sessions(2).with_max_sessions_per_ip(1);
+        let limiter = ConnectionLimiter::new(limits);
+        let a = "192.0.2.1".parse().unwrap();
+        let b = "192.0.2.2".parse().unwrap();
+
+        let first = limiter.acquire_session(a).unwrap();
+        let err = limiter.acquire_session(a).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::QuotaExceeded);
+        let second = limiter.acquire_session(b).unwrap();
+        assert!(limiter.acquire_session("192.0.2.3".parse().unwrap()).is_err());
+        assert_eq!(limiter.sessions(), 2);
+
+        drop(first);
+        assert_eq!(limiter.sessions_of_ip(a), 0);
+        assert!(limiter.acquire_session(a).is_ok());
+        drop(second);
+        assert_eq!(limiter.sessions(), 0);
+    }
+
+    #[tokio::test]
+    async fn server_refuses_over_limit() {
+        use crate::server::{Server, auth::NoAuth};
+        use std::time::Duration;
+        use tokio::{io::AsyncReadExt, net::TcpStream};
+
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(NoAuth)).await.unwrap();
+        let server = server.with_limits(ConnectionLimits::new().with_max_sessions_per_ip(1));
+        let addr = server.local_addr().unwrap();
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let (first, _) = server.accept().await.unwrap();
+        assert_eq!(server.limiter().unwrap().sessions(), 1);
+
+        // The second connection is closed without a handshake while the first one is alive
+        assert!(tokio::time::timeout(Duration::from_millis(100), server.accept()).await.is_err());
+        assert_eq!(refused.read(&mut [0]).await.unwrap(), 0);
+
+        drop(first);
+        let _third = TcpStream::connect(addr).await.unwrap();
+        let (_third, _) = server.accept().await.unwrap();
+        assert_eq!(server.limiter().unwrap().sessions(), 1);
+    }
+}
@@ -0,0 +1,401 @@
+//! Server metrics, exported in the Prometheus text format.
+//!
+//! A [`Metrics`] given to [`Server::with_metrics`](crate::server::Server::with_metrics) counts handshakes,
+//! authentication failures, requests, replies and active sessions by itself. Relayed bytes are counted by
+//! wrapping the relayed streams into [`Metered`], and dropped UDP packets by setting up the associated sockets
+//! with [`AssociatedUdpSocket::with_metrics`](crate::server::AssociatedUdpSocket::with_metrics).
+//!
+//! ```no_run
+//! use socks5_impl::server::{Metrics, Server, auth::NoAuth};
+//! use std::sync::Arc;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let metrics = Metrics::new();
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:9090").await?;
+//! tokio::spawn(metrics.clone().serve(listener));
+//!
+//! let server = Server::bind("127.0.0.1:1080".parse().unwrap(), Arc::new(NoAuth)).await?.with_metrics(metrics);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::protocol::{Command, Reply};
+use http::{Method, StatusCode};
+use http_impl::{HttpRequest, HttpResponseBuilder};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+};
+
+/// How long a client of [`Metrics::serve`] has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The pause of [`Metrics::serve`] after failing to accept a connection, e.g. when out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The upper bounds of the handshake duration histogram buckets, in seconds.
+const HANDSHAKE_BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+const COMMANDS: [Command; 3] = [Command::Connect, Command::Bind, Command::UdpAssociate];
+
+const REPLIES: [Reply; 9] = [
+    Reply::Succeeded,
+    Reply::GeneralFailure,
+    Reply::ConnectionNotAllowed,
+    Reply::NetworkUnreachable,
+    Reply::HostUnreachable,
+    Reply::ConnectionRefused,
+    Reply::TtlExpired,
+    Reply::CommandNotSupported,
+    Reply::AddressTypeNotSupported,
+];
+
+/// The counters of a server.
+///
+/// Cloning gives another handle to the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    shared: Arc<Registry>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    handshake_buckets: [AtomicU64; HANDSHAKE_BUCKETS.len()],
+    handshake_count: AtomicU64,
+    handshake_micros: AtomicU64,
+    auth_failures: Mutex<BTreeMap<String, u64>>,
+    requests: [AtomicU64; COMMANDS.len()],
+    replies: [AtomicU64; REPLIES.len()],
+    active_sessions: AtomicI64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    udp_dropped: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a completed handshake.
+    pub fn observe_handshake(&self, duration: Duration) {
+        let registry = &self.shared;
+        let secs = duration.as_secs_f64();
+        if let Some(bucket) = HANDSHAKE_BUCKETS.iter().position(|le| secs <= *le) {
+            registry.handshake_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        registry.handshake_count.fetch_add(1, Ordering::Relaxed);
+        registry.handshake_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Record a failed authentication.
+    pub fn auth_failure(&self, reason: &str) {
+        *self.shared.auth_failures.lock().unwrap().entry(reason.to_owned()).or_default() += 1;
+    }
+
+    /// Record a request of the client.
+    pub fn request(&self, command: Command) {
+        let index = COMMANDS.iter().position(|c| *c == command).unwrap_or_default();
+        self.shared.requests[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a reply sent to the client.
+    pub fn reply(&self, reply: Reply) {
+        self.shared.replies[u8::from(reply) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an active session until the returned guard is dropped.
+    pub fn session(&self) -> ActiveSession {
+        self.shared.active_sessions.fetch_add(1, Ordering::Relaxed);
+        ActiveSession(self.clone())
+    }
+
+    /// The number of active sessions.
+    pub fn active_sessions(&self) -> i64 {
+        self.shared.active_sessions.load(Ordering::Relaxed)
+    }
+
+    /// Count `bytes` sent by clients.
+    pub fn add_bytes_up(&self, bytes: usize) {
+        self.shared.bytes_up.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` received by clients.
+    pub fn add_bytes_down(&self, bytes: usize) {
+        self.shared.bytes_down.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a UDP packet dropped because its SOCKS5 UDP header could not be parsed.
+    pub fn udp_packet_dropped(&self) {
+        self.shared.udp_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let registry = &self.shared;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "socks5_handshake_duration_seconds",
+            "histogram",
+            "Duration of the SOCKS5 handshakes.",
+        );
+        let mut cumulative = 0;
+        for (le, count) in HANDSHAKE_BUCKETS.iter().zip(&registry.handshake_buckets) {
+            cumulative += load(count);
+            let _ = writeln!(out, "socks5_handshake_duration_seconds_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let count = load(&registry.handshake_count);
+        let sum = load(&registry.handshake_micros) as f64 / 1e6;
+        let _ = writeln!(out, "socks5_handshake_duration_seconds_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "socks5_handshake_duration_seconds_sum {sum}");
+        let _ = writeln!(out, "socks5_handshake_duration_seconds_count {count}");
+
+        header(
+            &mut out,
+            "socks5_auth_failures_total",
+            "counter",
+            "Failed SOCKS5 authentications by reason.",
+        );
+        for (reason, count) in registry.auth_failures.lock().unwrap().iter() {
+            let _ = writeln!(out, "socks5_auth_failures_total{{reason=\"{}\"}} {count}", escape(reason));
+        }
+
+        header(&mut out, "socks5_requests_total", "counter", "SOCKS5 requests by command.");
+        for (command, count) in COMMANDS.iter().zip(&registry.requests) {
+            let _ = writeln!(
+                out,
+                "socks5_requests_total{{command=\"{}\"}} {}",
+                command_label(*command),
+                load(count)
+            );
+        }
+
+        header(&mut out, "socks5_replies_total", "counter", "SOCKS5 replies by reply code.");
+        for (reply, count) in REPLIES.iter().zip(&registry.replies) {
+            let _ = writeln!(out, "socks5_replies_total{{reply=\"{}\"}} {}", reply_label(*reply), load(count));
+        }
+
+        header(&mut out, "socks5_active_sessions", "gauge", "Sessions currently open.");
+        let _ = writeln!(out, "socks5_active_sessions {}", registry.active_sessions.load(Ordering::Relaxed));
+
+        header(
+            &mut out,
+            "socks5_relayed_bytes_total",
+            "counter",
+            "Bytes relayed, by direction seen from the client.",
+        );
+        let _ = writeln!(out, "socks5_relayed_bytes_total{{direction=\"up\"}} {}", load(&registry.bytes_up));
+        let _ = writeln!(
+            out,
+            "socks5_relayed_bytes_total{{direction=\"down\"}} {}",
+            load(&registry.bytes_down)
+        );
+
+        header(
+            &mut out,
+            "socks5_udp_dropped_packets_total",
+            "counter",
+            "UDP packets dropped for an invalid SOCKS5 UDP header.",
+        );
+        let _ = writeln!(out, "socks5_udp_dropped_packets_total {}", load(&registry.udp_dropped));
+        out
+    }
+
+    /// Serve the metrics over HTTP on `listener`, at `/metrics`.
+    ///
+    /// Failures to accept a connection are logged and retried after a short pause, so this only returns on
+    /// cancellation. Clients get 10 seconds to send their request.
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    log::warn!("failed to accept a metrics connection: {err}");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                let Ok(Ok(request)) = tokio::time::timeout(REQUEST_TIMEOUT, HttpRequest::from_stream(&mut stream)).await else {
+                    return;
+                };
+                let response = match (request.method(), request.uri().path()) {
+                    (&Method::GET, "/metrics") => {
+                        let body = metrics.render();
+                        HttpResponseBuilder::new()
+                            .header("Content-Type", "text/plain; version=0.0.4")
+                            .header("Content-Length", &body.len().to_string())
+                            .body(body)
+                    }
+                    _ => HttpResponseBuilder::new()
+                        .status(StatusCode::NOT_FOUND)
+                        .header("Content-Length", "0"),
+                };
+                let _ = response.header("Connection", "close").build().write_to_stream(&mut stream).await;
+            });
+        }
+    }
+}
+
+/// Keeps a session counted as active until dropped.
+#[derive(Debug)]
+pub struct ActiveSession(Metrics);
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        self.0.shared.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts the traffic of the client stream `S` as relayed bytes, up for its reads and down for its writes, see
+/// [`Accounted`](crate::server::accounting::Accounted).
+#[derive(Debug)]
+pub struct Metered<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, metrics: Metrics) -> Self {
+        Self { inner, metrics }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.metrics.add_bytes_up(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.metrics.add_bytes_down(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub(crate) fn command_label(command: Command) -> &'static str {
+    match command {
+        Command::Connect => "connect",
+        Command::Bind => "bind",
+        Command::UdpAssociate => "udp_associate",
+    }
+}
+
+pub(crate) fn reply_label(reply: Reply) -> &'static str {
+    match reply {
+        Reply::Succeeded => "succeeded",
+        Reply::GeneralFailure => "general_failure",
+        Reply::ConnectionNotAllowed => "connection_not_allowed",
+        Reply::NetworkUnreachable => "network_unreachable",
+        Reply::HostUnreachable => "host_unreachable",
+        Reply::ConnectionRefused => "connection_refused",
+        Reply::TtlExpired => "ttl_expired",
+        Reply::CommandNotSupported => "command_not_supported",
+        Reply::AddressTypeNotSupported => "address_type_not_supported",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn render_and_serve() {
+        let metrics = Metrics::new();
+        metrics.observe_handshake(Duration::from_millis(3));
+        metrics.auth_failure("invalid_credentials");
+        metrics.request(Command::UdpAssociate);
+        metrics.reply(Reply::HostUnreachable);
+        let session = metrics.session();
+        metrics.udp_packet_dropped();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(metrics.clone().serve(listener));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in [
+            "socks5_handshake_duration_seconds_bucket{le=\"0.001\"} 0",
+            "socks5_handshake_duration_seconds_bucket{le=\"0.005\"} 1",
+            "socks5_handshake_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "socks5_auth_failures_total{reason=\"invalid_credentials\"} 1",
+            "socks5_requests_total{command=\"udp_associate\"} 1",
+            "socks5_replies_total{reply=\"host_unreachable\"} 1",
+            "socks5_active_sessions 1",
+            "socks5_udp_dropped_packets_total 1",
+        ] {
+            assert!(response.lines().any(|l| l == line), "missing {line} in {response}");
+        }
+
+        drop(session);
+        assert_eq!(metrics.active_sessions(), 0);
+    }
+
+    #[tokio::test]
+    async fn metered_stream() {
+        let metrics = Metrics::new();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Metered::new(server, metrics.clone());
+        client.write_all(b"ping").await.unwrap();
+        server.read_exact(&mut [0; 4]).await.unwrap();
+        server.write_all(b"pong!").await.unwrap();
+        assert!(metrics.render().contains("socks5_relayed_bytes_total{direction=\"up\"} 4\n"));
+        assert!(metrics.render().contains("socks5_relayed_bytes_total{direction=\"down\"} 5\n"));
+    }
+}
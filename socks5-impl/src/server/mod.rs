@@ -1,38 +1,108 @@
+use crate::server::session::Session;
 use std::{
     net::SocketAddr,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
 };
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, task::JoinSet};
 
+pub mod accounting;
+pub mod audit;
 pub mod auth;
 pub mod connection;
+pub mod context;
+pub mod limits;
+pub mod metrics;
+#[cfg(feature = "mux")]
+pub mod mux;
+pub mod observer;
+pub mod outbound;
+pub mod proxy_protocol;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod relay;
+#[cfg(feature = "mux")]
+pub mod reverse;
+mod session;
+pub mod shutdown;
+pub mod throttle;
+pub mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 pub use crate::{
+    server::accounting::{Accounted, Accounting, Quota, Usage, UserAccount, UserUsage},
+    server::audit::{AuditConfig, AuditLog},
     server::auth::{AuthAdaptor, AuthExecutor},
     server::connection::{
         ClientConnection, IncomingConnection,
         associate::{AssociatedUdpSocket, UdpAssociate},
         bind::Bind,
         connect::Connect,
+        mixed::{HttpProxyRequest, MixedConnection},
     },
+    server::context::{PeerCred, SessionContext},
+    server::limits::{ConnectionLimiter, ConnectionLimits},
+    server::metrics::{Metered, Metrics},
+    server::observer::{ObserverAdaptor, SessionObserver, SessionSummary},
+    server::outbound::{DirectConnector, HttpConnector, OutboundConnector, OutboundContext},
+    server::proxy_protocol::{Cidr, ProxyProtocol},
+    server::relay::{RelayStats, RelaySummary, Transfer},
+    server::shutdown::{DrainReport, ShutdownHandle, ShutdownSignal},
+    server::timeout::{SessionTimeouts, TimeLimited, TimeoutReason},
+    server::transport::{Acceptor, AsyncStream},
 };
 
+#[cfg(feature = "mux")]
+pub use crate::server::mux::MuxListener;
+#[cfg(all(feature = "client", feature = "mux"))]
+pub use crate::server::outbound::MuxConnector;
+#[cfg(feature = "client")]
+pub use crate::server::outbound::Socks5Connector;
+#[cfg(feature = "quic")]
+pub use crate::server::quic::QuicListener;
+#[cfg(feature = "mux")]
+pub use crate::server::reverse::{ReverseListener, ReverseRelay};
+#[cfg(feature = "tls")]
+pub use crate::server::tls::{TlsConfig, TlsListener};
+#[cfg(feature = "websocket")]
+pub use crate::server::websocket::WebSocketListener;
+
+/// An accepted connection, alongside the address of its client.
+type Accepted<O, A> = (IncomingConnection<O, <A as Acceptor>::Stream>, SocketAddr);
+
+/// A connection of a trusted load balancer, once its PROXY protocol header is read.
+type ProxyHeaderRead<A> = std::io::Result<(<A as Acceptor>::Stream, SessionContext)>;
+
 /// The socks5 server itself.
 ///
 /// The server can be constructed on a given socket address, or be created on an existing TcpListener.
+/// It can also run over any other transport, accepted from an [`Acceptor`], see [`from_acceptor`](Self::from_acceptor).
 ///
 /// The authentication method can be configured with the
 /// [`AuthExecutor`] trait.
-pub struct Server<O> {
-    listener: TcpListener,
+pub struct Server<O, A: Acceptor = TcpListener> {
+    listener: A,
     auth: AuthAdaptor<O>,
+    shutdown: ShutdownHandle,
+    limiter: Option<ConnectionLimiter>,
+    accounting: Option<Accounting>,
+    metrics: Option<Metrics>,
+    proxy_protocol: Option<ProxyProtocol>,
+    /// The connections of trusted load balancers whose PROXY protocol header is being read.
+    proxy_headers: Mutex<JoinSet<ProxyHeaderRead<A>>>,
+    observer: Option<ObserverAdaptor>,
+    audit: Option<AuditLog>,
 }
 
 impl<O: 'static> Server<O> {
     /// Create a new socks5 server with the given TCP listener and authentication method.
     #[inline]
     pub fn new(listener: TcpListener, auth: AuthAdaptor<O>) -> Self {
-        Self { listener, auth }
+        Self::from((listener, auth))
     }
 
     /// Create a new socks5 server on the given socket address and authentication method.
@@ -53,49 +123,235 @@ impl<O: 'static> Server<O> {
         Ok(Self::new(listener, auth))
     }
 
+    /// Get the the local socket address binded to this server
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(unix)]
+impl<O: 'static> Server<O, tokio::net::UnixListener> {
+    /// Create a new socks5 server on the Unix socket at `path`, with the given authentication method.
+    ///
+    /// The socket file must not exist yet. The sessions have no peer address, so the per source IP caps of
+    /// [`ConnectionLimits`] count all of them as one source; their clients are identified by the
+    /// [`peer_cred`](SessionContext::peer_cred) of their context instead.
+    pub fn bind_unix(path: impl AsRef<std::path::Path>, auth: AuthAdaptor<O>) -> std::io::Result<Self> {
+        Ok(Self::from_acceptor(tokio::net::UnixListener::bind(path)?, auth))
+    }
+
+    /// Create a new socks5 server on the Unix socket named `name` in the Linux abstract namespace.
+    ///
+    /// Abstract sockets have no file, so access to them is only controlled by the network namespace.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_unix_abstract(name: impl AsRef<[u8]>, auth: AuthAdaptor<O>) -> std::io::Result<Self> {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self::from_acceptor(tokio::net::UnixListener::from_std(listener)?, auth))
+    }
+}
+
+impl<O: 'static, A: Acceptor> Server<O, A> {
+    /// Create a new socks5 server accepting its connections from `acceptor`, with the given authentication method.
+    #[inline]
+    pub fn from_acceptor(acceptor: A, auth: AuthAdaptor<O>) -> Self {
+        Self {
+            listener: acceptor,
+            auth,
+            shutdown: ShutdownHandle::new(),
+            limiter: None,
+            accounting: None,
+            metrics: None,
+            proxy_protocol: None,
+            proxy_headers: Mutex::default(),
+            observer: None,
+            audit: None,
+        }
+    }
+
+    /// Enforce the given [`ConnectionLimits`] on the sessions of this server.
+    ///
+    /// Connections over the total or per source IP caps are closed by [`accept`](Self::accept) before the handshake.
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limiter = Some(ConnectionLimiter::new(limits));
+        self
+    }
+
+    /// Account the traffic of authenticated users, and reject the requests of users over their quota.
+    ///
+    /// The traffic itself is counted by wrapping the relayed streams, see [`Accounting`].
+    pub fn with_accounting(mut self, accounting: Accounting) -> Self {
+        self.accounting = Some(accounting);
+        self
+    }
+
+    /// Count handshakes, authentication failures, requests, replies and active sessions in `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Notify `observer` of the lifecycle of every session, from its acceptance to its end.
+    pub fn with_observer(mut self, observer: ObserverAdaptor) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Write a record of every session to `audit` when it closes, see [`audit`](crate::server::audit).
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Parse the PROXY protocol v2 header sent by the trusted load balancers of `proxy_protocol` before the handshake.
+    ///
+    /// The headers are read in the background, within the [`timeout`](ProxyProtocol::with_timeout) of `proxy_protocol`,
+    /// before the connections are counted by the limits and returned by [`accept`](Self::accept), with the address
+    /// of their client. See [`proxy_protocol`](crate::server::proxy_protocol).
+    pub fn with_proxy_protocol(mut self, proxy_protocol: ProxyProtocol) -> Self {
+        self.proxy_protocol = Some(proxy_protocol);
+        self
+    }
+
+    /// Get the [`ConnectionLimiter`] counting the sessions of this server, if limits are configured.
+    #[inline]
+    pub fn limiter(&self) -> Option<&ConnectionLimiter> {
+        self.limiter.as_ref()
+    }
+
     /// Accept an [`IncomingConnection`].
     /// The connection may not be a valid socks5 connection. You need to call
     /// [`IncomingConnection::authenticate`](crate::server::connection::IncomingConnection::authenticate)
     /// to hand-shake it into a proper socks5 connection.
-    #[inline]
-    pub async fn accept(&self) -> std::io::Result<(IncomingConnection<O>, SocketAddr)> {
-        let (stream, addr) = self.listener.accept().await?;
-        Ok((IncomingConnection::new(stream, self.auth.clone()), addr))
+    ///
+    /// The returned address is the [`client_addr`](SessionContext::client_addr) of the connection: the source
+    /// announced by a trusted load balancer, see [`with_proxy_protocol`](Self::with_proxy_protocol), or its peer.
+    ///
+    /// Once the [`ShutdownHandle`] of this server starts a shutdown, an error is returned instead.
+    pub async fn accept(&self) -> std::io::Result<Accepted<O, A>> {
+        let mut signal = self.shutdown.signal();
+        tokio::select! {
+            biased;
+            _ = signal.recv() => Err(shutting_down()),
+            accepted = std::future::poll_fn(|cx| self.poll_accept_within_limits(cx)) => accepted,
+        }
+    }
+
+    fn poll_accept_within_limits(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<Accepted<O, A>>> {
+        loop {
+            let (stream, ctx) = match self.poll_proxy_headers(cx) {
+                Poll::Ready(read) => read,
+                Poll::Pending => {
+                    let (stream, ctx) = std::task::ready!(self.listener.poll_accept(cx))?;
+                    match &self.proxy_protocol {
+                        Some(proxy) if proxy.is_trusted(ctx.peer_addr.ip()) => {
+                            let read = proxy_protocol::read_header(stream, ctx, proxy.timeout());
+                            self.proxy_headers.lock().unwrap().spawn(read);
+                            continue;
+                        }
+                        _ => (stream, ctx),
+                    }
+                }
+            };
+            let addr = ctx.client_addr();
+            if let Some(conn) = self.admit(stream, ctx) {
+                return Poll::Ready(Ok((conn, addr)));
+            }
+        }
+    }
+
+    /// Polls for a connection of a trusted load balancer whose PROXY protocol header has been read.
+    ///
+    /// The connections whose header is invalid or late are dropped.
+    fn poll_proxy_headers(&self, cx: &mut Context<'_>) -> Poll<(A::Stream, SessionContext)> {
+        let mut reads = self.proxy_headers.lock().unwrap();
+        while let Poll::Ready(Some(read)) = reads.poll_join_next(cx) {
+            match read {
+                Ok(Ok(read)) => return Poll::Ready(read),
+                Ok(Err(err)) => log::debug!("{err}"),
+                Err(err) => log::debug!("PROXY protocol header task failed: {err}"),
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Wrap an accepted stream into an [`IncomingConnection`], or drop it if it is over the limits.
+    fn admit(&self, stream: A::Stream, ctx: SessionContext) -> Option<IncomingConnection<O, A::Stream>> {
+        let ip = ctx.client_addr().ip();
+        let mut session = Session::new(ctx);
+        if let Some(limiter) = &self.limiter {
+            let permits = limiter.acquire_session(ip).ok()?;
+            session = session.with_limiter(limiter.clone(), permits);
+        }
+        if let Some(accounting) = &self.accounting {
+            session = session.with_accounting(accounting.clone());
+        }
+        if let Some(metrics) = &self.metrics {
+            session = session.with_metrics(metrics.clone());
+        }
+        if let Some(observer) = &self.observer {
+            session = session.with_observer(observer.clone());
+        }
+        if let Some(audit) = &self.audit {
+            session = session.with_observer(Arc::new(audit.clone()));
+        }
+        session.on_accepted();
+        Some(IncomingConnection::with_session(stream, self.auth.clone(), session))
     }
 
     /// Polls to accept an [`IncomingConnection<O>`](crate::server::connection::IncomingConnection).
     ///
-    /// The connection is only a freshly created TCP connection and may not be a valid SOCKS5 connection.
+    /// The connection is only a freshly accepted connection and may not be a valid SOCKS5 connection.
     /// You should call
     /// [`IncomingConnection::authenticate`](crate::server::connection::IncomingConnection::authenticate)
     /// to perform a SOCKS5 authentication handshake.
     ///
     /// If there is no connection to accept, Poll::Pending is returned and the current task will be notified by a waker.
     /// Note that on multiple calls to poll_accept, only the Waker from the Context passed to the most recent call is scheduled to receive a wakeup.
+    ///
+    /// After a shutdown has started, an error is returned. A pending poll is not woken up by the shutdown itself.
     #[inline]
-    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(IncomingConnection<O>, SocketAddr)>> {
-        self.listener
-            .poll_accept(cx)
-            .map_ok(|(stream, addr)| (IncomingConnection::new(stream, self.auth.clone()), addr))
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<Accepted<O, A>>> {
+        if self.shutdown.is_shutdown() {
+            return Poll::Ready(Err(shutting_down()));
+        }
+        self.poll_accept_within_limits(cx)
     }
 
-    /// Get the the local socket address binded to this server
+    /// Get the [`Acceptor`] this server accepts its connections from.
     #[inline]
-    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        self.listener.local_addr()
+    pub fn acceptor(&self) -> &A {
+        &self.listener
     }
+
+    /// Get the [`ShutdownHandle`] used to gracefully shut down this server and drain its sessions.
+    #[inline]
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+}
+
+fn shutting_down() -> std::io::Error {
+    std::io::Error::other("server is shutting down")
 }
 
-impl<O> From<(TcpListener, AuthAdaptor<O>)> for Server<O> {
+impl<O: 'static, A: Acceptor> From<(A, AuthAdaptor<O>)> for Server<O, A> {
     #[inline]
-    fn from((listener, auth): (TcpListener, AuthAdaptor<O>)) -> Self {
-        Self { listener, auth }
+    fn from((listener, auth): (A, AuthAdaptor<O>)) -> Self {
+        Self::from_acceptor(listener, auth)
     }
 }
 
-impl<O> From<Server<O>> for (TcpListener, AuthAdaptor<O>) {
+impl<O, A: Acceptor> From<Server<O, A>> for (A, AuthAdaptor<O>) {
     #[inline]
-    fn from(server: Server<O>) -> Self {
+    fn from(server: Server<O, A>) -> Self {
         (server.listener, server.auth)
     }
 }
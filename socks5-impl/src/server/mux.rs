@@ -0,0 +1,196 @@
+//! Multiplexed links on the server side, behind the `mux` feature.
+//!
+//! A [`MuxListener`] demultiplexes the links accepted by another [`Acceptor`], such as a
+//! `TlsListener` authenticating its clients by certificate, into one SOCKS5 connection per stream; see the
+//! [`mux`](crate::mux) module. Each stream gets a [`SessionContext`] of its own, with the addresses and the
+//! TLS identity of its link.
+//!
+//! ```no_run
+//! use socks5_impl::server::{Server, auth::NoAuth};
+//! use std::sync::Arc;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let server = Server::bind_mux("127.0.0.1:1080".parse().unwrap(), Arc::new(NoAuth)).await?;
+//! let (conn, _) = server.accept().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    mux::{Mode, MuxSession, MuxStream},
+    server::{AuthAdaptor, Server, context::SessionContext, transport::Acceptor},
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
+
+type Accepted = std::io::Result<(MuxStream, SessionContext)>;
+
+/// An [`Acceptor`] of the streams multiplexed over the links accepted by another acceptor.
+#[derive(Debug)]
+pub struct MuxListener<A: Acceptor = TcpListener> {
+    acceptor: Arc<A>,
+    accepted: Mutex<mpsc::Receiver<Accepted>>,
+    task: JoinHandle<()>,
+}
+
+impl<A: Acceptor + Send + Sync + 'static> MuxListener<A> {
+    /// Accept the streams of the links accepted by `acceptor`.
+    ///
+    /// This must be called from within a tokio runtime, which drives the links.
+    pub fn new(acceptor: A) -> Self {
+        let acceptor = Arc::new(acceptor);
+        let (sender, accepted) = mpsc::channel(64);
+        let task = tokio::spawn(accept_loop(acceptor.clone(), sender));
+        Self {
+            acceptor,
+            accepted: Mutex::new(accepted),
+            task,
+        }
+    }
+}
+
+impl<A: Acceptor> MuxListener<A> {
+    /// Get a reference to the acceptor the links come from.
+    pub fn get_ref(&self) -> &A {
+        &self.acceptor
+    }
+}
+
+impl<A: Acceptor> Drop for MuxListener<A> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl<A: Acceptor> Acceptor for MuxListener<A> {
+    type Stream = MuxStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Accepted> {
+        match std::task::ready!(self.accepted.lock().unwrap().poll_recv(cx)) {
+            Some(accepted) => Poll::Ready(accepted),
+            None => Poll::Ready(Err(std::io::Error::other("mux listener is closed"))),
+        }
+    }
+}
+
+async fn accept_loop<A: Acceptor + Send + Sync + 'static>(acceptor: Arc<A>, sender: mpsc::Sender<Accepted>) {
+    loop {
+        let (link, ctx) = match std::future::poll_fn(|cx| acceptor.poll_accept(cx)).await {
+            Ok(accepted) => accepted,
+            Err(err) => match sender.send(Err(err)).await {
+                Ok(()) => continue,
+                Err(_) => return,
+            },
+        };
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let session = MuxSession::new(link, Mode::Server);
+            while let Ok(stream) = session.accept().await {
+                if sender.send(Ok((stream, ctx.fork()))).await.is_err() {
+                    return;
+                }
+            }
+            log::debug!("mux link with {} closed", ctx.peer_addr);
+        });
+    }
+}
+
+impl<O: 'static> Server<O, MuxListener> {
+    /// Create a new socks5 server on the given socket address, accepting the streams of multiplexed TCP links.
+    pub async fn bind_mux(addr: SocketAddr, auth: AuthAdaptor<O>) -> std::io::Result<Self> {
+        let listener = MuxListener::new(TcpListener::bind(addr).await?);
+        Ok(Self::from_acceptor(listener, auth))
+    }
+
+    /// Get the local socket address bound to this server.
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.acceptor().get_ref().local_addr()
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{Address, Reply},
+        server::{ClientConnection, MuxConnector, OutboundConnector, auth::NoAuth},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Report its link and its session to the client of each CONNECT request.
+    fn serve_reports<O: Send + 'static, A: Acceptor + Send + Sync + 'static>(server: Server<O, A>) {
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = server.accept().await {
+                tokio::spawn(async move {
+                    let (conn, _) = conn.authenticate().await.unwrap();
+                    let ClientConnection::Connect(conn, target) = conn.wait_request().await.unwrap() else {
+                        panic!("not a CONNECT request");
+                    };
+                    let ctx = conn.context().clone();
+                    let mut conn = conn.reply(Reply::Succeeded, target).await.unwrap();
+                    let report = format!("{} {}", ctx.peer_addr, ctx.id);
+                    conn.stream.write_all(report.as_bytes()).await.unwrap();
+                    conn.stream.shutdown().await.unwrap();
+                });
+            }
+        });
+    }
+
+    async fn assert_sessions_share_a_link(connector: MuxConnector) {
+        let mut reports = Vec::new();
+        for port in [80, 443] {
+            let target = Address::from(("example.com", port));
+            let (mut stream, bound) = connector.connect(&target, &Default::default()).await.unwrap();
+            assert_eq!(bound, target);
+            let mut report = String::new();
+            stream.read_to_string(&mut report).await.unwrap();
+            let (link, id) = report.split_once(' ').unwrap();
+            reports.push((link.to_owned(), id.to_owned()));
+        }
+        // Both sessions came over the same link, with a context of their own
+        assert_eq!(reports[0].0, reports[1].0);
+        assert_ne!(reports[0].1, reports[1].1);
+    }
+
+    #[tokio::test]
+    async fn sessions_share_a_link() {
+        let server = Server::bind_mux("127.0.0.1:0".parse().unwrap(), Arc::new(NoAuth)).await.unwrap();
+        let proxy = server.local_addr().unwrap();
+        serve_reports(server);
+        assert_sessions_share_a_link(MuxConnector::new(proxy, None)).await;
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn sessions_share_a_tls_link() {
+        use crate::server::tls::{TlsConfig, TlsListener};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig, crypto::ring, pki_types::PrivatePkcs8KeyDer};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key.into())
+            .unwrap();
+        let listener = TlsListener::new(TcpListener::bind("127.0.0.1:0").await.unwrap(), TlsConfig::new(Arc::new(config))).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        serve_reports(Server::from_acceptor(MuxListener::new(listener), Arc::new(NoAuth)));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = MuxConnector::new(("localhost", port), None).with_tls(Arc::new(config));
+        assert_sessions_share_a_link(connector).await;
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    protocol::{Address, Command, Reply},
+    server::context::SessionContext,
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+/// Callbacks on the lifecycle of the sessions of a [`Server`](crate::server::Server), registered with
+/// [`Server::with_observer`](crate::server::Server::with_observer).
+///
+/// The callbacks are called inline by the session, so they should return quickly, spawning a task for any I/O.
+/// Each callback does nothing by default.
+///
+/// # Example
+/// ```rust
+/// use socks5_impl::server::{SessionContext, SessionObserver, SessionSummary};
+///
+/// struct Logger;
+///
+/// impl SessionObserver for Logger {
+///     fn on_closed(&self, ctx: &SessionContext, summary: &SessionSummary) {
+///         println!("session {} from {} closed after {:?}", ctx.id, ctx.client_addr(), summary.duration);
+///     }
+/// }
+/// ```
+#[allow(unused_variables)]
+pub trait SessionObserver {
+    /// A connection has been accepted.
+    fn on_accepted(&self, ctx: &SessionContext) {}
+
+    /// The handshake is done, with the negotiated method and identity in `ctx`.
+    ///
+    /// `failure` is the reason of a failed authentication, see [`AuthExecutor::failure_reason`](crate::server::AuthExecutor::failure_reason).
+    fn on_handshake(&self, ctx: &SessionContext, failure: Option<&str>) {}
+
+    /// The client sent a request.
+    fn on_request(&self, ctx: &SessionContext, command: Command, target: &Address) {}
+
+    /// The outbound connection to `target` has been opened by
+    /// [`Connect::connect_outbound`](crate::server::Connect::connect_outbound) in `latency`.
+    ///
+    /// `remote` is the address the outbound stream is connected to, when the connector knows it,
+    /// see [`OutboundConnector::peer_addr`](crate::server::OutboundConnector::peer_addr).
+    fn on_connected(&self, ctx: &SessionContext, target: &Address, remote: Option<SocketAddr>, latency: Duration) {}
+
+    /// A reply has been sent to the client.
+    fn on_reply(&self, ctx: &SessionContext, reply: Reply) {}
+
+    /// The session is over.
+    fn on_closed(&self, ctx: &SessionContext, summary: &SessionSummary) {}
+}
+
+pub type ObserverAdaptor = Arc<dyn SessionObserver + Send + Sync>;
+
+/// What a session did, reported to [`SessionObserver::on_closed`].
+///
+/// The library does not relay the traffic itself: the relayed bytes are the ones recorded with `record_transfer`
+/// on [`Connect`](crate::server::Connect::record_transfer), [`Bind`](crate::server::Bind::record_transfer) or
+/// [`UdpAssociate`](crate::server::UdpAssociate::record_transfer).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SessionSummary {
+    /// The bytes relayed from the client.
+    pub bytes_up: u64,
+    /// The bytes relayed to the client.
+    pub bytes_down: u64,
+    /// The time since the connection has been accepted.
+    pub duration: Duration,
+    /// The error which ended the session, if any.
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::StreamOperation;
+    use crate::server::{ClientConnection, DirectConnector, OutboundContext, Server, auth::NoAuth};
+    use std::sync::Mutex;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl SessionObserver for Recorder {
+        fn on_accepted(&self, ctx: &SessionContext) {
+            self.0.lock().unwrap().push(format!("accepted {}", ctx.id));
+        }
+
+        fn on_handshake(&self, ctx: &SessionContext, failure: Option<&str>) {
+            self.0.lock().unwrap().push(format!("handshake {:?} {failure:?}", ctx.auth_method));
+        }
+
+        fn on_request(&self, _ctx: &SessionContext, command: Command, target: &Address) {
+            self.0.lock().unwrap().push(format!("request {command:?} {target}"));
+        }
+
+        fn on_connected(&self, _ctx: &SessionContext, target: &Address, remote: Option<SocketAddr>, _latency: Duration) {
+            assert_eq!(remote.map(Address::from).as_ref(), Some(target));
+            self.0.lock().unwrap().push("connected".into());
+        }
+
+        fn on_reply(&self, _ctx: &SessionContext, reply: Reply) {
+            self.0.lock().unwrap().push(format!("reply {reply:?}"));
+        }
+
+        fn on_closed(&self, _ctx: &SessionContext, summary: &SessionSummary) {
+            let SessionSummary {
+                bytes_up,
+                bytes_down,
+                error,
+                ..
+            } = summary;
+            self.0.lock().unwrap().push(format!("closed {bytes_up} {bytes_down} {error:?}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn session_lifecycle() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(NoAuth)).await.unwrap();
+        let server = server.with_observer(recorder.clone());
+
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut request = vec![0x05, 0x01, 0x00];
+        Address::from(target_addr).write_to_buf(&mut request);
+        client.write_all(&request).await.unwrap();
+
+        let (conn, _) = server.accept().await.unwrap();
+        let id = conn.context().id;
+        let (conn, _) = conn.authenticate().await.unwrap();
+        let ClientConnection::Connect(conn, addr) = conn.wait_request().await.unwrap() else {
+            panic!("not a CONNECT request");
+        };
+        let ctx = OutboundContext::from(conn.context());
+        let (mut conn, _outbound) = conn.connect_outbound(&DirectConnector::default(), &addr, &ctx).await.unwrap();
+        conn.record_transfer(3, 5);
+        drop(conn);
+
+        let events = recorder.0.lock().unwrap().clone();
+        let expected = [
+            format!("accepted {id}"),
+            "handshake Some(NoAuth) None".into(),
+            format!("request Connect {target_addr}"),
+            "connected".into(),
+            "reply Succeeded".into(),
+            "closed 3 5 None".into(),
+        ];
+        assert_eq!(events, expected);
+    }
+}
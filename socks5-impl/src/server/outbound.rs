@@ -0,0 +1,502 @@
+use crate::{
+    happy_eyeballs::HappyEyeballs,
+    protocol::{Address, UserKey},
+    resolver::{Resolver, ResolverAdaptor, SystemResolver},
+    server::context::SessionContext,
+};
+use http::{Method, StatusCode, Uri};
+use http_impl::{BasicAuth, HttpError, HttpRequestBuilder, HttpResponse};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+/// This trait is for defining how the server reaches the target of a `CONNECT` request.
+///
+/// Pre-defined connectors can be found in the [`outbound`](crate::server::outbound) module:
+/// [`DirectConnector`] dials the target itself, while [`Socks5Connector`] and [`HttpConnector`]
+/// chain the request through an upstream proxy.
+///
+/// # Example
+/// ```rust
+/// use socks5_impl::protocol::Address;
+/// use socks5_impl::server::outbound::{OutboundConnector, OutboundContext};
+/// use tokio::net::TcpStream;
+///
+/// pub struct Blackhole;
+///
+/// #[async_trait::async_trait]
+/// impl OutboundConnector for Blackhole {
+///     type Stream = TcpStream;
+///
+///     async fn connect(&self, target: &Address, _ctx: &OutboundContext) -> std::io::Result<(TcpStream, Address)> {
+///         let err = format!("{target} is not allowed");
+///         Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, err))
+///     }
+/// }
+/// ```
+#[async_trait::async_trait]
+pub trait OutboundConnector {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    /// Open a stream to `target`, returning it alongside the bound address to report to the client.
+    async fn connect(&self, target: &Address, ctx: &OutboundContext) -> std::io::Result<(Self::Stream, Address)>;
+
+    /// The address `stream` is connected to, if known, reported to the [`SessionObserver`](crate::server::SessionObserver).
+    fn peer_addr(&self, _stream: &Self::Stream) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Information about the session an outbound connection is opened for.
+#[derive(Clone, Debug, Default)]
+pub struct OutboundContext {
+    /// The address of the SOCKS5 client that issued the request, if known.
+    pub peer_addr: Option<SocketAddr>,
+}
+
+impl From<&SessionContext> for OutboundContext {
+    fn from(ctx: &SessionContext) -> Self {
+        Self {
+            peer_addr: Some(ctx.client_addr()),
+        }
+    }
+}
+
+/// Connects to the target directly from this host.
+///
+/// Domain targets are resolved with the configured [`Resolver`], the [`SystemResolver`] by default.
+/// When a target resolves to several addresses, they are raced with [`HappyEyeballs`].
+#[derive(Clone)]
+pub struct DirectConnector {
+    resolver: ResolverAdaptor,
+    happy_eyeballs: HappyEyeballs,
+    timeout: Option<Duration>,
+}
+
+impl Default for DirectConnector {
+    fn default() -> Self {
+        Self {
+            resolver: Arc::new(SystemResolver),
+            happy_eyeballs: HappyEyeballs::default(),
+            timeout: None,
+        }
+    }
+}
+
+impl DirectConnector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the resolver used for domain targets.
+    pub fn with_resolver(mut self, resolver: ResolverAdaptor) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Set how connection attempts to multiple resolved addresses are raced.
+    pub fn with_happy_eyeballs(mut self, happy_eyeballs: HappyEyeballs) -> Self {
+        self.happy_eyeballs = happy_eyeballs;
+        self
+    }
+
+    /// Set a timeout for establishing the connection.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl OutboundConnector for DirectConnector {
+    type Stream = TcpStream;
+
+    async fn connect(&self, target: &Address, _ctx: &OutboundContext) -> std::io::Result<(TcpStream, Address)> {
+        let stream = with_timeout(self.timeout, connect_tcp(target, &*self.resolver, &self.happy_eyeballs)).await?;
+        let bound = Address::from(stream.local_addr()?);
+        Ok((stream, bound))
+    }
+
+    fn peer_addr(&self, stream: &TcpStream) -> Option<SocketAddr> {
+        stream.peer_addr().ok()
+    }
+}
+
+/// Chains the request through an upstream SOCKS5 server.
+#[cfg(feature = "client")]
+#[derive(Clone)]
+pub struct Socks5Connector {
+    proxy: Address,
+    auth: Option<UserKey>,
+    resolver: ResolverAdaptor,
+    happy_eyeballs: HappyEyeballs,
+    timeout: Option<Duration>,
+}
+
+#[cfg(feature = "client")]
+impl Socks5Connector {
+    pub fn new<A: Into<Address>>(proxy: A, auth: Option<UserKey>) -> Self {
+        Self {
+            proxy: proxy.into(),
+            auth,
+            resolver: Arc::new(SystemResolver),
+            happy_eyeballs: HappyEyeballs::default(),
+            timeout: None,
+        }
+    }
+
+    /// Set the resolver used when the proxy is given as a domain address.
+    pub fn with_resolver(mut self, resolver: ResolverAdaptor) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Set how connection attempts to multiple resolved addresses are raced.
+    pub fn with_happy_eyeballs(mut self, happy_eyeballs: HappyEyeballs) -> Self {
+        self.happy_eyeballs = happy_eyeballs;
+        self
+    }
+
+    /// Set a timeout for reaching the upstream server and completing its handshake.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[cfg(feature = "client")]
+#[async_trait::async_trait]
+impl OutboundConnector for Socks5Connector {
+    type Stream = BufStream<TcpStream>;
+
+    async fn connect(&self, target: &Address, _ctx: &OutboundContext) -> std::io::Result<(Self::Stream, Address)> {
+        with_timeout(self.timeout, async {
+            let mut stream = BufStream::new(connect_tcp(&self.proxy, &*self.resolver, &self.happy_eyeballs).await?);
+            let bound = crate::client::connect(&mut stream, target, self.auth.clone()).await?;
+            Ok((stream, bound))
+        })
+        .await
+    }
+}
+
+/// Chains the requests through an upstream SOCKS5 server, multiplexing them over a single TCP link.
+/// Behind the `mux` feature.
+///
+/// The link is opened by the first request, and opened again by the next request once it is closed. The upstream
+/// server accepts the streams of the link with a [`MuxListener`](crate::server::MuxListener), over TLS if the link
+/// is configured with `with_tls`.
+#[cfg(all(feature = "client", feature = "mux"))]
+#[derive(Clone)]
+pub struct MuxConnector {
+    proxy: Address,
+    auth: Option<UserKey>,
+    resolver: ResolverAdaptor,
+    happy_eyeballs: HappyEyeballs,
+    timeout: Option<Duration>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tokio_rustls::rustls::ClientConfig>>,
+    link: Arc<tokio::sync::Mutex<Option<crate::mux::MuxSession>>>,
+}
+
+#[cfg(all(feature = "client", feature = "mux"))]
+impl MuxConnector {
+    pub fn new<A: Into<Address>>(proxy: A, auth: Option<UserKey>) -> Self {
+        Self {
+            proxy: proxy.into(),
+            auth,
+            resolver: Arc::new(SystemResolver),
+            happy_eyeballs: HappyEyeballs::default(),
+            timeout: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            link: Arc::default(),
+        }
+    }
+
+    /// Run the link over TLS, verifying the certificate of the upstream server for the name or the IP address of
+    /// the proxy, like [`connect_proxy_tls`](crate::client::connect_proxy_tls). Behind the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<tokio_rustls::rustls::ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Set the resolver used when the proxy is given as a domain address.
+    pub fn with_resolver(mut self, resolver: ResolverAdaptor) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Set how connection attempts to multiple resolved addresses are raced.
+    pub fn with_happy_eyeballs(mut self, happy_eyeballs: HappyEyeballs) -> Self {
+        self.happy_eyeballs = happy_eyeballs;
+        self
+    }
+
+    /// Set a timeout for opening a stream, the link included if needed, and completing its handshake.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    async fn open(&self) -> std::io::Result<crate::mux::MuxStream> {
+        let mut link = self.link.lock().await;
+        if let Some(session) = link.as_ref().filter(|session| !session.is_closed()) {
+            return session.open();
+        }
+        let stream = connect_tcp(&self.proxy, &*self.resolver, &self.happy_eyeballs).await?;
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let server_name = crate::client::tls_server_name(&self.proxy)?;
+            let stream = tokio_rustls::TlsConnector::from(config.clone())
+                .connect(server_name, stream)
+                .await?;
+            let session = link.insert(crate::mux::MuxSession::new(stream, crate::mux::Mode::Client));
+            return session.open();
+        }
+        let session = link.insert(crate::mux::MuxSession::new(stream, crate::mux::Mode::Client));
+        session.open()
+    }
+}
+
+#[cfg(all(feature = "client", feature = "mux"))]
+#[async_trait::async_trait]
+impl OutboundConnector for MuxConnector {
+    type Stream = crate::mux::MuxStream;
+
+    async fn connect(&self, target: &Address, _ctx: &OutboundContext) -> std::io::Result<(Self::Stream, Address)> {
+        with_timeout(self.timeout, async {
+            let mut stream = self.open().await?;
+            let bound = crate::client::connect(&mut stream, target, self.auth.clone()).await?;
+            Ok((stream, bound))
+        })
+        .await
+    }
+}
+
+/// Chains the request through an upstream HTTP proxy with the `CONNECT` method.
+///
+/// HTTP proxies do not report the address they connected from, so the bound address is always unspecified.
+#[derive(Clone)]
+pub struct HttpConnector {
+    proxy: Address,
+    auth: Option<UserKey>,
+    resolver: ResolverAdaptor,
+    happy_eyeballs: HappyEyeballs,
+    timeout: Option<Duration>,
+}
+
+impl HttpConnector {
+    /// Credentials, if any, are sent with `Basic` proxy authentication.
+    pub fn new<A: Into<Address>>(proxy: A, auth: Option<UserKey>) -> Self {
+        Self {
+            proxy: proxy.into(),
+            auth,
+            resolver: Arc::new(SystemResolver),
+            happy_eyeballs: HappyEyeballs::default(),
+            timeout: None,
+        }
+    }
+
+    /// Set the resolver used when the proxy is given as a domain address.
+    pub fn with_resolver(mut self, resolver: ResolverAdaptor) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Set how connection attempts to multiple resolved addresses are raced.
+    pub fn with_happy_eyeballs(mut self, happy_eyeballs: HappyEyeballs) -> Self {
+        self.happy_eyeballs = happy_eyeballs;
+        self
+    }
+
+    /// Set a timeout for reaching the upstream proxy and receiving its response.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    async fn handshake(&self, target: &Address) -> std::io::Result<BufStream<TcpStream>> {
+        let authority = target.to_string();
+        let uri = authority
+            .parse::<Uri>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let mut builder = HttpRequestBuilder::new()
+            .method(Method::CONNECT)
+            .uri(uri)
+            .header("Host", &authority);
+        if let Some(auth) = &self.auth {
+            let auth = BasicAuth::new(&auth.username, &auth.password);
+            builder = builder.header("Proxy-Authorization", &auth.header_value());
+        }
+        let request = builder.build();
+
+        let mut stream = BufStream::new(connect_tcp(&self.proxy, &*self.resolver, &self.happy_eyeballs).await?);
+        stream.write_all(request.raw_bytes()).await?;
+        stream.flush().await?;
+
+        // The tunnel starts right after the response head, so it must not be over-read.
+        let response = HttpResponse::from_buf_reader(&mut stream).await.map_err(|err| match err {
+            HttpError::Io(err) => err,
+            err => std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+        })?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(stream);
+        }
+        let kind = match status {
+            StatusCode::PROXY_AUTHENTICATION_REQUIRED | StatusCode::FORBIDDEN => std::io::ErrorKind::PermissionDenied,
+            StatusCode::GATEWAY_TIMEOUT => std::io::ErrorKind::TimedOut,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => std::io::ErrorKind::ConnectionRefused,
+            _ => std::io::ErrorKind::Other,
+        };
+        let err = format!("upstream HTTP proxy refused CONNECT {authority}: {status}");
+        Err(std::io::Error::new(kind, err))
+    }
+}
+
+#[async_trait::async_trait]
+impl OutboundConnector for HttpConnector {
+    type Stream = BufStream<TcpStream>;
+
+    async fn connect(&self, target: &Address, _ctx: &OutboundContext) -> std::io::Result<(Self::Stream, Address)> {
+        let stream = with_timeout(self.timeout, self.handshake(target)).await?;
+        Ok((stream, Address::unspecified()))
+    }
+}
+
+async fn connect_tcp(
+    addr: &Address,
+    resolver: &(dyn Resolver + Send + Sync),
+    happy_eyeballs: &HappyEyeballs,
+) -> std::io::Result<TcpStream> {
+    let (stream, _) = happy_eyeballs.connect_address(addr, resolver).await?;
+    Ok(stream)
+}
+
+async fn with_timeout<T, F>(timeout: Option<Duration>, fut: F) -> std::io::Result<T>
+where
+    F: Future<Output = std::io::Result<T>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "outbound connect timeout"))?,
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_impl::HttpRequest;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn fake_http_proxy(response: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = HttpRequest::from_stream(&mut stream).await.unwrap();
+            assert_eq!(request.method(), Method::CONNECT);
+            assert_eq!(request.uri().to_string(), "example.com:22");
+            let auth = request.parse_basic_auth().unwrap().unwrap();
+            assert_eq!((auth.username.as_str(), auth.password.as_str()), ("user", "pass"));
+            stream.write_all(response).await.unwrap();
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn direct_connector() {
+        let echo = echo_server().await;
+        let resolver = crate::resolver::HostsResolver::new(SystemResolver).with_host("echo.test", [echo.ip()]);
+        let connector = DirectConnector::new()
+            .with_resolver(Arc::new(resolver))
+            .with_timeout(Duration::from_secs(5));
+        let target = Address::from(("echo.test", echo.port()));
+        let (mut stream, bound) = connector.connect(&target, &OutboundContext::default()).await.unwrap();
+        assert_eq!(bound, Address::from(stream.local_addr().unwrap()));
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn http_connector_keeps_early_payload() {
+        let proxy = fake_http_proxy(b"HTTP/1.1 200 Connection established\r\n\r\nSSH-2.0-test\r\n").await;
+        let connector = HttpConnector::new(proxy, Some(UserKey::new("user", "pass")));
+        let target = Address::from(("example.com", 22));
+        let (mut stream, bound) = connector.connect(&target, &OutboundContext::default()).await.unwrap();
+        assert_eq!(bound, Address::unspecified());
+
+        let mut buf = [0; 14];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SSH-2.0-test\r\n");
+    }
+
+    #[tokio::test]
+    async fn http_connector_rejected() {
+        let proxy = fake_http_proxy(http_impl::constants::AUTHENTICATION_REQUIRED).await;
+        let connector = HttpConnector::new(proxy, Some(UserKey::new("user", "pass")));
+        let target = Address::from(("example.com", 22));
+        let err = connector.connect(&target, &OutboundContext::default()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn socks5_connector_through_server() {
+        use crate::server::{ClientConnection, Server, auth::NoAuth};
+        use std::sync::Arc;
+
+        let echo = echo_server().await;
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(NoAuth)).await.unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (conn, peer_addr) = server.accept().await.unwrap();
+            let (conn, _) = conn.authenticate().await.unwrap();
+            let ClientConnection::Connect(conn, target) = conn.wait_request().await.unwrap() else {
+                panic!("expected CONNECT");
+            };
+            let ctx = OutboundContext {
+                peer_addr: Some(peer_addr),
+            };
+            let (mut conn, mut outbound) = conn.connect_outbound(&DirectConnector::new(), &target, &ctx).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut conn.stream, &mut outbound).await;
+        });
+
+        let connector = Socks5Connector::new(proxy, None);
+        let (mut stream, bound) = connector.connect(&echo.into(), &OutboundContext::default()).await.unwrap();
+        assert!(bound.is_ipv4());
+
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
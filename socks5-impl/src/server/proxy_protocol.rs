@@ -0,0 +1,240 @@
+//! PROXY protocol v2 support, for servers behind a load balancer.
+//!
+//! With [`Server::with_proxy_protocol`](crate::server::Server::with_proxy_protocol), connections from the trusted
+//! load balancers must start with a PROXY protocol v2 header, which is read before the server hands the connection
+//! out. The source it announces becomes the [`client_addr`](crate::server::SessionContext::client_addr) of the
+//! session, and the per source IP caps of [`ConnectionLimits`](crate::server::ConnectionLimits) apply to it.
+//! Headers with the `LOCAL` command, sent by the health checks of the load balancers, keep the address of the peer.
+//! Connections from other peers are handled as plain SOCKS5 connections, so they cannot spoof their address.
+
+use crate::server::SessionContext;
+use std::{net::IpAddr, str::FromStr, sync::Arc, time::Duration};
+use tokio::io::AsyncRead;
+
+/// An IP network, written `192.0.2.0/24` or `2001:db8::/32`. A bare address is a network of a single address.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The network of `addr` with a prefix of `prefix` bits, failing if the prefix is too long for the address family.
+    pub fn new(addr: IpAddr, prefix: u8) -> std::io::Result<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            let err = format!("invalid prefix length {prefix} for {addr}");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err));
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    /// Whether `ip` belongs to this network. IPv4-mapped IPv6 addresses are matched as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u32, prefix: u8) -> bool {
+    let shift = bits - u32::from(prefix);
+    net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid network {s}"));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = std::io::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+/// Which peers send a PROXY protocol v2 header ahead of the SOCKS5 handshake.
+#[derive(Clone, Debug)]
+pub struct ProxyProtocol {
+    trusted: Arc<[Cidr]>,
+    timeout: Duration,
+}
+
+impl Default for ProxyProtocol {
+    fn default() -> Self {
+        Self::new([])
+    }
+}
+
+impl ProxyProtocol {
+    /// Expect a PROXY protocol header from the peers in the `trusted` networks.
+    pub fn new(trusted: impl IntoIterator<Item = Cidr>) -> Self {
+        Self {
+            trusted: trusted.into_iter().collect(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Set how long a trusted peer has to send its header, 10 seconds by default. Late peers are disconnected.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Whether connections from `ip` start with a PROXY protocol header.
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// Read the PROXY protocol v2 header at the start of `stream` within `timeout`, and record the source address it
+/// announces in `ctx`.
+pub(crate) async fn read_header<S>(mut stream: S, mut ctx: SessionContext, timeout: Duration) -> std::io::Result<(S, SessionContext)>
+where
+    S: AsyncRead + Unpin,
+{
+    use ::proxy_protocol::{result::Error, version2::read_proxy_protocol};
+
+    let peer_addr = ctx.peer_addr;
+    let header = match tokio::time::timeout(timeout, read_proxy_protocol(&mut stream)).await {
+        Ok(Ok(header)) => header,
+        Ok(Err(Error::Io(err))) => return Err(std::io::Error::new(err.kind(), format!("PROXY protocol from {peer_addr}: {err}"))),
+        Ok(Err(err)) => {
+            let err = format!("PROXY protocol from {peer_addr}: {err}");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+        }
+        Err(_) => {
+            let err = format!("PROXY protocol from {peer_addr}: no header within {timeout:?}");
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, err));
+        }
+    };
+    ctx.proxy_source = header.addresses.map(|addresses| addresses.source);
+    Ok((stream, ctx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+
+        let host: Cidr = "2001:db8::1".parse().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("192.0.2.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn client_address_from_trusted_proxy() {
+        use crate::server::{ConnectionLimits, Server, auth::NoAuth};
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        };
+
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(NoAuth)).await.unwrap();
+        let trusted = ProxyProtocol::new(["127.0.0.0/8".parse().unwrap()]);
+        let limits = ConnectionLimits::new().with_max_sessions_per_ip(1);
+        let server = server.with_proxy_protocol(trusted).with_limits(limits);
+        let server_addr = server.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        header.extend_from_slice(&[198, 51, 100, 7, 127, 0, 0, 1, 0x30, 0x39, 0x04, 0x38]);
+        client.write_all(&header).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+
+        let (conn, addr) = server.accept().await.unwrap();
+        let source: SocketAddr = "198.51.100.7:12345".parse().unwrap();
+        assert_eq!(addr, source);
+        assert_eq!(conn.context().proxy_source, Some(source));
+        let limiter = server.limiter().unwrap();
+        assert_eq!(limiter.sessions_of_ip(source.ip()), 1);
+        assert_eq!(limiter.sessions_of_ip("127.0.0.1".parse().unwrap()), 0);
+        let (authenticated, _) = conn.authenticate().await.unwrap();
+        assert_eq!(authenticated.context().client_addr(), source);
+
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+
+        // A health check of the load balancer keeps its own address
+        let mut health_check = TcpStream::connect(server_addr).await.unwrap();
+        health_check.write_all(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00").await.unwrap();
+        health_check.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let (conn, addr) = server.accept().await.unwrap();
+        assert_eq!(addr, health_check.local_addr().unwrap());
+        assert_eq!(conn.context().proxy_source, None);
+    }
+
+    #[tokio::test]
+    async fn silent_proxy_does_not_block_accept() {
+        use crate::server::{Server, auth::NoAuth};
+        use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(NoAuth)).await.unwrap();
+        let trusted = ProxyProtocol::new(["127.0.0.0/8".parse().unwrap()]).with_timeout(Duration::from_millis(200));
+        let server = server.with_proxy_protocol(trusted);
+        let addr = server.local_addr().unwrap();
+
+        let _silent = TcpStream::connect(addr).await.unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        header.extend_from_slice(&[198, 51, 100, 7, 127, 0, 0, 1, 0x30, 0x39, 0x04, 0x38]);
+        client.write_all(&header).await.unwrap();
+
+        let (_, addr) = tokio::time::timeout(Duration::from_millis(100), server.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(addr, "198.51.100.7:12345".parse().unwrap());
+        // The silent connection is dropped once its header is late
+        let accepted = tokio::time::timeout(Duration::from_millis(500), server.accept()).await;
+        assert!(accepted.is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! SOCKS5 over QUIC on the server side, behind the `quic` feature.
+//!
+//! A [`QuicListener`] accepts the bidirectional streams of its QUIC connections as SOCKS5 connections, see the
+//! [`quic`](crate::quic) module. The TLS handshake of QUIC is configured by a [`TlsConfig`], whose client
+//! certificates name the [`tls_identity`](crate::server::SessionContext::tls_identity) of the sessions as over TLS.
+//!
+//! A UDP ASSOCIATE session relays its packets with the [`QuicAssociation`](crate::quic::QuicAssociation) of its
+//! stream rather than with an [`AssociatedUdpSocket`](crate::server::AssociatedUdpSocket):
+//!
+//! ```no_run
+//! use socks5_impl::{
+//!     protocol::{Address, Reply},
+//!     server::{ClientConnection, Server, TlsConfig, auth::NoAuth},
+//! };
+//! use std::sync::Arc;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let tls = TlsConfig::from_pem_files("server.crt", "server.key", None)?;
+//! let server = Server::bind_quic("0.0.0.0:1080".parse().unwrap(), tls, Arc::new(NoAuth))?;
+//! let (conn, _) = server.accept().await?;
+//! let (conn, _) = conn.authenticate().await?;
+//! if let ClientConnection::UdpAssociate(associate, _) = conn.wait_request().await? {
+//!     let association = associate.stream.associate();
+//!     let _associate = associate.reply(Reply::Succeeded, Address::unspecified()).await?;
+//!     let (pkt, frag, target) = association.recv().await?;
+//!     // ... relay `pkt` to `target`, and the responses back with `association.send`
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    quic::{QuicConnection, QuicStream},
+    server::{
+        AuthAdaptor, Server, TlsConfig,
+        context::SessionContext,
+        transport::{Acceptor, MAX_HANDSHAKES},
+    },
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{
+    sync::{Semaphore, mpsc},
+    task::JoinHandle,
+};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+type Accepted = std::io::Result<(QuicStream, SessionContext)>;
+
+/// An [`Acceptor`] of the streams of QUIC connections, each of which is a SOCKS5 connection.
+///
+/// The QUIC handshakes run in the background, and the connections whose handshake fails are dropped. Like a
+/// `TlsListener`, it runs up to 256 handshakes at once, and drops the streams the server does not take within the
+/// handshake timeout.
+#[derive(Debug)]
+pub struct QuicListener {
+    endpoint: quinn::Endpoint,
+    accepted: Mutex<mpsc::Receiver<Accepted>>,
+    task: JoinHandle<()>,
+}
+
+impl QuicListener {
+    /// Accept QUIC connections on the given socket address, with the TLS configuration `tls`, which must allow
+    /// TLS 1.3.
+    ///
+    /// This must be called from within a tokio runtime, which runs the handshakes.
+    pub fn bind(addr: SocketAddr, tls: TlsConfig) -> std::io::Result<Self> {
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls.server.clone())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("QUIC: {err}")))?;
+        let endpoint = quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)?;
+        let (sender, accepted) = mpsc::channel(64);
+        let task = tokio::spawn(accept_loop(endpoint.clone(), tls, sender));
+        Ok(Self {
+            endpoint,
+            accepted: Mutex::new(accepted),
+            task,
+        })
+    }
+
+    /// Get the local socket address of the QUIC endpoint.
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Get a reference to the underlying QUIC endpoint.
+    pub fn endpoint(&self) -> &quinn::Endpoint {
+        &self.endpoint
+    }
+}
+
+impl Drop for QuicListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Acceptor for QuicListener {
+    type Stream = QuicStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Accepted> {
+        match std::task::ready!(self.accepted.lock().unwrap().poll_recv(cx)) {
+            Some(accepted) => Poll::Ready(accepted),
+            None => Poll::Ready(Err(std::io::Error::other("QUIC listener is closed"))),
+        }
+    }
+}
+
+async fn accept_loop(endpoint: quinn::Endpoint, tls: TlsConfig, sender: mpsc::Sender<Accepted>) {
+    let Ok(local_addr) = endpoint.local_addr() else {
+        return;
+    };
+    let running = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+    loop {
+        let Ok(permit) = running.clone().acquire_owned().await else {
+            return;
+        };
+        let Some(incoming) = endpoint.accept().await else {
+            return;
+        };
+        let (tls, sender) = (tls.clone(), sender.clone());
+        tokio::spawn(async move {
+            let peer_addr = incoming.remote_address();
+            let connection = match tokio::time::timeout(tls.handshake_timeout, incoming).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(err)) => return log::debug!("QUIC handshake with {peer_addr} failed: {err}"),
+                Err(_) => return log::debug!("QUIC handshake with {peer_addr} timed out"),
+            };
+            // The connection lasts for many sessions, only its handshake counts against the others
+            drop(permit);
+            let certs = connection
+                .peer_identity()
+                .and_then(|certs| certs.downcast::<Vec<CertificateDer>>().ok());
+            let identity = certs.and_then(|certs| (tls.identity)(certs.first()?));
+            let connection = QuicConnection::new(connection);
+            loop {
+                let stream = match connection.accept().await {
+                    Ok(stream) => stream,
+                    Err(err) => return log::debug!("QUIC connection with {peer_addr} closed: {err}"),
+                };
+                let ctx = SessionContext::new(peer_addr, local_addr).with_tls_identity(identity.clone());
+                match tokio::time::timeout(tls.handshake_timeout, sender.send(Ok((stream, ctx)))).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => return,
+                    Err(_) => log::debug!("QUIC stream from {peer_addr} was not accepted in time"),
+                }
+            }
+        });
+    }
+}
+
+impl<O: 'static> Server<O, QuicListener> {
+    /// Create a new socks5 server on the given socket address, accepting the streams of QUIC connections.
+    pub fn bind_quic(addr: SocketAddr, tls: TlsConfig, auth: AuthAdaptor<O>) -> std::io::Result<Self> {
+        Ok(Self::from_acceptor(QuicListener::bind(addr, tls)?, auth))
+    }
+
+    /// Get the local socket address bound to this server.
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.acceptor().local_addr()
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{Address, Reply},
+        server::{ClientConnection, auth::NoAuth},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{
+        ClientConfig, RootCertStore, ServerConfig,
+        crypto::ring,
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    };
+
+    #[tokio::test]
+    async fn sessions_and_datagrams_share_a_connection() {
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+        let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()));
+        let server = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        let server = Server::bind_quic("127.0.0.1:0".parse().unwrap(), TlsConfig::new(Arc::new(server)), Arc::new(NoAuth)).unwrap();
+        let proxy = server.local_addr().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let (connection, _) = crate::client::connect_proxy_quic(proxy, Arc::new(client)).await.unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = connection.open().await.unwrap();
+            crate::client::connect(&mut stream, ("example.com", 80), None).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut pong = [0; 4];
+            stream.read_exact(&mut pong).await.unwrap();
+
+            let mut stream = connection.open().await.unwrap();
+            crate::client::udp_associate(&mut stream, Address::unspecified(), None)
+                .await
+                .unwrap();
+            let association = stream.associate();
+            association.send(b"query", 0, Address::from(("example.com", 53))).await.unwrap();
+            let (answer, frag, source) = association.recv().await.unwrap();
+            (pong, answer, frag, source)
+        });
+
+        let (conn, peer_addr) = server.accept().await.unwrap();
+        assert_eq!(peer_addr.ip(), proxy.ip());
+        let (conn, _) = conn.authenticate().await.unwrap();
+        let ClientConnection::Connect(conn, _) = conn.wait_request().await.unwrap() else {
+            panic!("not a CONNECT request");
+        };
+        let mut conn = conn.reply(Reply::Succeeded, Address::unspecified()).await.unwrap();
+        let mut ping = [0; 4];
+        conn.stream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        conn.stream.write_all(b"pong").await.unwrap();
+
+        // The second session is another stream of the same connection
+        let (conn, _) = server.accept().await.unwrap();
+        let (conn, _) = conn.authenticate().await.unwrap();
+        let ClientConnection::UdpAssociate(associate, _) = conn.wait_request().await.unwrap() else {
+            panic!("not a UDP ASSOCIATE request");
+        };
+        let association = associate.stream.associate();
+        let _associate = associate.reply(Reply::Succeeded, Address::unspecified()).await.unwrap();
+        let (query, frag, target) = association.recv().await.unwrap();
+        assert_eq!(
+            (&query[..], frag, target.clone()),
+            (&b"query"[..], 0, Address::from(("example.com", 53)))
+        );
+        association.send(b"answer", 0, target.clone()).await.unwrap();
+
+        let (pong, answer, frag, source) = client.await.unwrap();
+        assert_eq!(&pong, b"pong");
+        assert_eq!((&answer[..], frag, source), (&b"answer"[..], 0, target));
+    }
+}
@@ -0,0 +1,563 @@
+//! Relaying the bytes of a CONNECT session between the client and the target.
+//!
+//! [`relay`] copies both directions until each of them reaches its end of stream, passing the half-close of one
+//! side on to the other. On Linux, when both streams are TCP streams, the bytes are moved with `splice(2)` through
+//! a pipe, without being copied to user space; any other stream is relayed with a buffered copy.
+//!
+//! [`relay_with_stats`] also records the bytes, the first byte latency and the last activity of each direction in a
+//! [`RelayStats`], which can be read while the relay runs, and returns a [`RelaySummary`] telling which side
+//! closed first and why.
+//!
+//! ```no_run
+//! use socks5_impl::{
+//!     protocol::Address,
+//!     server::{ClientConnection, Server, auth::NoAuth, outbound::DirectConnector},
+//! };
+//! use std::sync::Arc;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let server = Server::bind("127.0.0.1:1080".parse().unwrap(), Arc::new(NoAuth)).await?;
+//! let (conn, _) = server.accept().await?;
+//! let (conn, _) = conn.authenticate().await?;
+//! if let ClientConnection::Connect(conn, target) = conn.wait_request().await? {
+//!     let (mut conn, mut outbound) = conn.connect_outbound(&DirectConnector::new(), &target, &Default::default()).await?;
+//!     let transfer = conn.relay(&mut outbound).await?;
+//!     println!("{} bytes up, {} bytes down", transfer.bytes_up, transfer.bytes_down);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(target_os = "linux")]
+use std::any::Any;
+use std::{
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+#[cfg(target_os = "linux")]
+use stream::Stream;
+#[cfg(target_os = "linux")]
+use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+
+/// The size of the buffer of each direction of a buffered copy.
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// The bytes relayed in each direction of a session.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Transfer {
+    /// The bytes sent by the client to the target.
+    pub bytes_up: u64,
+    /// The bytes sent by the target to the client.
+    pub bytes_down: u64,
+}
+
+/// An end of a relayed session.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Side {
+    Client,
+    Target,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Client => write!(f, "client"),
+            Side::Target => write!(f, "target"),
+        }
+    }
+}
+
+/// Why a direction of a relayed session ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// The sending side reached its end of stream, which was passed on to the receiving side.
+    Eof,
+    /// Reading from the sending side failed.
+    ReadError(ErrorKind),
+    /// Writing to, or shutting down, the receiving side failed.
+    WriteError(ErrorKind),
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::Eof => write!(f, "end of stream"),
+            CloseReason::ReadError(kind) => write!(f, "read error: {kind}"),
+            CloseReason::WriteError(kind) => write!(f, "write error: {kind}"),
+        }
+    }
+}
+
+/// The activity of one direction of a relayed session.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DirectionStats {
+    /// The bytes relayed.
+    pub bytes: u64,
+    /// The time from the start of the relay to the first byte relayed.
+    pub first_byte: Option<Duration>,
+    /// When bytes were last relayed.
+    pub last_activity: Option<Instant>,
+    /// The time from the start of the relay to the end of this direction, and why it ended.
+    pub closed: Option<(Duration, CloseReason)>,
+}
+
+/// The statistics of a relay, which can be read while it runs, to log its progress or enforce an idle timeout.
+#[derive(Clone, Debug)]
+pub struct RelayStats(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    started_at: Instant,
+    up: Mutex<DirectionStats>,
+    down: Mutex<DirectionStats>,
+}
+
+impl Default for RelayStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RelayStats {
+    /// Create the statistics of a relay starting now.
+    pub fn new() -> Self {
+        Self(Arc::new(Shared {
+            started_at: Instant::now(),
+            up: Mutex::default(),
+            down: Mutex::default(),
+        }))
+    }
+
+    /// Get when the relay started.
+    pub fn started_at(&self) -> Instant {
+        self.0.started_at
+    }
+
+    /// Get the activity from the client to the target.
+    pub fn up(&self) -> DirectionStats {
+        *self.0.up.lock().unwrap()
+    }
+
+    /// Get the activity from the target to the client.
+    pub fn down(&self) -> DirectionStats {
+        *self.0.down.lock().unwrap()
+    }
+
+    /// Get when bytes were last relayed in either direction, or when the relay started if none were.
+    pub fn last_activity(&self) -> Instant {
+        let (up, down) = (self.up().last_activity, self.down().last_activity);
+        up.max(down).unwrap_or(self.0.started_at)
+    }
+
+    /// Get the time since bytes were last relayed in either direction.
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity().elapsed()
+    }
+
+    fn meter(&self, side: Side) -> Meter<'_> {
+        let stats = match side {
+            Side::Client => &self.0.up,
+            Side::Target => &self.0.down,
+        };
+        Meter {
+            started_at: self.0.started_at,
+            stats,
+        }
+    }
+
+    fn summary(&self, error: Option<std::io::Error>) -> RelaySummary {
+        RelaySummary {
+            duration: self.0.started_at.elapsed(),
+            up: self.up(),
+            down: self.down(),
+            error,
+        }
+    }
+}
+
+/// The outcome of a relay.
+#[derive(Debug)]
+pub struct RelaySummary {
+    /// The time from the start to the end of the relay.
+    pub duration: Duration,
+    /// The activity from the client to the target.
+    pub up: DirectionStats,
+    /// The activity from the target to the client.
+    pub down: DirectionStats,
+    /// The error which ended the relay, if any. The other direction is then left unfinished.
+    pub error: Option<std::io::Error>,
+}
+
+impl RelaySummary {
+    /// Get the bytes relayed in each direction.
+    pub fn transfer(&self) -> Transfer {
+        Transfer {
+            bytes_up: self.up.bytes,
+            bytes_down: self.down.bytes,
+        }
+    }
+
+    /// Get the side which closed first, and why.
+    ///
+    /// A direction ended by an end of stream or a read error was closed by its sending side, a direction ended by a
+    /// write error by its receiving side.
+    pub fn first_closed(&self) -> Option<(Side, CloseReason)> {
+        let up = self.up.closed.map(|(at, reason)| (at, Side::Client, reason));
+        let down = self.down.closed.map(|(at, reason)| (at, Side::Target, reason));
+        let (_, sender, reason) = match (up, down) {
+            (Some(up), Some(down)) => std::cmp::min_by_key(up, down, |(at, ..)| *at),
+            (closed, None) | (None, closed) => closed?,
+        };
+        let side = match (sender, reason) {
+            (Side::Client, CloseReason::WriteError(_)) => Side::Target,
+            (Side::Target, CloseReason::WriteError(_)) => Side::Client,
+            (sender, _) => sender,
+        };
+        Some((side, reason))
+    }
+
+    /// Get the bytes relayed in each direction, or the error which ended the relay.
+    pub fn into_result(self) -> std::io::Result<Transfer> {
+        let transfer = self.transfer();
+        self.error.map_or(Ok(transfer), Err)
+    }
+}
+
+/// Record the activity of one direction of a relay.
+struct Meter<'a> {
+    started_at: Instant,
+    stats: &'a Mutex<DirectionStats>,
+}
+
+impl Meter<'_> {
+    fn record(&self, bytes: usize) {
+        let now = Instant::now();
+        let mut stats = self.stats.lock().unwrap();
+        stats.first_byte.get_or_insert(now - self.started_at);
+        stats.bytes += bytes as u64;
+        stats.last_activity = Some(now);
+    }
+
+    fn close(&self, reason: CloseReason) {
+        self.stats.lock().unwrap().closed = Some((self.started_at.elapsed(), reason));
+    }
+
+    /// Close this direction on `err`, returning it.
+    fn fail(&self, err: std::io::Error, reason: fn(ErrorKind) -> CloseReason) -> std::io::Error {
+        self.close(reason(err.kind()));
+        err
+    }
+}
+
+/// Relay the bytes between `client` and `target` until both directions are done.
+///
+/// When one side reaches its end of stream, the other side is shut down for writing while the opposite direction
+/// goes on. Two TCP streams, or [`Stream`](stream::Stream)s of them, are relayed with `splice(2)` on Linux.
+pub async fn relay<A, B>(client: &mut A, target: &mut B) -> std::io::Result<Transfer>
+where
+    A: AsyncRead + AsyncWrite + Unpin + 'static,
+    B: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    relay_with_stats(client, target, &RelayStats::new()).await.into_result()
+}
+
+/// Relay the bytes between `client` and `target` as [`relay`] does, recording the activity of each direction in
+/// `stats`, and summarize the relay once it is done.
+pub async fn relay_with_stats<A, B>(client: &mut A, target: &mut B, stats: &RelayStats) -> RelaySummary
+where
+    A: AsyncRead + AsyncWrite + Unpin + 'static,
+    B: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    #[cfg(target_os = "linux")]
+    if as_tcp(client).is_some() && as_tcp(target).is_some() {
+        // The bytes read ahead, such as those the client pipelined after its request, are not in the sockets anymore
+        let forwarded = async {
+            forward_buffered(client, target, stats.meter(Side::Client)).await?;
+            forward_buffered(target, client, stats.meter(Side::Target)).await
+        };
+        if let Err(err) = forwarded.await {
+            return stats.summary(Some(err));
+        }
+        if let (Some(client), Some(target)) = (as_tcp(client), as_tcp(target)) {
+            return stats.summary(splice::relay(client, target, stats).await.err());
+        }
+    }
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut target_read, mut target_write) = tokio::io::split(target);
+    let up = copy(&mut client_read, &mut target_write, stats.meter(Side::Client));
+    let down = copy(&mut target_read, &mut client_write, stats.meter(Side::Target));
+    stats.summary(tokio::try_join!(up, down).err())
+}
+
+/// Copy the bytes of `from` to `to`, then shut `to` down for writing.
+async fn copy<R, W>(from: &mut R, to: &mut W, meter: Meter<'_>) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let n = from.read(&mut buf).await.map_err(|err| meter.fail(err, CloseReason::ReadError))?;
+        if n == 0 {
+            break;
+        }
+        // Flush each chunk, so that a buffering stream such as TLS does not hold it while the next one is awaited
+        to.write_all(&buf[..n])
+            .await
+            .map_err(|err| meter.fail(err, CloseReason::WriteError))?;
+        to.flush().await.map_err(|err| meter.fail(err, CloseReason::WriteError))?;
+        meter.record(n);
+    }
+    to.shutdown().await.map_err(|err| meter.fail(err, CloseReason::WriteError))?;
+    meter.close(CloseReason::Eof);
+    Ok(())
+}
+
+/// Get the TCP stream behind `stream`, if it is one.
+#[cfg(target_os = "linux")]
+fn as_tcp<T: 'static>(stream: &T) -> Option<&TcpStream> {
+    let stream: &dyn Any = stream;
+    match stream.downcast_ref::<Stream<TcpStream>>() {
+        Some(stream) => Some(stream),
+        None => stream.downcast_ref(),
+    }
+}
+
+/// Write the bytes `from` read ahead from its TCP stream to `to`.
+#[cfg(target_os = "linux")]
+async fn forward_buffered<A: 'static, B: AsyncWrite + Unpin>(from: &mut A, to: &mut B, meter: Meter<'_>) -> std::io::Result<()> {
+    let from: &mut dyn Any = from;
+    let Some(buffered) = from.downcast_mut::<Stream<TcpStream>>().map(Stream::take_buffered) else {
+        return Ok(());
+    };
+    if !buffered.is_empty() {
+        to.write_all(&buffered)
+            .await
+            .map_err(|err| meter.fail(err, CloseReason::WriteError))?;
+        meter.record(buffered.len());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    use super::{CloseReason, Meter, RelayStats, Side};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use tokio::{io::Interest, net::TcpStream};
+
+    /// The bytes moved by a single `splice(2)` call, the default capacity of a pipe.
+    const PIPE_CAPACITY: usize = 64 * 1024;
+
+    pub(super) async fn relay(client: &TcpStream, target: &TcpStream, stats: &RelayStats) -> std::io::Result<()> {
+        let up = copy(client, target, stats.meter(Side::Client));
+        let down = copy(target, client, stats.meter(Side::Target));
+        tokio::try_join!(up, down).map(|_| ())
+    }
+
+    /// Move the bytes of `from` to `to` through a pipe, then shut `to` down for writing.
+    async fn copy(from: &TcpStream, to: &TcpStream, meter: Meter<'_>) -> std::io::Result<()> {
+        let pipe = Pipe::new().map_err(|err| meter.fail(err, CloseReason::ReadError))?;
+        loop {
+            // The pipe is empty here, so that a would-block can only come from the socket
+            let mut pending = from
+                .async_io(Interest::READABLE, || {
+                    splice(from.as_raw_fd(), pipe.write.as_raw_fd(), PIPE_CAPACITY)
+                })
+                .await
+                .map_err(|err| meter.fail(err, CloseReason::ReadError))?;
+            if pending == 0 {
+                break;
+            }
+            while pending > 0 {
+                let n = to
+                    .async_io(Interest::WRITABLE, || splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending))
+                    .await
+                    .map_err(|err| meter.fail(err, CloseReason::WriteError))?;
+                if n == 0 {
+                    return Err(meter.fail(std::io::ErrorKind::WriteZero.into(), CloseReason::WriteError));
+                }
+                pending -= n;
+                meter.record(n);
+            }
+        }
+        shutdown_write(to).map_err(|err| meter.fail(err, CloseReason::WriteError))?;
+        meter.close(CloseReason::Eof);
+        Ok(())
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> std::io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        // SAFETY: both descriptors are open for the duration of the call, and no offsets are passed
+        let n = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, flags) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn shutdown_write(stream: &TcpStream) -> std::io::Result<()> {
+        // SAFETY: the descriptor is open for the duration of the call
+        if unsafe { libc::shutdown(stream.as_raw_fd(), libc::SHUT_WR) } < 0 {
+            let err = std::io::Error::last_os_error();
+            // The peer is already gone, as with a buffered copy
+            if err.kind() != std::io::ErrorKind::NotConnected {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        fn new() -> std::io::Result<Self> {
+            let mut fds = [0; 2];
+            // SAFETY: `fds` has room for the two descriptors
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // SAFETY: the descriptors were just opened, and nothing else owns them
+            let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            Ok(Self { read, write })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::TimeLimited;
+    use stream::Stream;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Connect a TCP stream pair over the loopback.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connected = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, (accepted, _)) = tokio::try_join!(connected, listener.accept()).unwrap();
+        (connected, accepted)
+    }
+
+    #[tokio::test]
+    async fn tcp_streams_are_relayed_with_half_close() {
+        let (mut client, client_end) = tcp_pair().await;
+        let (target_end, mut target) = tcp_pair().await;
+        let relayed = tokio::spawn(async move {
+            let (mut client_end, mut target_end) = (Stream::new(client_end), target_end);
+            relay(&mut client_end, &mut target_end).await
+        });
+
+        let request = vec![7; 300 * 1024];
+        client.write_all(&request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        target.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, request);
+
+        // The target still answers once the client is done sending
+        target.write_all(b"response").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let transfer = relayed.await.unwrap().unwrap();
+        assert_eq!(
+            transfer,
+            Transfer {
+                bytes_up: 300 * 1024,
+                bytes_down: 8
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn other_streams_are_copied() {
+        let (mut client, mut client_end) = tokio::io::duplex(1024);
+        let (mut target_end, mut target) = tcp_pair().await;
+        let relayed = tokio::spawn(async move { relay(&mut client_end, &mut target_end).await });
+
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut ping = Vec::new();
+        target.read_to_end(&mut ping).await.unwrap();
+        assert_eq!(ping, b"ping");
+        target.write_all(b"pong").await.unwrap();
+        drop(target);
+        let mut pong = Vec::new();
+        client.read_to_end(&mut pong).await.unwrap();
+        assert_eq!(pong, b"pong");
+
+        let transfer = relayed.await.unwrap().unwrap();
+        assert_eq!(
+            transfer,
+            Transfer {
+                bytes_up: 4,
+                bytes_down: 4
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn summary_tells_who_closed_first() {
+        for buffered in [false, true] {
+            let (mut client, client_end) = tcp_pair().await;
+            let (target_end, mut target) = tcp_pair().await;
+            let stats = RelayStats::new();
+            let relayed = tokio::spawn({
+                let stats = stats.clone();
+                async move {
+                    if buffered {
+                        let (mut client_end, mut target_end) = (TimeLimited::new(client_end, Default::default()), target_end);
+                        relay_with_stats(&mut client_end, &mut target_end, &stats).await
+                    } else {
+                        let (mut client_end, mut target_end) = (client_end, target_end);
+                        relay_with_stats(&mut client_end, &mut target_end, &stats).await
+                    }
+                }
+            });
+
+            target.write_all(b"banner").await.unwrap();
+            let mut banner = [0; 6];
+            client.read_exact(&mut banner).await.unwrap();
+            while stats.down().bytes < 6 {
+                tokio::task::yield_now().await;
+            }
+            assert!(stats.down().first_byte.is_some());
+            assert_eq!(stats.up().first_byte, None);
+            assert!(stats.idle_time() < Duration::from_secs(10));
+
+            // The target closes first, the client answers before closing too
+            target.shutdown().await.unwrap();
+            assert_eq!(client.read(&mut banner).await.unwrap(), 0);
+            client.write_all(b"bye").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut bye = Vec::new();
+            target.read_to_end(&mut bye).await.unwrap();
+            assert_eq!(bye, b"bye");
+
+            let summary = relayed.await.unwrap();
+            assert!(summary.error.is_none());
+            assert_eq!(summary.first_closed(), Some((Side::Target, CloseReason::Eof)));
+            assert_eq!(
+                summary.transfer(),
+                Transfer {
+                    bytes_up: 3,
+                    bytes_down: 6
+                }
+            );
+            assert_eq!(summary.up.closed.map(|(_, reason)| reason), Some(CloseReason::Eof));
+        }
+    }
+}
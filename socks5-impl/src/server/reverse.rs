@@ -0,0 +1,352 @@
+//! Reverse SOCKS5, behind the `mux` feature.
+//!
+//! A server which cannot accept connections, such as one in a NATed site, keeps outbound control connections to a
+//! public [`ReverseRelay`] instead: the relay accepts the SOCKS5 clients and tunnels each of their connections back
+//! as a [`mux`](crate::mux) stream over one of the control connections, where a [`ReverseListener`] hands it over
+//! to the [`Server`] as any other connection.
+//!
+//! The relay does not speak SOCKS5 itself, the handshake, the authentication and the requests are all handled at
+//! the site. Before the bytes of the client, the relay sends the address of the client and the address the client
+//! connected to, so that the [`SessionContext`] of the session, and the connection limits, see the actual client.
+//!
+//! The relay trusts any control connection accepted by its site acceptor; wrap it in a `TlsListener` requiring
+//! client certificates, or only expose it to the sites, when the relay is public.
+//!
+//! ```no_run
+//! use socks5_impl::server::{ReverseRelay, Server, auth::NoAuth};
+//! use std::sync::Arc;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! // On the public host
+//! let relay = ReverseRelay::bind("0.0.0.0:9000".parse().unwrap(), "0.0.0.0:1080".parse().unwrap()).await?;
+//!
+//! // At the site
+//! let server = Server::connect_reverse("203.0.113.1:9000".parse().unwrap(), Arc::new(NoAuth));
+//! let (conn, client_addr) = server.accept().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    mux::{Mode, MuxSession, MuxStream},
+    protocol::{Address, StreamOperation},
+    server::{AuthAdaptor, Server, context::SessionContext, transport::Acceptor},
+};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// The time the relay has to send the addresses of a tunneled client.
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The delay before dialing the relay again, doubled after each failure up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The delay before accepting again after an error, such as running out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The interval at which both ends ping each other over a control connection, which is closed once a ping is not
+/// answered within it.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+type Accepted = std::io::Result<(MuxStream, SessionContext)>;
+
+/// An [`Acceptor`] of the connections tunneled by a [`ReverseRelay`], over control connections dialed to it.
+///
+/// Each control connection is dialed again whenever it closes or fails to connect, until the listener is dropped.
+/// A control connection whose relay stops answering pings, every 30 seconds, is closed and dialed again.
+#[derive(Debug)]
+pub struct ReverseListener {
+    accepted: Mutex<mpsc::Receiver<Accepted>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ReverseListener {
+    /// Keep `links` control connections, each established by `dial`, to a relay.
+    ///
+    /// `dial` may wrap the connections in TLS. This must be called from within a tokio runtime, which drives the
+    /// links.
+    pub fn new<F, Fut, S>(dial: F, links: usize) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (sender, accepted) = mpsc::channel(64);
+        let dial = Arc::new(dial);
+        let tasks = (0..links.max(1))
+            .map(|_| tokio::spawn(keep_link(dial.clone(), sender.clone())))
+            .collect();
+        Self {
+            accepted: Mutex::new(accepted),
+            tasks,
+        }
+    }
+
+    /// Keep one TCP control connection to the relay at `relay`.
+    pub fn connect(relay: SocketAddr) -> Self {
+        Self::new(move || TcpStream::connect(relay), 1)
+    }
+}
+
+impl Drop for ReverseListener {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+impl Acceptor for ReverseListener {
+    type Stream = MuxStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Accepted> {
+        match std::task::ready!(self.accepted.lock().unwrap().poll_recv(cx)) {
+            Some(accepted) => Poll::Ready(accepted),
+            None => Poll::Ready(Err(std::io::Error::other("reverse listener is closed"))),
+        }
+    }
+}
+
+async fn keep_link<F, Fut, S>(dial: Arc<F>, sender: mpsc::Sender<Accepted>)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = std::io::Result<S>>,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match dial().await {
+            Ok(link) => {
+                backoff = MIN_BACKOFF;
+                let session = MuxSession::new(link, Mode::Client).with_keepalive(KEEPALIVE_INTERVAL);
+                while let Ok(stream) = session.accept().await {
+                    let sender = sender.clone();
+                    tokio::spawn(async move {
+                        match tokio::time::timeout(PREAMBLE_TIMEOUT, read_preamble(stream)).await {
+                            Ok(Ok(accepted)) => {
+                                let _ = sender.send(Ok(accepted)).await;
+                            }
+                            Ok(Err(err)) => log::debug!("reverse tunnel failed: {err}"),
+                            Err(_) => log::debug!("reverse tunnel timed out"),
+                        }
+                    });
+                }
+                if sender.is_closed() {
+                    return;
+                }
+                log::debug!("reverse link closed");
+            }
+            Err(err) => log::warn!("reverse link failed to connect: {err}"),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn read_preamble(mut stream: MuxStream) -> Accepted {
+    let peer_addr = SocketAddr::try_from(Address::retrieve_from_async_stream(&mut stream).await?)?;
+    let local_addr = SocketAddr::try_from(Address::retrieve_from_async_stream(&mut stream).await?)?;
+    Ok((stream, SessionContext::new(peer_addr, local_addr)))
+}
+
+impl<O: 'static> Server<O, ReverseListener> {
+    /// Create a new socks5 server serving the clients of the relay at `relay`, over a TCP control connection.
+    ///
+    /// The address returned by [`accept`](Self::accept) is the address of the client at the relay.
+    pub fn connect_reverse(relay: SocketAddr, auth: AuthAdaptor<O>) -> Self {
+        Self::from_acceptor(ReverseListener::connect(relay), auth)
+    }
+}
+
+/// The public end of reverse SOCKS5: it accepts the control connections of the sites on one acceptor, the SOCKS5
+/// clients on another, and tunnels each client to a site.
+///
+/// The clients are spread over the control connections in turn; a client arriving while no site is connected is
+/// disconnected. A control connection whose site stops answering pings is closed. The relay stops when it is
+/// dropped.
+#[derive(Debug)]
+pub struct ReverseRelay<S: Acceptor = TcpListener, C: Acceptor = TcpListener> {
+    sites: Arc<S>,
+    clients: Arc<C>,
+    links: Arc<Links>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+/// The control connections of the sites connected to a relay.
+#[derive(Debug, Default)]
+struct Links {
+    sessions: Mutex<Vec<MuxSession>>,
+    next: AtomicUsize,
+}
+
+impl Links {
+    /// Open a stream to one of the sites, dropping the control connections which are closed.
+    fn open(&self) -> Option<MuxStream> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|session| !session.is_closed());
+        if sessions.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed) % sessions.len();
+        sessions[next].open().ok()
+    }
+}
+
+impl<S, C> ReverseRelay<S, C>
+where
+    S: Acceptor + Send + Sync + 'static,
+    C: Acceptor + Send + Sync + 'static,
+{
+    /// Accept the control connections of the sites from `sites`, and the SOCKS5 clients from `clients`.
+    ///
+    /// This must be called from within a tokio runtime, which drives the tunnels.
+    pub fn new(sites: S, clients: C) -> Self {
+        let (sites, clients) = (Arc::new(sites), Arc::new(clients));
+        let links = Arc::new(Links::default());
+        let tasks = [
+            tokio::spawn(accept_sites(sites.clone(), links.clone())),
+            tokio::spawn(accept_clients(clients.clone(), links.clone())),
+        ];
+        Self {
+            sites,
+            clients,
+            links,
+            tasks,
+        }
+    }
+}
+
+impl ReverseRelay {
+    /// Accept the control connections of the sites on `sites`, and the SOCKS5 clients on `clients`, over TCP.
+    pub async fn bind(sites: SocketAddr, clients: SocketAddr) -> std::io::Result<Self> {
+        Ok(Self::new(TcpListener::bind(sites).await?, TcpListener::bind(clients).await?))
+    }
+}
+
+impl<S: Acceptor, C: Acceptor> ReverseRelay<S, C> {
+    /// Get a reference to the acceptor of the control connections.
+    pub fn sites(&self) -> &S {
+        &self.sites
+    }
+
+    /// Get a reference to the acceptor of the SOCKS5 clients.
+    pub fn clients(&self) -> &C {
+        &self.clients
+    }
+
+    /// Get the number of control connections currently open.
+    pub fn links(&self) -> usize {
+        let sessions = self.links.sessions.lock().unwrap();
+        sessions.iter().filter(|session| !session.is_closed()).count()
+    }
+}
+
+impl<S: Acceptor, C: Acceptor> Drop for ReverseRelay<S, C> {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+async fn accept_sites<S: Acceptor>(sites: Arc<S>, links: Arc<Links>) {
+    loop {
+        match std::future::poll_fn(|cx| sites.poll_accept(cx)).await {
+            Ok((link, ctx)) => {
+                log::debug!("reverse link from {}", ctx.peer_addr);
+                let session = MuxSession::new(link, Mode::Server).with_keepalive(KEEPALIVE_INTERVAL);
+                links.sessions.lock().unwrap().push(session);
+            }
+            Err(err) => {
+                log::debug!("failed to accept a reverse link: {err}");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn accept_clients<C: Acceptor>(clients: Arc<C>, links: Arc<Links>) {
+    loop {
+        let (mut stream, ctx) = match std::future::poll_fn(|cx| clients.poll_accept(cx)).await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::debug!("failed to accept a client: {err}");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let Some(mut tunnel) = links.open() else {
+            log::warn!("no reverse link for the client {}", ctx.peer_addr);
+            continue;
+        };
+        tokio::spawn(async move {
+            let mut preamble = Vec::new();
+            Address::from(ctx.peer_addr).write_to_buf(&mut preamble);
+            Address::from(ctx.local_addr).write_to_buf(&mut preamble);
+            let relayed = async {
+                tunnel.write_all(&preamble).await?;
+                tokio::io::copy_bidirectional(&mut stream, &mut tunnel).await
+            };
+            if let Err(err) = relayed.await {
+                log::debug!("reverse tunnel for {} closed: {err}", ctx.peer_addr);
+            }
+        });
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::Reply,
+        server::{ClientConnection, auth::NoAuth},
+    };
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn clients_are_tunneled_to_the_site() {
+        let relay = ReverseRelay::bind("127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server = Server::connect_reverse(relay.sites().local_addr().unwrap(), Arc::new(NoAuth));
+        while relay.links() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut stream = TcpStream::connect(relay.clients().local_addr().unwrap()).await.unwrap();
+        let client_addr = stream.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let bound = crate::client::connect(&mut stream, ("example.com", 80), None).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut pong = [0; 4];
+            stream.read_exact(&mut pong).await.unwrap();
+            (bound, pong)
+        });
+
+        let (conn, peer_addr) = server.accept().await.unwrap();
+        assert_eq!(peer_addr, client_addr);
+        assert_eq!(conn.context().local_addr, relay.clients().local_addr().unwrap());
+        let (conn, _) = conn.authenticate().await.unwrap();
+        let ClientConnection::Connect(conn, target) = conn.wait_request().await.unwrap() else {
+            panic!("not a CONNECT request");
+        };
+        assert_eq!(target, Address::from(("example.com", 80)));
+        let mut conn = conn.reply(Reply::Succeeded, Address::unspecified()).await.unwrap();
+        let mut ping = [0; 4];
+        conn.stream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        conn.stream.write_all(b"pong").await.unwrap();
+        assert_eq!(client.await.unwrap(), (Address::unspecified(), *b"pong"));
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{
+    protocol::{Address, AuthMethod, Command, Reply},
+    server::{
+        accounting::Accounting,
+        context::SessionContext,
+        limits::{ConnectionLimiter, Permit},
+        metrics::{ActiveSession, Metrics},
+        observer::{ObserverAdaptor, SessionSummary},
+    },
+};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// The protocol spoken by the client, in which the replies are written.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) enum Dialect {
+    #[default]
+    Socks5,
+    Socks4,
+    /// An HTTP `CONNECT` tunnel.
+    HttpConnect,
+    /// A plain HTTP request, forwarded to the origin server.
+    HttpForward,
+}
+
+/// The state a connection carries from its acceptance to the end of the session.
+pub(crate) struct Session {
+    context: SessionContext,
+    dialect: Dialect,
+    limiter: Option<ConnectionLimiter>,
+    accounting: Option<Accounting>,
+    permits: Vec<Permit>,
+    metrics: Option<Metrics>,
+    _active: Option<ActiveSession>,
+    observers: Vec<ObserverAdaptor>,
+    started: Instant,
+    summary: SessionSummary,
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session").field("context", &self.context).finish()
+    }
+}
+
+impl Session {
+    pub(crate) fn new(context: SessionContext) -> Self {
+        Self {
+            context,
+            dialect: Dialect::Socks5,
+            limiter: None,
+            accounting: None,
+            permits: Vec::new(),
+            metrics: None,
+            _active: None,
+            observers: Vec::new(),
+            started: Instant::now(),
+            summary: SessionSummary::default(),
+        }
+    }
+
+    pub(crate) fn with_limiter(mut self, limiter: ConnectionLimiter, permits: Vec<Permit>) -> Self {
+        self.limiter = Some(limiter);
+        self.permits = permits;
+        self
+    }
+
+    pub(crate) fn with_accounting(mut self, accounting: Accounting) -> Self {
+        self.accounting = Some(accounting);
+        self
+    }
+
+    pub(crate) fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self._active = Some(metrics.session());
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub(crate) fn with_observer(mut self, observer: ObserverAdaptor) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    pub(crate) fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    pub(crate) fn context(&self) -> &SessionContext {
+        &self.context
+    }
+
+    pub(crate) fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    pub(crate) fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    pub(crate) fn set_auth_method(&mut self, method: AuthMethod) {
+        self.context.auth_method = Some(method);
+    }
+
+    pub(crate) fn on_accepted(&self) {
+        for observer in &self.observers {
+            observer.on_accepted(&self.context);
+        }
+    }
+
+    pub(crate) fn on_handshake(&self, duration: Duration, failure: Option<&str>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_handshake(duration);
+            if let Some(reason) = failure {
+                metrics.auth_failure(reason);
+            }
+        }
+        for observer in &self.observers {
+            observer.on_handshake(&self.context, failure);
+        }
+    }
+
+    pub(crate) fn on_auth_failure(&self, reason: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.auth_failure(reason);
+        }
+    }
+
+    pub(crate) fn on_request(&self, command: Command, target: &Address) {
+        if let Some(metrics) = &self.metrics {
+            metrics.request(command);
+        }
+        for observer in &self.observers {
+            observer.on_request(&self.context, command, target);
+        }
+    }
+
+    pub(crate) fn on_connected(&self, target: &Address, remote: Option<SocketAddr>, latency: Duration) {
+        for observer in &self.observers {
+            observer.on_connected(&self.context, target, remote, latency);
+        }
+    }
+
+    pub(crate) fn on_reply(&self, reply: Reply) {
+        if let Some(metrics) = &self.metrics {
+            metrics.reply(reply);
+        }
+        for observer in &self.observers {
+            observer.on_reply(&self.context, reply);
+        }
+    }
+
+    pub(crate) fn record_transfer(&mut self, bytes_up: u64, bytes_down: u64) {
+        self.summary.bytes_up += bytes_up;
+        self.summary.bytes_down += bytes_down;
+    }
+
+    /// Record the error ending the session, keeping the first one.
+    pub(crate) fn record_error(&mut self, err: &dyn std::fmt::Display) {
+        self.summary.error.get_or_insert_with(|| err.to_string());
+    }
+
+    /// Record the authenticated user, whose caps and quotas are checked by [`admit_request`](Self::admit_request).
+    ///
+    /// Without one, the user is the one named by the TLS client certificate, if any.
+    pub(crate) fn set_user(&mut self, user: Option<String>) {
+        self.context.identity = user.or_else(|| self.context.tls_identity.clone());
+    }
+
+    /// Check the per user caps and quotas for a request, taking the slots it needs.
+    pub(crate) fn admit_request(&mut self, command: Command) -> std::io::Result<()> {
+        let Some(user) = &self.context.identity else {
+            return Ok(());
+        };
+        if let Some(accounting) = &self.accounting {
+            accounting.check(user)?;
+        }
+        if let Some(limiter) = &self.limiter {
+            let mut permits = vec![limiter.acquire_user(user)?];
+            if command == Command::UdpAssociate {
+                permits.push(limiter.acquire_udp_association(user)?);
+            }
+            self.permits.extend(permits);
+        }
+        if let Some(accounting) = &self.accounting {
+            accounting.record_connection(user);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.summary.duration = self.started.elapsed();
+        for observer in &self.observers {
+            observer.on_closed(&self.context, &self.summary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{ConnectionLimits, Quota};
+
+    fn context() -> SessionContext {
+        let addr = "127.0.0.1:1080".parse().unwrap();
+        SessionContext::new(addr, addr)
+    }
+
+    #[test]
+    fn per_user_limits() {
+        let limits = ConnectionLimits::new()
+            .with_max_sessions_per_user(2)
+            .with_max_udp_associations_per_user(1);
+        let limiter = ConnectionLimiter::new(limits);
+        let session = || Session::new(context()).with_limiter(limiter.clone(), Vec::new());
+
+        let mut udp = session();
+        udp.set_user(Some("alice".into()));
+        udp.admit_request(Command::UdpAssociate).unwrap();
+
+        let mut second = session();
+        second.set_user(Some("alice".into()));
+        assert!(second.admit_request(Command::UdpAssociate).is_err());
+        assert_eq!(limiter.sessions_of_user("alice"), 1);
+        second.admit_request(Command::Connect).unwrap();
+        assert!(limiter.acquire_user("alice").is_err());
+
+        let mut anonymous = session();
+        anonymous.admit_request(Command::UdpAssociate).unwrap();
+        drop((udp, second));
+        assert_eq!(limiter.sessions_of_user("alice"), 0);
+    }
+
+    #[test]
+    fn over_quota_user_is_rejected() {
+        let accounting = Accounting::new().with_default_quota(Quota::new().with_daily_bytes(100));
+        let mut session = Session::new(context()).with_accounting(accounting.clone());
+        session.set_user(Some("alice".into()));
+        session.admit_request(Command::Connect).unwrap();
+        accounting.account("alice").add_download(100);
+
+        let err = session.admit_request(Command::Bind).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::QuotaExceeded);
+        assert_eq!(accounting.usage("alice").unwrap().daily.connections, 1);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{Notify, watch},
+    task::AbortHandle,
+};
+
+/// A handle to gracefully shut down a [`Server`](crate::server::Server) and drain its sessions.
+///
+/// Sessions spawned with [`spawn`](Self::spawn) are tracked. On [`shutdown`](Self::shutdown), the server stops accepting,
+/// every [`ShutdownSignal`] fires, and tracked sessions get until the deadline to finish before they are aborted.
+///
+/// # Example
+/// ```no_run
+/// use socks5_impl::server::{Server, auth::NoAuth};
+/// use std::{sync::Arc, time::Duration};
+///
+/// # #[tokio::main]
+/// # async fn main() -> std::io::Result<()> {
+/// let server = Server::bind("127.0.0.1:1080".parse().unwrap(), Arc::new(NoAuth)).await?;
+/// let shutdown = server.shutdown_handle();
+///
+/// let handle = shutdown.clone();
+/// tokio::spawn(async move {
+///     tokio::signal::ctrl_c().await.unwrap();
+///     let report = handle.shutdown(Duration::from_secs(30)).await;
+///     println!("{} sessions drained, {} aborted", report.drained, report.aborted);
+/// });
+///
+/// while let Ok((conn, _)) = server.accept().await {
+///     shutdown.spawn(async move {
+///         let _ = conn.authenticate().await;
+///         // handle the session
+///     });
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    signal: watch::Sender<bool>,
+    sessions: Mutex<HashMap<u64, AbortHandle>>,
+    next_id: AtomicU64,
+    completed: AtomicUsize,
+    idle: Notify,
+}
+
+/// The outcome of a [`ShutdownHandle::shutdown`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DrainReport {
+    /// Sessions that finished on their own before the deadline.
+    pub drained: usize,
+    /// Sessions that were still running at the deadline and got aborted.
+    pub aborted: usize,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        let shared = Shared {
+            signal: watch::Sender::new(false),
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            completed: AtomicUsize::new(0),
+            idle: Notify::new(),
+        };
+        Self { shared: Arc::new(shared) }
+    }
+
+    /// Spawn a session onto the tokio runtime and track it for draining.
+    pub fn spawn<F>(&self, session: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let shared = self.shared.clone();
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        // Hold the lock until the handle is registered, so a session that finishes immediately still finds itself.
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        let task = tokio::spawn(async move {
+            session.await;
+            let mut sessions = shared.sessions.lock().unwrap();
+            sessions.remove(&id);
+            shared.completed.fetch_add(1, Ordering::Relaxed);
+            if sessions.is_empty() {
+                shared.idle.notify_waiters();
+            }
+        });
+        sessions.insert(id, task.abort_handle());
+    }
+
+    /// A signal that fires when the shutdown starts.
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.shared.signal.subscribe())
+    }
+
+    /// Returns `true` once [`shutdown`](Self::shutdown) has been called.
+    pub fn is_shutdown(&self) -> bool {
+        *self.shared.signal.borrow()
+    }
+
+    /// The number of tracked sessions that are still running.
+    pub fn active_sessions(&self) -> usize {
+        self.shared.sessions.lock().unwrap().len()
+    }
+
+    /// Stop accepting, signal in-flight sessions and wait up to `deadline` for them to finish.
+    ///
+    /// Sessions still running when the deadline expires are aborted, which closes their sockets.
+    pub async fn shutdown(&self, deadline: Duration) -> DrainReport {
+        let completed_before = self.shared.completed.load(Ordering::Relaxed);
+        self.shared.signal.send_replace(true);
+
+        let _ = tokio::time::timeout(deadline, async {
+            loop {
+                let idle = self.shared.idle.notified();
+                if self.active_sessions() == 0 {
+                    break;
+                }
+                idle.await;
+            }
+        })
+        .await;
+
+        let remaining: Vec<_> = self.shared.sessions.lock().unwrap().drain().map(|(_, task)| task).collect();
+        for task in &remaining {
+            task.abort();
+        }
+        let drained = self.shared.completed.load(Ordering::Relaxed) - completed_before;
+        DrainReport {
+            drained,
+            aborted: remaining.len(),
+        }
+    }
+}
+
+/// Fires when the shutdown of the server starts.
+///
+/// In-flight sessions can wait on it to wind down early, e.g. by not starting new transfers.
+#[derive(Clone, Debug)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    /// Returns `true` once the shutdown has started.
+    pub fn is_shutdown(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until the shutdown starts.
+    pub async fn recv(&mut self) {
+        // An error means the handle is gone, so no shutdown can ever be signaled.
+        if self.0.wait_for(|shutdown| *shutdown).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Server, auth::NoAuth};
+
+    #[tokio::test]
+    async fn drains_then_aborts() {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(NoAuth)).await.unwrap();
+        let shutdown = server.shutdown_handle();
+
+        let mut signal = shutdown.signal();
+        shutdown.spawn(async move {
+            signal.recv().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        });
+        shutdown.spawn(std::future::pending());
+        shutdown.spawn(async {});
+        tokio::task::yield_now().await;
+
+        let accept = tokio::spawn(async move { server.accept().await.map(|_| ()) });
+        let report = shutdown.shutdown(Duration::from_millis(200)).await;
+        assert_eq!(report, DrainReport { drained: 1, aborted: 1 });
+        assert_eq!(shutdown.active_sessions(), 0);
+        assert!(shutdown.is_shutdown());
+        assert!(accept.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,492 @@
+//! Bandwidth throttling of relayed traffic with token buckets.
+//!
+//! A [`Throttler`] holds the global and per user buckets and hands out a [`Throttle`] for every session,
+//! which adds a bucket of its own for the per connection limit. The [`Throttle`] is then applied with a
+//! [`Throttled`] wrapper around the client [`Stream`](stream::Stream), and with
+//! [`AssociatedUdpSocket::with_throttle`](crate::server::AssociatedUdpSocket::with_throttle) for UDP.
+//!
+//! Directions are seen from the client: *upload* is what the client sends, *download* what it receives.
+//!
+//! The sessions sharing a bucket, e.g. the sessions of one user, take turns with a fair share of its burst,
+//! so one busy session cannot starve the others.
+//!
+//! ```rust
+//! use socks5_impl::server::throttle::{BandwidthLimits, RateLimit, ThrottleConfig, Throttled, Throttler};
+//!
+//! let config = ThrottleConfig {
+//!     user: BandwidthLimits::new().with_download(RateLimit::new(1 << 20)).with_upload(RateLimit::new(256 << 10)),
+//!     global: BandwidthLimits::new().with_download(RateLimit::new(100 << 20)),
+//!     ..Default::default()
+//! };
+//! let throttler = Throttler::new(config);
+//! # async fn relay(throttler: Throttler, stream: stream::Stream) {
+//! let mut stream = Throttled::new(stream, throttler.throttle(Some("alice")));
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// A rate in bytes per second, with the burst of bytes allowed at once.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct RateLimit {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// A rate of `bytes_per_second`, with a burst of one second worth of traffic.
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            burst: bytes_per_second,
+        }
+    }
+
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+/// Upload and download rates of one level of throttling.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct BandwidthLimits {
+    pub upload: Option<RateLimit>,
+    pub download: Option<RateLimit>,
+}
+
+impl BandwidthLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_upload(mut self, limit: RateLimit) -> Self {
+        self.upload = Some(limit);
+        self
+    }
+
+    pub fn with_download(mut self, limit: RateLimit) -> Self {
+        self.download = Some(limit);
+        self
+    }
+}
+
+/// The per connection, per user and global bandwidth limits of a [`Throttler`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ThrottleConfig {
+    pub connection: BandwidthLimits,
+    pub user: BandwidthLimits,
+    pub global: BandwidthLimits,
+}
+
+/// A token bucket, refilled at the rate of its [`RateLimit`] up to its burst.
+///
+/// Cloning gives another handle to the same bucket.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    state: Arc<Mutex<BucketState>>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+    sharers: usize,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    pub fn new(limit: RateLimit) -> Self {
+        let state = BucketState {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+            sharers: 0,
+        };
+        let state = Arc::new(Mutex::new(state));
+        Self { limit, state }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// The number of sessions currently drawing from this bucket.
+    pub fn sharers(&self) -> usize {
+        self.state.lock().unwrap().sharers
+    }
+
+    /// Returns the tokens available now, and the fair share of one session.
+    fn available(&self) -> (f64, f64) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.limit.bytes_per_second as f64).min(self.limit.burst as f64);
+        state.updated = now;
+        let share = (self.limit.burst as f64 / state.sharers.max(1) as f64).max(1.0);
+        (state.tokens, share)
+    }
+
+    /// Whether no session draws from the bucket and it refilled up to its burst, like a new bucket.
+    fn is_idle(&self) -> bool {
+        let (tokens, _) = self.available();
+        self.sharers() == 0 && tokens >= self.limit.burst as f64
+    }
+
+    /// The time until `tokens` more tokens are available.
+    fn time_to(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64(tokens / self.limit.bytes_per_second.max(1) as f64)
+    }
+
+    /// Take `tokens` from the bucket, which may go into debt. Negative values give tokens back.
+    fn consume(&self, tokens: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.tokens = (state.tokens - tokens).min(self.limit.burst as f64);
+    }
+}
+
+/// A session registered on a bucket, counted for the fair share until dropped.
+#[derive(Debug)]
+struct Share(TokenBucket);
+
+impl Share {
+    fn new(bucket: &TokenBucket) -> Self {
+        bucket.state.lock().unwrap().sharers += 1;
+        Self(bucket.clone())
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().sharers -= 1;
+    }
+}
+
+/// The buckets one session draws from, for both directions.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    upload: Vec<Share>,
+    download: Vec<Share>,
+}
+
+impl Throttle {
+    /// A throttle without any limit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also draw uploaded bytes from `bucket`.
+    pub fn with_upload_bucket(mut self, bucket: &TokenBucket) -> Self {
+        self.upload.push(Share::new(bucket));
+        self
+    }
+
+    /// Also draw downloaded bytes from `bucket`.
+    pub fn with_download_bucket(mut self, bucket: &TokenBucket) -> Self {
+        self.download.push(Share::new(bucket));
+        self
+    }
+
+    /// Wait until `len` uploaded bytes may pass. Used for datagrams, which cannot be split.
+    pub async fn consume_upload(&self, len: usize) {
+        consume(&self.upload, len).await
+    }
+
+    /// Wait until `len` downloaded bytes may pass. Used for datagrams, which cannot be split.
+    pub async fn consume_download(&self, len: usize) {
+        consume(&self.download, len).await
+    }
+}
+
+async fn consume(shares: &[Share], len: usize) {
+    loop {
+        let wait = shares
+            .iter()
+            .map(|Share(bucket)| match bucket.available().0 {
+                tokens if tokens < 0.0 => bucket.time_to(-tokens),
+                _ => Duration::ZERO,
+            })
+            .max()
+            .unwrap_or_default();
+        if wait.is_zero() {
+            shares.iter().for_each(|Share(bucket)| bucket.consume(len as f64));
+            return;
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Poll for a grant of at most `want` bytes from every bucket of `shares`.
+fn poll_grant(shares: &[Share], sleep: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+    if shares.is_empty() || want == 0 {
+        return Poll::Ready(want);
+    }
+    loop {
+        if let Some(timer) = sleep.as_mut() {
+            ready!(timer.as_mut().poll(cx));
+            *sleep = None;
+        }
+        let mut grant = want as f64;
+        let mut wait = None;
+        for Share(bucket) in shares {
+            let (tokens, share) = bucket.available();
+            if tokens < 1.0 {
+                // The wait may round down to zero, but zero bytes must not be granted
+                wait = wait.max(Some(bucket.time_to(1.0 - tokens)));
+            }
+            grant = grant.min(tokens).min(share);
+        }
+        let Some(wait) = wait else {
+            let grant = grant.floor() as usize;
+            shares.iter().for_each(|Share(bucket)| bucket.consume(grant as f64));
+            return Poll::Ready(grant);
+        };
+        *sleep = Some(Box::pin(tokio::time::sleep(wait)));
+    }
+}
+
+fn refund(shares: &[Share], unused: usize) {
+    if unused > 0 {
+        shares.iter().for_each(|Share(bucket)| bucket.consume(-(unused as f64)));
+    }
+}
+
+/// Hands out the [`Throttle`] of every session, sharing the global and per user buckets.
+///
+/// The buckets of a user are only dropped once no session uses them and they refilled, so reconnecting
+/// does not reset the limits of a user.
+#[derive(Clone, Debug, Default)]
+pub struct Throttler {
+    shared: Arc<ThrottlerShared>,
+}
+
+#[derive(Debug, Default)]
+struct ThrottlerShared {
+    config: ThrottleConfig,
+    global: Buckets,
+    users: Mutex<HashMap<String, Buckets>>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(limits: &BandwidthLimits) -> Self {
+        Self {
+            upload: limits.upload.map(TokenBucket::new),
+            download: limits.download.map(TokenBucket::new),
+        }
+    }
+
+    fn add_to(&self, mut throttle: Throttle) -> Throttle {
+        if let Some(bucket) = &self.upload {
+            throttle = throttle.with_upload_bucket(bucket);
+        }
+        if let Some(bucket) = &self.download {
+            throttle = throttle.with_download_bucket(bucket);
+        }
+        throttle
+    }
+
+    fn is_idle(&self) -> bool {
+        [&self.upload, &self.download].into_iter().flatten().all(TokenBucket::is_idle)
+    }
+}
+
+impl Throttler {
+    pub fn new(config: ThrottleConfig) -> Self {
+        let shared = ThrottlerShared {
+            config,
+            global: Buckets::new(&config.global),
+            users: Mutex::new(HashMap::new()),
+        };
+        Self { shared: Arc::new(shared) }
+    }
+
+    pub fn config(&self) -> &ThrottleConfig {
+        &self.shared.config
+    }
+
+    /// Create the [`Throttle`] of a new session of `user`, or of an anonymous session.
+    pub fn throttle(&self, user: Option<&str>) -> Throttle {
+        let shared = &self.shared;
+        let mut throttle = Buckets::new(&shared.config.connection).add_to(Throttle::new());
+        if let Some(user) = user {
+            let mut users = shared.users.lock().unwrap();
+            users.retain(|_, buckets| !buckets.is_idle());
+            let buckets = users.entry(user.to_owned()).or_insert_with(|| Buckets::new(&shared.config.user));
+            throttle = buckets.add_to(throttle);
+        }
+        shared.global.add_to(throttle)
+    }
+}
+
+/// Throttles the traffic of the client stream `S`, whose reads are uploads and writes downloads, see
+/// [`Accounted`](crate::server::accounting::Accounted).
+#[derive(Debug)]
+pub struct Throttled<S> {
+    inner: S,
+    throttle: Throttle,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, throttle: Throttle) -> Self {
+        Self {
+            inner,
+            throttle,
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let grant = ready!(poll_grant(&this.throttle.upload, &mut this.read_sleep, cx, buf.remaining()));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(grant));
+        let res = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let filled = limited.filled().len();
+        refund(&this.throttle.upload, grant - filled);
+        ready!(res)?;
+        buf.advance(filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let grant = ready!(poll_grant(&this.throttle.download, &mut this.write_sleep, cx, buf.len()));
+        let res = Pin::new(&mut this.inner).poll_write(cx, &buf[..grant]);
+        let written = match &res {
+            Poll::Ready(Ok(written)) => *written,
+            _ => 0,
+        };
+        refund(&this.throttle.download, grant - written);
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn stream_is_throttled() {
+        let config = ThrottleConfig {
+            connection: BandwidthLimits::new().with_download(RateLimit::new(20_000).with_burst(2_000)),
+            ..Default::default()
+        };
+        let throttler = Throttler::new(config);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = Throttled::new(server, throttler.throttle(None));
+
+        let start = std::time::Instant::now();
+        let writer = tokio::spawn(async move {
+            server.write_all(&[7; 10_000]).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+        let mut client = client;
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+
+        assert_eq!(received, vec![7; 10_000]);
+        // 2 000 bytes of burst, then 8 000 bytes at 20 000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(350), "{:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn user_bucket_is_shared() {
+        let config = ThrottleConfig {
+            user: BandwidthLimits::new().with_upload(RateLimit::new(10_000).with_burst(1_000)),
+            ..Default::default()
+        };
+        let throttler = Throttler::new(config);
+        let first = throttler.throttle(Some("alice"));
+        let second = throttler.throttle(Some("alice"));
+        let other = throttler.throttle(Some("bob"));
+        assert_eq!(first.upload[0].0.sharers(), 2);
+
+        let start = std::time::Instant::now();
+        // 1 000 bytes of burst, then 2 000 bytes in debt paid back at 10 000 bytes per second
+        first.consume_upload(1_500).await;
+        second.consume_upload(1_500).await;
+        first.consume_upload(1).await;
+        assert!(start.elapsed() >= Duration::from_millis(150), "{:?}", start.elapsed());
+
+        let start = std::time::Instant::now();
+        other.consume_upload(1_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        drop((first, second));
+        assert_eq!(throttler.throttle(Some("carol")).upload.len(), 1);
+        // the bucket of alice is still refilling
+        assert_eq!(throttler.shared.users.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn user_is_throttled_after_reconnecting() {
+        let config = ThrottleConfig {
+            user: BandwidthLimits::new().with_upload(RateLimit::new(10_000).with_burst(1_000)),
+            ..Default::default()
+        };
+        let throttler = Throttler::new(config);
+        let session = throttler.throttle(Some("alice"));
+        session.consume_upload(3_000).await;
+        drop(session);
+
+        // 2 000 bytes in debt paid back at 10 000 bytes per second
+        let start = std::time::Instant::now();
+        throttler.throttle(Some("alice")).consume_upload(1).await;
+        assert!(start.elapsed() >= Duration::from_millis(150), "{:?}", start.elapsed());
+
+        // refilled up to its burst, the bucket of alice is dropped
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        drop(throttler.throttle(Some("bob")));
+        let users = throttler.shared.users.lock().unwrap();
+        assert_eq!(users.keys().collect::<Vec<_>>(), ["bob"]);
+    }
+}
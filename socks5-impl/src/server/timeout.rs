@@ -0,0 +1,269 @@
+//! Idle timeouts and maximum lifetimes of established sessions.
+//!
+//! The handshake is bounded by [`IncomingConnection::authenticate_with_timeout`](crate::server::IncomingConnection::authenticate_with_timeout);
+//! once the session is established, wrap the client stream into a [`TimeLimited`], and set up the associated UDP
+//! socket with [`AssociatedUdpSocket::with_timeouts`](crate::server::AssociatedUdpSocket::with_timeouts).
+//! Sessions that hit a limit fail with [`TimedOut`](std::io::ErrorKind::TimedOut), and the reason is logged.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// The idle timeout and maximum lifetime of a session.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SessionTimeouts {
+    /// Close the session when no byte went through in either direction for this long.
+    pub idle: Option<Duration>,
+    /// Close the session after this long, whatever its activity.
+    pub max_lifetime: Option<Duration>,
+}
+
+impl SessionTimeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_idle(mut self, idle: Duration) -> Self {
+        self.idle = Some(idle);
+        self
+    }
+
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+}
+
+/// Why a session has been closed by its [`SessionTimeouts`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimeoutReason {
+    Idle(Duration),
+    MaxLifetime(Duration),
+}
+
+impl std::fmt::Display for TimeoutReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeoutReason::Idle(idle) => write!(f, "session idle for {idle:?}"),
+            TimeoutReason::MaxLifetime(lifetime) => write!(f, "session reached its maximum lifetime of {lifetime:?}"),
+        }
+    }
+}
+
+impl From<TimeoutReason> for std::io::Error {
+    fn from(reason: TimeoutReason) -> Self {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, reason.to_string())
+    }
+}
+
+/// Tracks the activity of a session against its [`SessionTimeouts`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadlines {
+    timeouts: SessionTimeouts,
+    started: Instant,
+    last_activity: Instant,
+}
+
+impl Deadlines {
+    pub(crate) fn new(timeouts: SessionTimeouts) -> Self {
+        let now = Instant::now();
+        Self {
+            timeouts,
+            started: now,
+            last_activity: now,
+        }
+    }
+
+    pub(crate) fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// The next instant at which a limit may be hit, if any.
+    pub(crate) fn next(&self) -> Option<Instant> {
+        let idle = self.timeouts.idle.map(|idle| self.last_activity + idle);
+        let lifetime = self.timeouts.max_lifetime.map(|lifetime| self.started + lifetime);
+        idle.into_iter().chain(lifetime).min()
+    }
+
+    /// The limit hit at `now`, if any.
+    pub(crate) fn expired(&self, now: Instant) -> Option<TimeoutReason> {
+        let timeouts = &self.timeouts;
+        if let Some(lifetime) = timeouts.max_lifetime.filter(|lifetime| now >= self.started + *lifetime) {
+            return Some(TimeoutReason::MaxLifetime(lifetime));
+        }
+        timeouts
+            .idle
+            .filter(|idle| now >= self.last_activity + *idle)
+            .map(TimeoutReason::Idle)
+    }
+}
+
+/// A timer for one direction of a [`TimeLimited`], so that each direction can be polled from its own task.
+#[derive(Debug, Default)]
+struct Timer(Option<Pin<Box<Sleep>>>);
+
+impl Timer {
+    /// Returns the limit hit by the session, or registers the task to be woken at the next deadline.
+    fn poll_expired(&mut self, deadlines: &Deadlines, cx: &mut Context<'_>) -> Option<TimeoutReason> {
+        loop {
+            let next = deadlines.next()?;
+            let sleep = self.0.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(next)));
+            if sleep.deadline() != next {
+                sleep.as_mut().reset(next);
+            }
+            if sleep.as_mut().poll(cx).is_pending() {
+                return None;
+            }
+            if let Some(reason) = deadlines.expired(Instant::now()) {
+                return Some(reason);
+            }
+        }
+    }
+}
+
+/// Closes the session of the client stream `S` when it is idle for too long, or reaches its maximum lifetime.
+///
+/// Reads and writes then fail with [`TimedOut`](std::io::ErrorKind::TimedOut), which ends the relay.
+/// The lifetime counts from the creation of the wrapper.
+#[derive(Debug)]
+pub struct TimeLimited<S> {
+    inner: S,
+    deadlines: Deadlines,
+    read_timer: Timer,
+    write_timer: Timer,
+}
+
+impl<S> TimeLimited<S> {
+    pub fn new(inner: S, timeouts: SessionTimeouts) -> Self {
+        Self {
+            inner,
+            deadlines: Deadlines::new(timeouts),
+            read_timer: Timer::default(),
+            write_timer: Timer::default(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+fn expired(reason: TimeoutReason) -> std::io::Error {
+    log::info!("closing {reason}");
+    reason.into()
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeLimited<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(reason) = this.read_timer.poll_expired(&this.deadlines, cx) {
+            return Poll::Ready(Err(expired(reason)));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if buf.filled().len() > filled {
+            this.deadlines.touch();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeLimited<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(reason) = this.write_timer.poll_expired(&this.deadlines, cx) {
+            return Poll::Ready(Err(expired(reason)));
+        }
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if written > 0 {
+            this.deadlines.touch();
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Wait until the session tracked by `deadlines` hits a limit, and return the error closing it.
+///
+/// `deadlines` is read again at every deadline, so activity recorded meanwhile postpones the timeout.
+pub(crate) async fn wait_expired(deadlines: impl Fn() -> Deadlines) -> std::io::Error {
+    loop {
+        let current = deadlines();
+        let Some(next) = current.next() else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep_until(next).await;
+        if let Some(reason) = deadlines().expired(Instant::now()) {
+            return expired(reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn idle_and_lifetime() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let timeouts = SessionTimeouts::new().with_idle(Duration::from_millis(100));
+        let mut server = TimeLimited::new(server, timeouts);
+
+        // Regular traffic keeps the session alive past the idle timeout
+        let writer = tokio::spawn(async move {
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                client.write_all(b"x").await.unwrap();
+            }
+            client
+        });
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        let _client = writer.await.unwrap();
+
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), TimeoutReason::Idle(Duration::from_millis(100)).to_string());
+
+        let (_client, server) = tokio::io::duplex(64);
+        let timeouts = timeouts.with_max_lifetime(Duration::from_millis(20));
+        let mut server = TimeLimited::new(server, timeouts);
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert!(err.to_string().contains("maximum lifetime"));
+    }
+
+    #[tokio::test]
+    async fn udp_association_times_out() {
+        use crate::server::AssociatedUdpSocket;
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let timeouts = SessionTimeouts::new().with_idle(Duration::from_millis(50));
+        let socket = AssociatedUdpSocket::from((socket, 1500)).with_timeouts(timeouts);
+        let err = socket.recv_from().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,285 @@
+//! SOCKS5 over TLS, behind the `tls` feature.
+//!
+//! A [`TlsListener`] terminates TLS before the SOCKS5 handshake, so that the RFC 1929 passwords and the requests
+//! are not sent in cleartext. When the [`TlsConfig`] verifies client certificates, the name of the certificate
+//! becomes the [`tls_identity`](crate::server::SessionContext::tls_identity) of the session, which is its user
+//! unless the [`AuthExecutor`](crate::server::AuthExecutor) authenticates another one.
+//!
+//! ```no_run
+//! use socks5_impl::server::{Server, auth::NoAuth, tls::TlsConfig};
+//! use std::sync::Arc;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let tls = TlsConfig::from_pem_files("server.crt", "server.key", Some("clients-ca.crt".as_ref()))?;
+//! let server = Server::bind_tls("127.0.0.1:1080".parse().unwrap(), tls, Arc::new(NoAuth)).await?;
+//! let (conn, _) = server.accept().await?;
+//! println!("client certificate of {:?}", conn.context().tls_identity);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::server::{
+    AuthAdaptor, Server,
+    context::SessionContext,
+    transport::{Acceptor, Handshakes},
+};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::WebPkiClientVerifier,
+    },
+    server::TlsStream,
+};
+
+type IdentityFn = dyn Fn(&CertificateDer<'_>) -> Option<String> + Send + Sync;
+
+/// How a [`TlsListener`] terminates TLS.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub(crate) server: Arc<ServerConfig>,
+    pub(crate) handshake_timeout: Duration,
+    pub(crate) identity: Arc<IdentityFn>,
+}
+
+impl TlsConfig {
+    /// Terminate TLS with the given rustls configuration.
+    ///
+    /// The client certificates, if the configuration verifies them, are named by their subject common name.
+    pub fn new(server: Arc<ServerConfig>) -> Self {
+        Self {
+            server,
+            handshake_timeout: Duration::from_secs(10),
+            identity: Arc::new(common_name),
+        }
+    }
+
+    /// Load the certificate chain and the private key of the server from PEM files.
+    ///
+    /// With `client_ca`, the clients must present a certificate issued by one of the authorities of this PEM file.
+    pub fn from_pem_files(cert_chain: impl AsRef<Path>, key: impl AsRef<Path>, client_ca: Option<&Path>) -> std::io::Result<Self> {
+        let certs = CertificateDer::pem_file_iter(cert_chain)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(invalid_data)?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(invalid_data)?;
+        let builder = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(client_ca).map_err(invalid_data)? {
+                    roots.add(cert.map_err(invalid_data)?).map_err(invalid_data)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(ring::default_provider()))
+                    .build()
+                    .map_err(invalid_data)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let server = builder.with_single_cert(certs, key).map_err(invalid_data)?;
+        Ok(Self::new(Arc::new(server)))
+    }
+
+    /// Drop the connections whose TLS handshake is not done within `timeout`, 10 seconds by default.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Name the client certificates with `identity` rather than with their subject common name.
+    pub fn with_identity<F>(mut self, identity: F) -> Self
+    where
+        F: Fn(&CertificateDer<'_>) -> Option<String> + Send + Sync + 'static,
+    {
+        self.identity = Arc::new(identity);
+        self
+    }
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("handshake_timeout", &self.handshake_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The subject common name of a certificate, if it has one.
+pub fn common_name(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.to_owned())
+}
+
+fn invalid_data(err: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("TLS: {err}"))
+}
+
+/// An [`Acceptor`] of TLS connections over TCP.
+///
+/// The TLS handshakes run in the background, so that a slow client does not hold the others back;
+/// the connections whose handshake fails are dropped. No more than 256 handshakes run at once: past them, the
+/// listener stops accepting until one of them is done.
+#[derive(Debug)]
+pub struct TlsListener {
+    handshakes: Handshakes<TlsStream<TcpStream>>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    /// Terminate TLS on the connections accepted by `listener`.
+    ///
+    /// This must be called from within a tokio runtime, which runs the handshakes.
+    pub fn new(listener: TcpListener, config: TlsConfig) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config.server.clone());
+        let handshakes = Handshakes::spawn(listener, config.handshake_timeout, move |stream, ctx: SessionContext| {
+            let (acceptor, identity) = (acceptor.clone(), config.identity.clone());
+            async move {
+                let stream = acceptor.accept(stream).await?;
+                let cert = stream.get_ref().1.peer_certificates().and_then(|certs| certs.first());
+                let ctx = ctx.with_tls_identity(cert.and_then(|cert| identity(cert)));
+                Ok((stream, ctx))
+            }
+        });
+        Ok(Self { handshakes, local_addr })
+    }
+
+    /// Get the local socket address of the TCP listener.
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Acceptor for TlsListener {
+    type Stream = TlsStream<TcpStream>;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, SessionContext)>> {
+        self.handshakes.poll_accept(cx)
+    }
+}
+
+impl<O: 'static> Server<O, TlsListener> {
+    /// Create a new socks5 server on the given socket address, terminating TLS as configured by `tls`.
+    pub async fn bind_tls(addr: SocketAddr, tls: TlsConfig, auth: AuthAdaptor<O>) -> std::io::Result<Self> {
+        let listener = TlsListener::new(TcpListener::bind(addr).await?, tls)?;
+        Ok(Self::from_acceptor(listener, auth))
+    }
+
+    /// Get the local socket address bound to this server.
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.acceptor().local_addr()
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{Address, AuthMethod, Reply},
+        server::{ClientConnection, auth::NoAuth},
+    };
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair};
+    use tokio_rustls::rustls::{ClientConfig, pki_types::PrivatePkcs8KeyDer};
+
+    /// Issue a certificate for `name`, as a server name or as the common name of a client.
+    fn issue(issuer: &Issuer<'_, KeyPair>, name: &str, usage: ExtendedKeyUsagePurpose) -> (String, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        (params.signed_by(&key, issuer).unwrap().pem(), key)
+    }
+
+    fn client_config(ca: &CertificateDer<'static>, cert: Option<(&str, &KeyPair)>) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        Arc::new(match cert {
+            Some((cert, key)) => {
+                let chain = vec![CertificateDer::from_pem_slice(cert.as_bytes()).unwrap()];
+                let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+                builder.with_client_auth_cert(chain, key.into()).unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        })
+    }
+
+    #[tokio::test]
+    async fn client_certificate_identity() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+        let (server_cert, server_key) = issue(&issuer, "localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let (client_cert, client_key) = issue(&issuer, "alice", ExtendedKeyUsagePurpose::ClientAuth);
+
+        let dir = std::env::temp_dir().join(format!("socks5-impl-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("server.crt"), &server_cert).unwrap();
+        std::fs::write(dir.join("server.key"), server_key.serialize_pem()).unwrap();
+        std::fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+        let tls = TlsConfig::from_pem_files(dir.join("server.crt"), dir.join("server.key"), Some(&dir.join("ca.crt"))).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let server = Server::bind_tls("127.0.0.1:0".parse().unwrap(), tls, Arc::new(NoAuth))
+            .await
+            .unwrap();
+        let proxy = Address::from(("localhost", server.local_addr().unwrap().port()));
+
+        // Without a client certificate, the TLS handshake fails and the connection is never accepted; with TLS 1.3
+        // the client only learns it when it reads the alert of the server, so the SOCKS5 request fails
+        let config = client_config(ca.der(), None);
+        let refused = async {
+            let (mut stream, _) = crate::client::connect_proxy_tls(proxy.clone(), config).await?;
+            crate::client::connect(&mut stream, ("example.com", 80), None).await
+        };
+        assert!(refused.await.is_err());
+
+        let config = client_config(ca.der(), Some((&client_cert, &client_key)));
+        let (mut stream, _) = crate::client::connect_proxy_tls(proxy, config).await.unwrap();
+        let client = tokio::spawn(async move {
+            let bound = crate::client::connect(&mut stream, ("example.com", 80), None).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut pong = [0; 4];
+            stream.read_exact(&mut pong).await.unwrap();
+            (bound, pong)
+        });
+
+        let (conn, _) = server.accept().await.unwrap();
+        assert_eq!(conn.context().tls_identity.as_deref(), Some("alice"));
+        let (authenticated, _) = conn.authenticate().await.unwrap();
+        assert_eq!(authenticated.context().auth_method, Some(AuthMethod::NoAuth));
+        assert_eq!(authenticated.context().identity.as_deref(), Some("alice"));
+        let ClientConnection::Connect(conn, target) = authenticated.wait_request().await.unwrap() else {
+            panic!("not a CONNECT request");
+        };
+        assert_eq!(target, Address::from(("example.com", 80)));
+        let mut conn = conn.reply(Reply::Succeeded, Address::unspecified()).await.unwrap();
+        let mut ping = [0; 4];
+        conn.stream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        conn.stream.write_all(b"pong").await.unwrap();
+        assert_eq!(client.await.unwrap(), (Address::unspecified(), *b"pong"));
+    }
+}
@@ -0,0 +1,312 @@
+//! The transports a [`Server`](crate::server::Server) can run over.
+//!
+//! A server accepts its connections from an [`Acceptor`], which yields a byte stream and the [`SessionContext`]
+//! describing its peer. [`TcpListener`] is the default acceptor; any other transport, such as TLS or an in-memory
+//! [`tokio::io::duplex`], can be served by implementing [`Acceptor`] and building the server with
+//! [`Server::from_acceptor`](crate::server::Server::from_acceptor).
+//!
+//! On Unix, a [`UnixListener`](tokio::net::UnixListener) is an acceptor too, see
+//! [`Server::bind_unix`](crate::server::Server::bind_unix). The credentials of its clients are reported as the
+//! [`peer_cred`](SessionContext::peer_cred) of their session, for an [`AuthExecutor`](crate::server::AuthExecutor)
+//! to authorize them with [`execute_with_context`](crate::server::AuthExecutor::execute_with_context).
+//!
+//! Behind the `tls` and `websocket` features, the `TlsListener` and `WebSocketListener` acceptors run their
+//! handshake in the background before handing the connections over.
+
+use crate::server::context::SessionContext;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+/// A byte stream a SOCKS5 session can run over.
+///
+/// It is implemented for every `AsyncRead + AsyncWrite + Unpin + Send + 'static` type.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncStream for T {}
+
+/// A source of incoming connections for a [`Server`](crate::server::Server).
+pub trait Acceptor {
+    /// The stream of an accepted connection.
+    type Stream: AsyncStream;
+
+    /// Polls to accept a connection, alongside the context of its session.
+    ///
+    /// The [`peer_addr`](SessionContext::peer_addr) of the context is the address the connection limits and the
+    /// trusted PROXY protocol peers are checked against.
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, SessionContext)>>;
+}
+
+impl Acceptor for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, SessionContext)>> {
+        let (stream, _) = std::task::ready!(TcpListener::poll_accept(self, cx))?;
+        let ctx = SessionContext::from_stream(&stream);
+        Poll::Ready(Ok((stream, ctx)))
+    }
+}
+
+impl<A: Acceptor + ?Sized> Acceptor for std::sync::Arc<A> {
+    type Stream = A::Stream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, SessionContext)>> {
+        (**self).poll_accept(cx)
+    }
+}
+
+#[cfg(unix)]
+impl Acceptor for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, SessionContext)>> {
+        let (stream, _) = std::task::ready!(tokio::net::UnixListener::poll_accept(self, cx))?;
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let peer_cred = stream.peer_cred().ok().map(Into::into);
+        let ctx = SessionContext::new(unspecified, unspecified).with_peer_cred(peer_cred);
+        Poll::Ready(Ok((stream, ctx)))
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "websocket"))]
+type Handshaken<S> = std::io::Result<(S, SessionContext)>;
+
+/// The number of handshakes run at once, past which the acceptor waits for one to finish before accepting again.
+#[cfg(any(feature = "tls", feature = "websocket"))]
+pub(crate) const MAX_HANDSHAKES: usize = 256;
+
+/// The connections of an acceptor, once their handshake, such as the TLS one, is done.
+///
+/// The handshakes run in the background, so that a slow client does not hold the others back;
+/// the connections whose handshake fails or times out are dropped. At most [`MAX_HANDSHAKES`] run at once, and
+/// a connection counts against them until it is accepted from the server, or dropped if it is not within the
+/// handshake timeout.
+#[cfg(any(feature = "tls", feature = "websocket"))]
+#[derive(Debug)]
+pub(crate) struct Handshakes<S> {
+    done: std::sync::Mutex<tokio::sync::mpsc::Receiver<Handshaken<S>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[cfg(any(feature = "tls", feature = "websocket"))]
+impl<S: Send + 'static> Handshakes<S> {
+    /// Accept the connections of `acceptor` and run `handshake` on each of them, within `timeout`.
+    ///
+    /// The handshaken connections are dropped too if the server does not take them within another `timeout`.
+    ///
+    /// This must be called from within a tokio runtime.
+    pub(crate) fn spawn<A, F, Fut>(acceptor: A, timeout: std::time::Duration, handshake: F) -> Self
+    where
+        A: Acceptor + Send + Sync + 'static,
+        F: Fn(A::Stream, SessionContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Handshaken<S>> + Send + 'static,
+    {
+        let (sender, done) = tokio::sync::mpsc::channel(64);
+        let handshake = std::sync::Arc::new(handshake);
+        let running = std::sync::Arc::new(tokio::sync::Semaphore::new(MAX_HANDSHAKES));
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok(permit) = running.clone().acquire_owned().await else {
+                    return;
+                };
+                let (stream, ctx) = match std::future::poll_fn(|cx| acceptor.poll_accept(cx)).await {
+                    Ok(accepted) => accepted,
+                    Err(err) => match sender.send(Err(err)).await {
+                        Ok(()) => continue,
+                        Err(_) => return,
+                    },
+                };
+                let (handshake, sender) = (handshake.clone(), sender.clone());
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let peer_addr = ctx.peer_addr;
+                    match tokio::time::timeout(timeout, handshake(stream, ctx)).await {
+                        Ok(Ok(done)) => {
+                            if tokio::time::timeout(timeout, sender.send(Ok(done))).await.is_err() {
+                                log::debug!("connection from {peer_addr} was not accepted in time");
+                            }
+                        }
+                        Ok(Err(err)) => log::debug!("handshake with {peer_addr} failed: {err}"),
+                        Err(_) => log::debug!("handshake with {peer_addr} timed out"),
+                    }
+                });
+            }
+        });
+        Self {
+            done: std::sync::Mutex::new(done),
+            task,
+        }
+    }
+
+    pub(crate) fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Handshaken<S>> {
+        match std::task::ready!(self.done.lock().unwrap().poll_recv(cx)) {
+            Some(done) => Poll::Ready(done),
+            None => Poll::Ready(Err(std::io::Error::other("listener is closed"))),
+        }
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "websocket"))]
+impl<S> Drop for Handshakes<S> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{Address, AuthMethod, Reply},
+        server::{ClientConnection, MixedConnection, Server, auth::UserKeyAuth},
+    };
+    use std::{net::SocketAddr, sync::Arc, sync::Mutex};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        sync::mpsc,
+    };
+
+    /// Accepts the in-memory streams sent on a channel.
+    struct ChannelAcceptor(Mutex<mpsc::UnboundedReceiver<DuplexStream>>);
+
+    impl Acceptor for ChannelAcceptor {
+        type Stream = DuplexStream;
+
+        fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, SessionContext)>> {
+            let stream = std::task::ready!(self.0.lock().unwrap().poll_recv(cx));
+            let stream = stream.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+            let peer_addr: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+            Poll::Ready(Ok((stream, SessionContext::new(peer_addr, "192.0.2.2:1080".parse().unwrap()))))
+        }
+    }
+
+    #[tokio::test]
+    async fn server_over_an_in_memory_transport() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let acceptor = ChannelAcceptor(Mutex::new(receiver));
+        let server = Server::from_acceptor(acceptor, Arc::new(UserKeyAuth::new("alice", "secret")));
+
+        let (mut client, stream) = tokio::io::duplex(1024);
+        sender.send(stream).unwrap();
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00, 0x03, 11]).await.unwrap();
+        client.write_all(b"example.com\x01\xbb").await.unwrap();
+
+        let (conn, peer_addr) = server.accept().await.unwrap();
+        assert_eq!(peer_addr, "192.0.2.1:40000".parse().unwrap());
+        let MixedConnection::Socks5(conn) = conn.sniff().await.unwrap() else {
+            panic!("not a SOCKS5 client");
+        };
+        let (authenticated, output) = conn.authenticate().await.unwrap();
+        assert!(output.unwrap());
+        assert_eq!(authenticated.context().auth_method, Some(AuthMethod::UserPass));
+        let ClientConnection::Connect(conn, target) = authenticated.wait_request().await.unwrap() else {
+            panic!("not a CONNECT request");
+        };
+        assert_eq!(target, Address::from(("example.com", 443)));
+        let mut conn = conn.reply(Reply::Succeeded, Address::unspecified()).await.unwrap();
+
+        let mut replies = [0; 2 + 2 + 10];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(&replies[..4], &[0x05, 0x02, 0x01, 0x00]);
+        assert_eq!(&replies[4..6], &[0x05, 0x00]);
+        conn.stream.write_all(b"pong").await.unwrap();
+        let mut pong = [0; 4];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+    }
+
+    /// Accepts the clients run by the given user only.
+    #[cfg(unix)]
+    struct UidAuth(u32);
+
+    #[cfg(unix)]
+    #[async_trait::async_trait]
+    impl crate::server::AuthExecutor for UidAuth {
+        type Output = std::io::Result<u32>;
+
+        fn auth_method(&self) -> AuthMethod {
+            AuthMethod::NoAuth
+        }
+
+        async fn execute(&self, _: &mut dyn AsyncStream) -> Self::Output {
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        }
+
+        async fn execute_with_context(&self, _: &mut dyn AsyncStream, ctx: &SessionContext) -> Self::Output {
+            match ctx.peer_cred {
+                Some(cred) if cred.uid == self.0 => Ok(cred.uid),
+                _ => Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied)),
+            }
+        }
+
+        fn identity(&self, output: &Self::Output) -> Option<String> {
+            output.as_ref().ok().map(|uid| format!("uid:{uid}"))
+        }
+    }
+
+    /// Check that a client is denied by a server allowing another user, then accepted once its user is allowed.
+    #[cfg(unix)]
+    async fn authorize_by_uid<F>(listener: tokio::net::UnixListener, connect: impl Fn() -> F)
+    where
+        F: Future<Output = tokio::net::UnixStream>,
+    {
+        let server = Server::from_acceptor(listener, Arc::new(UidAuth(u32::MAX)));
+        let mut client = connect().await;
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        let cred = conn.context().peer_cred.unwrap();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(cred.pid, Some(std::process::id() as i32));
+        assert!(conn.authenticate().await.unwrap().1.is_err());
+
+        let (listener, _) = server.into();
+        let server = Server::from_acceptor(listener, Arc::new(UidAuth(cred.uid)));
+        let mut client = connect().await;
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        let (authenticated, output) = conn.authenticate().await.unwrap();
+        assert_eq!(output.unwrap(), cred.uid);
+        assert_eq!(authenticated.context().identity, Some(format!("uid:{}", cred.uid)));
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_with_peer_credentials() {
+        let path = std::env::temp_dir().join(format!("socks5-impl-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = Server::bind_unix(&path, Arc::new(UidAuth(0))).unwrap();
+        let (listener, _) = server.into();
+        authorize_by_uid(listener, || async { tokio::net::UnixStream::connect(&path).await.unwrap() }).await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn unix_socket_in_abstract_namespace() {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("socks5-impl-{}", std::process::id());
+        let server = Server::bind_unix_abstract(&name, Arc::new(UidAuth(0))).unwrap();
+        let (listener, _) = server.into();
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        authorize_by_uid(listener, || async {
+            let client = std::os::unix::net::UnixStream::connect_addr(&addr).unwrap();
+            client.set_nonblocking(true).unwrap();
+            tokio::net::UnixStream::from_std(client).unwrap()
+        })
+        .await;
+    }
+}
@@ -0,0 +1,148 @@
+//! SOCKS5 over WebSocket on the server side, behind the `websocket` feature.
+//!
+//! A [`WebSocketListener`] answers the WebSocket upgrades on a path before the SOCKS5 handshake, so that the
+//! server can sit behind an HTTP reverse proxy or a CDN. It wraps any other [`Acceptor`], such as a
+//! `TlsListener` to serve `wss://`.
+//!
+//! ```no_run
+//! use socks5_impl::server::{Server, auth::NoAuth};
+//! use std::sync::Arc;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let server = Server::bind_websocket("127.0.0.1:8080".parse().unwrap(), "/socks", Arc::new(NoAuth)).await?;
+//! let (conn, _) = server.accept().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    server::{
+        AuthAdaptor, Server,
+        context::SessionContext,
+        transport::{Acceptor, Handshakes},
+    },
+    websocket::{self, WebSocketStream},
+};
+use http::StatusCode;
+use http_impl::HttpRequest;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+/// The time a client has to complete its upgrade request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An [`Acceptor`] of WebSocket connections, over the connections of another acceptor.
+///
+/// The upgrades run in the background, so that a slow client does not hold the others back. The requests for
+/// another path are answered with `404 Not Found`, and the connections which fail to upgrade within 10 seconds
+/// are dropped. Like a `TlsListener`, it answers up to 256 upgrades at once.
+#[derive(Debug)]
+pub struct WebSocketListener<A: Acceptor = TcpListener> {
+    acceptor: Arc<A>,
+    handshakes: Handshakes<WebSocketStream<A::Stream>>,
+}
+
+impl<A: Acceptor + Send + Sync + 'static> WebSocketListener<A> {
+    /// Answer the WebSocket upgrades for `path` on the connections accepted by `acceptor`.
+    ///
+    /// This must be called from within a tokio runtime, which runs the upgrades.
+    pub fn new(acceptor: A, path: impl Into<String>) -> Self {
+        let acceptor = Arc::new(acceptor);
+        let path: Arc<str> = path.into().into();
+        let handshakes = Handshakes::spawn(acceptor.clone(), HANDSHAKE_TIMEOUT, move |mut stream, ctx: SessionContext| {
+            let path = path.clone();
+            async move {
+                let request = HttpRequest::from_stream(&mut stream)
+                    .await
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+                if request.uri().path() != &*path {
+                    websocket::refuse(&mut stream, StatusCode::NOT_FOUND).await?;
+                    let err = format!("WebSocket upgrade for {}", request.uri().path());
+                    return Err(std::io::Error::new(std::io::ErrorKind::NotFound, err));
+                }
+                Ok((websocket::upgrade(stream, &request).await?, ctx))
+            }
+        });
+        Self { acceptor, handshakes }
+    }
+}
+
+impl<A: Acceptor> WebSocketListener<A> {
+    /// Get a reference to the acceptor the WebSocket connections come from.
+    pub fn get_ref(&self) -> &A {
+        &self.acceptor
+    }
+}
+
+impl<A: Acceptor> Acceptor for WebSocketListener<A> {
+    type Stream = WebSocketStream<A::Stream>;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, SessionContext)>> {
+        self.handshakes.poll_accept(cx)
+    }
+}
+
+impl<O: 'static> Server<O, WebSocketListener> {
+    /// Create a new socks5 server on the given socket address, answering the WebSocket upgrades for `path`.
+    pub async fn bind_websocket(addr: SocketAddr, path: &str, auth: AuthAdaptor<O>) -> std::io::Result<Self> {
+        let listener = WebSocketListener::new(TcpListener::bind(addr).await?, path);
+        Ok(Self::from_acceptor(listener, auth))
+    }
+
+    /// Get the local socket address bound to this server.
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.acceptor().get_ref().local_addr()
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{Address, Reply},
+        server::{ClientConnection, auth::NoAuth},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn socks5_over_websocket() {
+        let server = Server::bind_websocket("127.0.0.1:0".parse().unwrap(), "/socks", Arc::new(NoAuth))
+            .await
+            .unwrap();
+        let proxy = server.local_addr().unwrap();
+
+        // A request for another path is refused and never accepted
+        let (stream, _) = crate::client::connect_proxy(proxy).await.unwrap();
+        let err = websocket::connect(stream, &proxy.to_string(), "/other").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+        let (mut stream, _) = crate::client::connect_proxy_websocket(proxy, "/socks").await.unwrap();
+        let client = tokio::spawn(async move {
+            let bound = crate::client::connect(&mut stream, ("example.com", 80), None).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut pong = [0; 4];
+            stream.read_exact(&mut pong).await.unwrap();
+            (bound, pong)
+        });
+
+        let (conn, peer_addr) = server.accept().await.unwrap();
+        assert_eq!(peer_addr.ip(), proxy.ip());
+        let (authenticated, _) = conn.authenticate().await.unwrap();
+        let ClientConnection::Connect(conn, target) = authenticated.wait_request().await.unwrap() else {
+            panic!("not a CONNECT request");
+        };
+        assert_eq!(target, Address::from(("example.com", 80)));
+        let mut conn = conn.reply(Reply::Succeeded, Address::unspecified()).await.unwrap();
+        let mut ping = [0; 4];
+        conn.stream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        conn.stream.write_all(b"pong").await.unwrap();
+        assert_eq!(client.await.unwrap(), (Address::unspecified(), *b"pong"));
+    }
+}
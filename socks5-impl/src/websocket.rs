@@ -0,0 +1,477 @@
+//! SOCKS5 over WebSocket (RFC 6455), behind the `websocket` feature.
+//!
+//! A [`WebSocketStream`] carries a byte stream in binary frames, so that SOCKS5 sessions can cross the HTTP
+//! reverse proxies and CDNs which only forward WebSocket upgrades. The server side answers the upgrade with
+//! [`accept`] or [`upgrade`], the client side requests it with [`connect`]; both then run SOCKS5 over the
+//! returned stream as over a TCP one.
+//!
+//! ```no_run
+//! # #[cfg(feature = "client")]
+//! # async fn example() -> socks5_impl::Result<()> {
+//! use socks5_impl::{client, websocket};
+//!
+//! let (stream, _proxy_addr) = client::connect_proxy(("my-proxy-server.com", 443)).await?;
+//! let mut stream = websocket::connect(stream, "my-proxy-server.com", "/socks").await?;
+//! client::connect(&mut stream, ("google.com", 80), None).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::{Buf, BufMut, BytesMut};
+use http::{Method, StatusCode};
+use http_impl::{HttpError, HttpRequest, HttpRequestBuilder, HttpResponse, HttpResponseBuilder};
+use sha1::{Digest, Sha1};
+use std::{
+    io::{Error, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The GUID appended to the key of the client to compute the accept value, per RFC 6455 section 1.3.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest payload of the frames written, larger writes are split.
+const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// The end of a WebSocket connection, which masks the frames it sends if it is the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// A byte stream over a WebSocket connection.
+///
+/// The written bytes are sent in binary frames and the payloads of the data frames received are read back,
+/// whatever their type. Pings are answered, only the last one if the peer pings faster than it reads. A close frame reads as the end of the stream and shutting the
+/// stream down sends one, so that each end can still write after the other closed, like a TCP half-close.
+#[derive(Debug)]
+pub struct WebSocketStream<S> {
+    stream: S,
+    role: Role,
+    /// Received bytes not yet decoded.
+    read_buf: BytesMut,
+    /// Encoded frames not yet sent.
+    write_buf: BytesMut,
+    /// The payload left to read of the current data frame, and its mask.
+    payload_left: u64,
+    mask: Option<[u8; 4]>,
+    mask_offset: usize,
+    close_received: bool,
+    close_sent: bool,
+    /// The payload of the last ping not answered yet, only the last one is, once the frames queued before it are sent.
+    pong: Option<Vec<u8>>,
+}
+
+impl<S> WebSocketStream<S> {
+    /// Run WebSocket framing over `stream`, once the opening handshake is done.
+    pub fn new(stream: S, role: Role) -> Self {
+        Self {
+            stream,
+            role,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            payload_left: 0,
+            mask: None,
+            mask_offset: 0,
+            close_received: false,
+            close_sent: false,
+            pong: None,
+        }
+    }
+
+    /// Decode `received` before what is read from the stream, such as the bytes following the handshake.
+    pub fn with_received(mut self, received: &[u8]) -> Self {
+        self.read_buf.extend_from_slice(received);
+        self
+    }
+
+    /// Get the role of this end of the connection.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Get a shared reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get a mutable reference to the underlying stream.
+    ///
+    /// Reading from or writing to it corrupts the WebSocket framing.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consume this stream, returning the underlying one.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let buf = &mut self.write_buf;
+        buf.put_u8(0x80 | opcode);
+        let masked = if self.role == Role::Client { 0x80 } else { 0 };
+        match payload.len() {
+            len if len < 126 => buf.put_u8(masked | len as u8),
+            len if len <= u16::MAX as usize => {
+                buf.put_u8(masked | 126);
+                buf.put_u16(len as u16);
+            }
+            len => {
+                buf.put_u8(masked | 127);
+                buf.put_u64(len as u64);
+            }
+        }
+        if self.role == Role::Server {
+            buf.extend_from_slice(payload);
+            return;
+        }
+        // The masks must not be predictable by the application, so that it cannot craft bytes for the proxies on the way
+        let mask: [u8; 4] = rand::random();
+        buf.extend_from_slice(&mask);
+        buf.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut chunk = [0; 8192];
+        let mut buf = ReadBuf::new(&mut chunk);
+        ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+        if buf.filled().is_empty() {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "WebSocket connection closed without a close frame",
+            )));
+        }
+        self.read_buf.extend_from_slice(buf.filled());
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            while !self.write_buf.is_empty() {
+                let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf))?;
+                if n == 0 {
+                    return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                }
+                self.write_buf.advance(n);
+            }
+            match self.pong.take() {
+                Some(pong) if !self.close_sent => self.queue_frame(OP_PONG, &pong),
+                _ => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+
+    /// Decode the next frame header in the read buffer, handling the control frames.
+    ///
+    /// Returns `Ready(Ok(true))` when a data frame starts, `Ready(Ok(false))` when a control frame was handled.
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        let invalid = |msg: &str| Poll::Ready(Err(Error::new(ErrorKind::InvalidData, format!("WebSocket: {msg}"))));
+        while self.read_buf.len() < 2 {
+            ready!(self.poll_fill(cx))?;
+        }
+        let (first, second) = (self.read_buf[0], self.read_buf[1]);
+        let masked = second & 0x80 != 0;
+        let len_len = match second & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let header_len = 2 + len_len + if masked { 4 } else { 0 };
+        while self.read_buf.len() < header_len {
+            ready!(self.poll_fill(cx))?;
+        }
+        if first & 0x70 != 0 {
+            return invalid("reserved bits are set");
+        }
+        if masked != (self.role == Role::Server) {
+            return invalid("frames must be masked by the client only");
+        }
+        let opcode = first & 0x0f;
+        let len = match second & 0x7f {
+            126 => u16::from_be_bytes([self.read_buf[2], self.read_buf[3]]) as u64,
+            127 => u64::from_be_bytes(self.read_buf[2..10].try_into().unwrap()),
+            len => len as u64,
+        };
+        let mask = masked.then(|| self.read_buf[header_len - 4..header_len].try_into().unwrap());
+
+        if matches!(opcode, OP_CONTINUATION | OP_TEXT | OP_BINARY) {
+            self.read_buf.advance(header_len);
+            (self.payload_left, self.mask, self.mask_offset) = (len, mask, 0);
+            return Poll::Ready(Ok(true));
+        }
+        if !matches!(opcode, OP_CLOSE | OP_PING | OP_PONG) {
+            return invalid("unknown opcode");
+        }
+        if first & 0x80 == 0 || len > 125 {
+            return invalid("control frames must be final and short");
+        }
+        let frame_len = header_len + len as usize;
+        while self.read_buf.len() < frame_len {
+            ready!(self.poll_fill(cx))?;
+        }
+        let mut frame = self.read_buf.split_to(frame_len);
+        let payload = &mut frame[header_len..];
+        if let Some(mask) = mask {
+            payload.iter_mut().enumerate().for_each(|(i, byte)| *byte ^= mask[i % 4]);
+        }
+        match opcode {
+            OP_CLOSE => self.close_received = true,
+            OP_PING if !self.close_sent => {
+                // A peer pinging faster than it reads replaces its unanswered pings, rather than piling pongs up
+                self.pong = Some(payload.to_vec());
+                // The pong is sent by the next writes or flushes if the stream is not writable now.
+                if let Poll::Ready(Err(err)) = self.poll_send(cx) {
+                    return Poll::Ready(Err(err));
+                }
+            }
+            _ => {}
+        }
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        while this.payload_left == 0 {
+            if this.close_received {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_frame(cx))?;
+        }
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if this.read_buf.is_empty() {
+            ready!(this.poll_fill(cx))?;
+        }
+        let n = this.read_buf.len().min(buf.remaining()).min(this.payload_left as usize);
+        let mut payload = this.read_buf.split_to(n);
+        if let Some(mask) = this.mask {
+            let offset = this.mask_offset;
+            payload.iter_mut().enumerate().for_each(|(i, byte)| *byte ^= mask[(offset + i) % 4]);
+        }
+        buf.put_slice(&payload);
+        this.payload_left -= n as u64;
+        this.mask_offset = (this.mask_offset + n) % 4;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        if this.close_sent {
+            return Poll::Ready(Err(Error::new(ErrorKind::BrokenPipe, "WebSocket connection is closing")));
+        }
+        let n = buf.len().min(MAX_FRAME_PAYLOAD);
+        this.queue_frame(OP_BINARY, &buf[..n]);
+        // The frame is queued, the bytes are written as far as the caller is concerned.
+        if let Poll::Ready(Err(err)) = this.poll_send(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            if let Some(pong) = this.pong.take() {
+                this.queue_frame(OP_PONG, &pong);
+            }
+            this.close_sent = true;
+            this.queue_frame(OP_CLOSE, &1000u16.to_be_bytes());
+        }
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/// Read the upgrade request of a WebSocket client from `stream` and accept it, like [`upgrade`].
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> std::io::Result<WebSocketStream<S>> {
+    let request = HttpRequest::from_stream(&mut stream).await.map_err(http_error)?;
+    upgrade(stream, &request).await
+}
+
+/// Accept the upgrade `request`, already read from `stream`, such as by an HTTP server routing on its path.
+///
+/// A request which is not a valid WebSocket upgrade is refused with `400 Bad Request`, or with
+/// `426 Upgrade Required` if only its protocol version is wrong.
+pub async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &HttpRequest) -> std::io::Result<WebSocketStream<S>> {
+    let has_token = |name: &str, token: &str| {
+        let value = request.header(name).unwrap_or_default();
+        value.split(',').any(|v| v.trim().eq_ignore_ascii_case(token))
+    };
+    let key = request.header("Sec-WebSocket-Key").unwrap_or_default();
+    let key_is_valid = STANDARD.decode(key).is_ok_and(|key| key.len() == 16);
+    if request.method() != Method::GET || !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") || !key_is_valid {
+        refuse(&mut stream, StatusCode::BAD_REQUEST).await?;
+        return Err(Error::new(ErrorKind::InvalidData, "not a WebSocket upgrade request"));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        refuse(&mut stream, StatusCode::UPGRADE_REQUIRED).await?;
+        return Err(Error::new(ErrorKind::InvalidData, "unsupported WebSocket version"));
+    }
+    let response = HttpResponseBuilder::new()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", &accept_key(key))
+        .build();
+    response.write_to_stream(&mut stream).await.map_err(http_error)?;
+    Ok(WebSocketStream::new(stream, Role::Server).with_received(request.body()))
+}
+
+/// Refuse an upgrade request with `status`, closing the connection.
+pub(crate) async fn refuse<S: AsyncWrite + Unpin>(stream: &mut S, status: StatusCode) -> std::io::Result<()> {
+    let mut response = HttpResponseBuilder::new()
+        .status(status)
+        .header("Content-Length", "0")
+        .header("Connection", "close");
+    if status == StatusCode::UPGRADE_REQUIRED {
+        response = response.header("Sec-WebSocket-Version", "13");
+    }
+    response.build().write_to_stream(stream).await.map_err(http_error)?;
+    stream.shutdown().await
+}
+
+/// Request a WebSocket upgrade of `stream` to the server `host`, for `path`.
+///
+/// `host` is sent as the `Host` header, with the port if it is not the default one of the scheme.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, host: &str, path: &str) -> std::io::Result<WebSocketStream<S>> {
+    let uri = path
+        .parse::<http::Uri>()
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("{path}: {err}")))?;
+    let key = STANDARD.encode(rand::random::<[u8; 16]>());
+    let request = HttpRequestBuilder::new()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Host", host)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Key", &key)
+        .header("Sec-WebSocket-Version", "13")
+        .build();
+    stream.write_all(request.raw_bytes()).await?;
+    stream.flush().await?;
+
+    let response = HttpResponse::from_stream(&mut stream).await.map_err(http_error)?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        let err = format!("WebSocket upgrade refused with {}", response.status());
+        return Err(Error::new(ErrorKind::ConnectionRefused, err));
+    }
+    if response.header("Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid Sec-WebSocket-Accept"));
+    }
+    Ok(WebSocketStream::new(stream, Role::Client).with_received(response.body()))
+}
+
+/// The `Sec-WebSocket-Accept` value answering the `Sec-WebSocket-Key` of a client.
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+fn http_error(err: HttpError) -> Error {
+    match err {
+        HttpError::Io(err) => err,
+        err => Error::new(ErrorKind::InvalidData, err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn accept_key_of_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let (client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut server = accept(server).await.unwrap();
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            server.write_all(&received).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+
+        let mut client = connect(client, "localhost", "/").await.unwrap();
+        // Larger than a frame, and than the duplex buffer
+        let sent: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        // A ping between data frames is answered and skipped
+        client.queue_frame(OP_PING, b"ping");
+        client.write_all(&sent).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut pong = [0; 6];
+        client.get_mut().read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x80 | OP_PONG, 4, b'p', b'i', b'n', b'g']);
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).await.unwrap();
+        assert!(echoed == sent);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_last_ping_waits_for_an_answer() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = WebSocketStream::new(server, Role::Server);
+        let writer = tokio::spawn(async move {
+            // Masked with zeros, and never reading the pongs
+            for i in 0..1000u32 {
+                client.write_all(&[0x80 | OP_PING, 0x84, 0, 0, 0, 0]).await.unwrap();
+                client.write_all(&i.to_be_bytes()).await.unwrap();
+            }
+            client.write_all(&[0x80 | OP_BINARY, 0x81, 0, 0, 0, 0, b'x']).await.unwrap();
+            client
+        });
+
+        let mut x = [0; 1];
+        server.read_exact(&mut x).await.unwrap();
+        assert_eq!(&x, b"x");
+        assert!(server.write_buf.len() <= 6);
+        assert_eq!(server.pong.as_deref(), Some(&999u32.to_be_bytes()[..]));
+        let _client = writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_upgrades() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(accept(server));
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n")
+            .await
+            .unwrap();
+        let response = HttpResponse::from_stream(&mut client).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.header("Sec-WebSocket-Version"), Some("13"));
+        assert!(server.await.unwrap().is_err());
+    }
+}
@@ -8,7 +8,10 @@ use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
 
-/// A wrapper around `TcpStream` that performs async graceful shutdown on drop.
+/// The bytes read at once from the inner stream when reading ahead.
+const READ_AHEAD_SIZE: usize = 4096;
+
+/// A wrapper around a `TcpStream`, or any other async byte stream, that performs async graceful shutdown on drop.
 ///
 /// When this struct is dropped, it will spawn a background task to perform
 /// a graceful TCP shutdown. This ensures proper connection termination without
@@ -20,29 +23,70 @@ use tokio::net::TcpStream;
 /// - Drop is non-blocking and won't impact async task performance
 /// - Graceful shutdown happens in the background
 /// - If you need to ensure shutdown completes, call `shutdown()` explicitly before dropping
+///
+/// The socket options and addresses are only available on a `Stream<TcpStream>`, the default.
+///
+/// With [`set_read_ahead`](Self::set_read_ahead), small reads such as those of a protocol handshake are served
+/// from a buffer. The bytes read ahead are served before the inner stream by the `AsyncRead` implementation only:
+/// check [`buffered`](Self::buffered) before reading from the inner stream through `Deref`.
 #[derive(Debug)]
-pub struct Stream {
-    stream: Option<TcpStream>,
+pub struct Stream<S = TcpStream>
+where
+    S: AsyncWrite + Unpin + Send + 'static,
+{
+    stream: Option<S>,
+    read_ahead: bool,
+    buffered: Vec<u8>,
+    /// The bytes of `buffered` already read.
+    consumed: usize,
 }
 
-impl Stream {
-    /// Get internal TcpStream reference
+impl<S: AsyncWrite + Unpin + Send + 'static> Stream<S> {
+    /// Get internal stream reference
     #[inline]
-    fn get_stream(&self) -> &TcpStream {
+    fn get_stream(&self) -> &S {
         self.stream.as_ref().expect("Stream has been consumed")
     }
 
-    /// Get internal TcpStream mutable reference
+    /// Get internal stream mutable reference
     #[inline]
-    fn get_stream_mut(&mut self) -> &mut TcpStream {
+    fn get_stream_mut(&mut self) -> &mut S {
         self.stream.as_mut().expect("Stream has been consumed")
     }
 }
 
-impl Stream {
+impl<S: AsyncWrite + Unpin + Send + 'static> Stream<S> {
     #[inline]
-    pub fn new(stream: TcpStream) -> Self {
-        Self { stream: Some(stream) }
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: Some(stream),
+            read_ahead: false,
+            buffered: Vec::new(),
+            consumed: 0,
+        }
+    }
+
+    /// Read up to 4 KiB from the inner stream at once when a smaller read finds no buffered bytes.
+    ///
+    /// This saves a system call per field of a handshake, and keeps the bytes a peer sent ahead of its turn, such
+    /// as the payload pipelined after a request, for the following reads. Turning it off keeps the bytes already
+    /// buffered.
+    #[inline]
+    pub fn set_read_ahead(&mut self, read_ahead: bool) {
+        self.read_ahead = read_ahead;
+    }
+
+    /// Get the bytes read ahead from the inner stream, and not read from this stream yet.
+    #[inline]
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffered[self.consumed..]
+    }
+
+    /// Take the bytes read ahead from the inner stream, and not read from this stream yet.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        let mut buffered = std::mem::take(&mut self.buffered);
+        buffered.drain(..std::mem::take(&mut self.consumed));
+        buffered
     }
 
     /// Causes the other peer to receive a read of length 0, indicating that no more data will be sent.
@@ -58,15 +102,19 @@ impl Stream {
         self.get_stream_mut().shutdown().await
     }
 
-    /// Consumes the `Stream` and returns the inner `TcpStream`.
+    /// Consumes the `Stream` and returns the inner stream.
     ///
-    /// This method extracts the underlying `TcpStream` without triggering the async drop
+    /// This method extracts the underlying stream without triggering the async drop
     /// behavior, giving you full control over the connection lifecycle.
+    ///
+    /// The [`buffered`](Self::buffered) bytes are dropped, [`take_buffered`](Self::take_buffered) them first.
     #[inline]
-    pub fn into_inner(mut self) -> TcpStream {
+    pub fn into_inner(mut self) -> S {
         self.stream.take().expect("Stream has been consumed")
     }
+}
 
+impl Stream<TcpStream> {
     /// Returns the local address that this stream is bound to.
     #[inline]
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
@@ -113,29 +161,45 @@ impl Stream {
     }
 }
 
-impl Deref for Stream {
-    type Target = TcpStream;
+impl<S: AsyncWrite + Unpin + Send + 'static> Deref for Stream<S> {
+    type Target = S;
 
     fn deref(&self) -> &Self::Target {
         self.get_stream()
     }
 }
 
-impl DerefMut for Stream {
+impl<S: AsyncWrite + Unpin + Send + 'static> DerefMut for Stream<S> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.get_stream_mut()
     }
 }
 
-// Implement AsyncRead trait by delegating to inner TcpStream
-impl AsyncRead for Stream {
+// Implement AsyncRead trait by delegating to inner stream
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncRead for Stream<S> {
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
-        Pin::new(self.get_stream_mut()).poll_read(cx, buf)
+        let this = &mut *self;
+        if this.consumed == this.buffered.len() {
+            if !this.read_ahead || buf.remaining() >= READ_AHEAD_SIZE {
+                return Pin::new(this.get_stream_mut()).poll_read(cx, buf);
+            }
+            let mut chunk = [0; READ_AHEAD_SIZE];
+            let mut ahead = ReadBuf::new(&mut chunk);
+            std::task::ready!(Pin::new(this.get_stream_mut()).poll_read(cx, &mut ahead))?;
+            this.buffered.clear();
+            this.buffered.extend_from_slice(ahead.filled());
+            this.consumed = 0;
+        }
+        let buffered = &this.buffered[this.consumed..];
+        let n = buffered.len().min(buf.remaining());
+        buf.put_slice(&buffered[..n]);
+        this.consumed += n;
+        Poll::Ready(Ok(()))
     }
 }
 
-// Implement AsyncWrite trait by delegating to inner TcpStream
-impl AsyncWrite for Stream {
+// Implement AsyncWrite trait by delegating to inner stream
+impl<S: AsyncWrite + Unpin + Send + 'static> AsyncWrite for Stream<S> {
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
         Pin::new(self.get_stream_mut()).poll_write(cx, buf)
     }
@@ -158,9 +222,9 @@ impl AsyncWrite for Stream {
 }
 
 #[cfg(not(test))]
-impl Drop for Stream {
+impl<S: AsyncWrite + Unpin + Send + 'static> Drop for Stream<S> {
     fn drop(&mut self) {
-        // Take TcpStream from Option
+        // Take the stream from Option
         if let Some(stream) = self.stream.take() {
             // Try to async execute shutdown in current tokio runtime
             if let Ok(handle) = tokio::runtime::Handle::try_current() {